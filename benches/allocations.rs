@@ -0,0 +1,87 @@
+//! Counts the allocations of the hot paths over the 4096 blocks of a 512 * 512 image
+//!
+//! Run with `cargo bench --bench allocations`
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bitvec::prelude::BitVec;
+use color_watermark::{dct, qim};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const NUM_BLOCKS: usize = 4096;
+
+fn count<T>(label: &str, f: impl FnOnce() -> T) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("{:<28} {:>6} allocations", label, allocations);
+}
+
+fn main() {
+    let step_size = 50.0;
+    let dithers = qim::generate_dither_signal(qim::MASK_LEN, step_size, 42);
+    let mask = qim::CoefficientMask::default();
+    let blocks: Vec<Vec<f32>> = (0..NUM_BLOCKS)
+        .map(|n| (0..64).map(|i| ((n * 31 + i * 17) % 200) as f32).collect())
+        .collect();
+
+    // Collecting the extracted bits into a default-capacity BitVec against a pre-sized one
+    count("bits pushed one by one", || {
+        let mut bits: BitVec = BitVec::new();
+        for block in blocks.iter() {
+            for bit in qim::extract_wm(block, &dithers, step_size, &mask) {
+                bits.push(bit);
+            }
+        }
+        bits
+    });
+
+    count("bits pre-sized", || {
+        let mut bits: BitVec = BitVec::with_capacity(NUM_BLOCKS * qim::MASK_LEN);
+        for block in blocks.iter() {
+            bits.extend_from_bitslice(&qim::extract_wm(block, &dithers, step_size, &mask));
+        }
+        bits
+    });
+
+    count("bits filled in place", || {
+        let mut bits: BitVec = BitVec::repeat(false, NUM_BLOCKS * qim::MASK_LEN);
+        for (block, out) in blocks.iter().zip(bits.chunks_mut(qim::MASK_LEN)) {
+            qim::extract_wm_into(block, &dithers, step_size, &mask, out);
+        }
+        bits
+    });
+
+    // The transforms reuse one scratch buffer and transpose in place, a per-block transpose
+    // buffer would cost 2 * 4096 allocations
+    let block_dct = dct::BlockDct::new(8);
+    let mut transformed = blocks.clone();
+    count("forward and inverse DCT", || {
+        block_dct.forward(&mut transformed);
+        block_dct.inverse(&mut transformed);
+    });
+}
@@ -0,0 +1,58 @@
+//! Compares re-planning the DCT on every image against reusing one planned [`BlockDct`]
+//!
+//! Run with `cargo bench --bench dct_planning`
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use color_watermark::dct::{self, BlockDct};
+
+const NUM_IMAGES: usize = 100;
+const WIDTH: usize = 64;
+const HEIGHT: usize = 64;
+
+fn make_blocks(seed: usize) -> Vec<Vec<f32>> {
+    let mut plane: Vec<u8> = (0..WIDTH * HEIGHT)
+        .map(|i| ((i * 31 + seed * 17) % 256) as u8)
+        .collect();
+    dct::split_into_blocks(&mut plane, WIDTH, HEIGHT, 8).blocks
+}
+
+fn run(label: &str, mut f: impl FnMut(&mut [Vec<f32>])) -> Duration {
+    let mut batch: Vec<_> = (0..NUM_IMAGES).map(make_blocks).collect();
+
+    let start = Instant::now();
+    for blocks in batch.iter_mut() {
+        f(blocks);
+    }
+    let elapsed = start.elapsed();
+    black_box(&batch);
+
+    println!(
+        "{:<24} {:>10.3?} total, {:>10.3?} per image",
+        label,
+        elapsed,
+        elapsed / NUM_IMAGES as u32
+    );
+    elapsed
+}
+
+fn main() {
+    println!("{} images of {} * {}", NUM_IMAGES, WIDTH, HEIGHT);
+
+    let replanned = run("plan on every call", |blocks| {
+        dct::apply_2d_dct(blocks, 8);
+        dct::apply_2d_idct(blocks, 8);
+    });
+
+    let block_dct = BlockDct::new(8);
+    let reused = run("reused BlockDct", |blocks| {
+        block_dct.forward(blocks);
+        block_dct.inverse(blocks);
+    });
+
+    println!(
+        "planning overhead: {:.3?} per image",
+        replanned.saturating_sub(reused) / NUM_IMAGES as u32
+    );
+}
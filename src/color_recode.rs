@@ -1,62 +1,155 @@
-use bitvec::prelude::BitVec;
-use image::{DynamicImage, GenericImage};
+use bitvec::prelude::{BitSlice, BitVec};
+use image::{ColorType, DynamicImage, GenericImageView, GrayImage, Pixel, RgbImage};
 
-/// Recodes the original picture color info into 3-bit color representation scheme
-pub fn recode_to_3bits(image: &DynamicImage) -> BitVec {
-    let mut ret = BitVec::new();
-
-    for byte in image.as_bytes() {
-        if *byte > 127 {
-            ret.push(true);
-        } else {
-            ret.push(false);
+use crate::colorspace;
+
+/// How the watermark pixels are recoded into bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WatermarkKind {
+    /// 3 channels per pixel, see [`recode`]
+    Rgb,
+    /// 1 channel per pixel, see [`recode_gray_to_bits`]
+    Gray,
+}
+
+impl WatermarkKind {
+    /// Gray for luma images, with or without alpha, Rgb otherwise
+    pub fn detect(image: &DynamicImage) -> Self {
+        match image.color() {
+            ColorType::L8 | ColorType::La8 | ColorType::L16 | ColorType::La16 => Self::Gray,
+            _ => Self::Rgb,
         }
     }
 
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Rgb => 3,
+            Self::Gray => 1,
+        }
+    }
+
+    pub fn bits_per_pixel(&self, bits_per_channel: u8) -> usize {
+        self.channels() * bits_per_channel as usize
+    }
+
+    /// Recodes the image keeping the top `bits_per_channel` bits of each channel
+    ///
+    /// Panics unless `bits_per_channel` is in `1..=8`
+    pub fn recode(&self, image: &DynamicImage, bits_per_channel: u8) -> BitVec {
+        match self {
+            Self::Rgb => recode(image, bits_per_channel),
+            Self::Gray => quantize(image.to_luma8().as_raw(), bits_per_channel),
+        }
+    }
+
+    /// Reconstructs a `width * height` image from bits recoded with this kind
+    ///
+    /// Panics unless `bits_per_channel` is in `1..=8`
+    pub fn reconstruct(
+        &self,
+        bits: &BitVec,
+        width: u32,
+        height: u32,
+        bits_per_channel: u8,
+    ) -> DynamicImage {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            width,
+            height,
+            kind = ?self,
+            bits = bits.len(),
+            ones = bits.count_ones(),
+            "reconstructing watermark"
+        );
+        match self {
+            Self::Rgb => recode_to_rgb_with(bits, width, height, bits_per_channel),
+            Self::Gray => {
+                let mut image = GrayImage::new(width, height);
+                dequantize(bits, bits_per_channel, &mut image);
+                DynamicImage::ImageLuma8(image)
+            }
+        }
+    }
+}
+
+/// Keeps the top `depth` bits of each sample, most significant bit first
+fn quantize(samples: &[u8], depth: u8) -> BitVec {
+    assert!((1..=8).contains(&depth), "{} bits per channel", depth);
+    let mut ret = BitVec::with_capacity(samples.len() * depth as usize);
+    for sample in samples {
+        for i in (8 - depth..8).rev() {
+            ret.push((sample >> i) & 1 == 1);
+        }
+    }
     ret
 }
 
+/// Inverse of [`quantize`], spreading the `depth` bit values evenly over `[0, 255]`
+///
+/// Samples without bits are left at 0
+fn dequantize(bits: &BitSlice, depth: u8, samples: &mut [u8]) {
+    assert!((1..=8).contains(&depth), "{} bits per channel", depth);
+    let max = (1_u32 << depth) - 1;
+    for (sample, chunk) in samples.iter_mut().zip(bits.chunks_exact(depth as usize)) {
+        let value = chunk.iter().fold(0, |acc, bit| (acc << 1) | *bit as u32);
+        *sample = (value * 255 / max) as u8;
+    }
+}
+
+/// Recodes the original picture color info keeping the top `bits_per_channel` bits of each channel
+///
+/// Takes any image with 8-bit samples, a DynamicImage or a typed buffer like an RgbImage, converted
+/// to RGB8 first, so exactly `3 * bits_per_channel` bits are emitted per pixel regardless of alpha channel
+/// or bit depth. More bits give a more faithful watermark for less capacity
+///
+/// Panics unless `bits_per_channel` is in `1..=8`
+pub fn recode<I>(image: &I, bits_per_channel: u8) -> BitVec
+where
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    quantize(&colorspace::rgb8_samples(image), bits_per_channel)
+}
+
+/// Recodes the original picture color info into 3-bit color representation scheme
+///
+/// Same as [`recode`] with 1 bit per channel, set for channels above 127
+pub fn recode_to_3bits<I>(image: &I) -> BitVec
+where
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    recode(image, 1)
+}
+
+/// Recode the bits of [`recode`] back to RGB DynamicImage
+///
+/// Panics unless `bits_per_channel` is in `1..=8`
+pub fn recode_to_rgb_with(
+    bits: &BitVec,
+    width: u32,
+    height: u32,
+    bits_per_channel: u8,
+) -> DynamicImage {
+    let mut image = RgbImage::new(width, height);
+    dequantize(bits, bits_per_channel, &mut image);
+    DynamicImage::ImageRgb8(image)
+}
+
 /// Recode the bits in the 3-bit color representation scheme back to RGB DynamicImage
 pub fn recode_to_rgb(bits: &BitVec, width: u32, height: u32) -> DynamicImage {
-    let mut image = DynamicImage::new_rgb8(width, height);
-    let mut x = 0;
-    let mut y = 0;
-
-    let mut r = 0;
-    let mut g = 0;
-    for (i, bit) in bits.iter().enumerate() {
-        if *bit.as_ref() {
-            match i % 3 {
-                0 => {
-                    r = 255;
-                }
-                1 => {
-                    g = 255;
-                }
-                _ => {
-                    let b = 255;
-                    image.put_pixel(x, y, image::Rgba([r, g, b, 255]));
-                    y += if x + 1 == width { 1 } else { 0 };
-                    x = (x + 1) % width;
-                }
-            };
-        } else {
-            match i % 3 {
-                0 => {
-                    r = 0;
-                }
-                1 => {
-                    g = 0;
-                }
-                _ => {
-                    let b = 0;
-                    image.put_pixel(x, y, image::Rgba([r, g, b, 255]));
-                    y += if x + 1 == width { 1 } else { 0 };
-                    x = (x + 1) % width;
-                }
-            };
-        }
-    }
+    recode_to_rgb_with(bits, width, height, 1)
+}
+
+/// Recodes the luma of the picture into 1 bit per pixel, set for luma above 127
+pub fn recode_gray_to_bits(image: &DynamicImage) -> BitVec {
+    WatermarkKind::Gray.recode(image, 1)
+}
 
-    image
+/// Recode the bits of [`recode_gray_to_bits`] back to a black and white luma DynamicImage
+///
+/// Missing bits are left black
+pub fn recode_bits_to_gray(bits: &BitVec, width: u32, height: u32) -> DynamicImage {
+    WatermarkKind::Gray.reconstruct(bits, width, height, 1)
 }
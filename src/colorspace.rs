@@ -1,31 +1,173 @@
-use image::{DynamicImage, GenericImage, GenericImageView};
-use yuvutils_rs::{
-    BufferStoreMut, YuvConversionMode, YuvPlanarImage, YuvPlanarImageMut, YuvRange,
-    YuvStandardMatrix,
+use std::ops::{Deref, DerefMut};
+
+use image::{
+    ColorType, DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgb, RgbImage, RgbaImage,
 };
+use yuvutils_rs::{BufferStoreMut, YuvConversionMode, YuvPlanarImage, YuvPlanarImageMut};
+
+pub use yuvutils_rs::{YuvRange, YuvStandardMatrix};
+
+/// Resolution of the chroma planes relative to the luma plane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChromaSubsampling {
+    /// One chroma sample per pixel, the default
+    Yuv444,
+    /// One chroma sample per 2 * 2 pixels, as in most video pipelines
+    ///
+    /// Halving the chroma resolution blurs colors through the round trip, and the chroma planes are too
+    /// small to carry bits, so only the Y plane can be watermarked. 16-bit images are converted to 8 bits
+    Yuv420,
+}
+
+/// Coefficients, value range and chroma subsampling used for the RGB <-> YCbCr conversion
+///
+/// Defaults to Bt709 with full range, without subsampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColorConfig {
+    #[cfg_attr(feature = "serde", serde(with = "YuvStandardMatrixDef"))]
+    pub matrix: YuvStandardMatrix,
+    /// Full range spans 0 to 255, limited range, the TV range of video, puts luma in 16 to 235 and chroma
+    /// in 16 to 240
+    ///
+    /// Embedding and extraction must use the same range. Limited luma spans 219 levels instead of 256, so
+    /// the same step size moves the RGB samples 255 / 219 times further, about 1.3 dB of PSNR. Scale the step
+    /// by 219 / 255 for the distortion of full range
+    #[cfg_attr(feature = "serde", serde(with = "YuvRangeDef"))]
+    pub range: YuvRange,
+    pub subsampling: ChromaSubsampling,
+}
+
+/// Serde mirror of [`YuvStandardMatrix`], which yuvutils doesn't serialize
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(remote = "YuvStandardMatrix")]
+enum YuvStandardMatrixDef {
+    Bt601,
+    Bt709,
+    Bt2020,
+    Smpte240,
+    Bt470_6,
+    Custom(f32, f32),
+}
+
+/// Serde mirror of [`YuvRange`]
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(remote = "YuvRange")]
+enum YuvRangeDef {
+    Limited,
+    Full,
+}
+
+impl Default for ColorConfig {
+    fn default() -> Self {
+        Self {
+            matrix: YuvStandardMatrix::Bt709,
+            range: YuvRange::Full,
+            subsampling: ChromaSubsampling::Yuv444,
+        }
+    }
+}
+
+macro_rules! plane {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name<S = u8>(pub Vec<S>);
+
+        impl<S> Deref for $name<S> {
+            type Target = Vec<S>;
+
+            fn deref(&self) -> &Vec<S> {
+                &self.0
+            }
+        }
 
-/// Takes an RGB DynamicImage and convert to YCrCb
+        impl<S> DerefMut for $name<S> {
+            fn deref_mut(&mut self) -> &mut Vec<S> {
+                &mut self.0
+            }
+        }
+    };
+}
+
+plane!(
+    /// Luma plane, one sample per pixel in row-major order
+    YPlane
+);
+plane!(
+    /// Blue-difference chroma plane, the U plane of yuvutils, one sample per pixel in row-major order,
+    /// or per 2 * 2 pixels with [`ChromaSubsampling::Yuv420`]
+    CbPlane
+);
+plane!(
+    /// Red-difference chroma plane, the V plane of yuvutils, one sample per pixel in row-major order,
+    /// or per 2 * 2 pixels with [`ChromaSubsampling::Yuv420`]
+    CrPlane
+);
+
+/// The planes of a YCbCr image together with its dimensions, in pixels
+///
+/// Returned by the `convert_to_YCbCr` conversions and taken back by the `convert_to_RGB` ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YCbCrPlanes<S = u8> {
+    pub y: YPlane<S>,
+    pub cb: CbPlane<S>,
+    pub cr: CrPlane<S>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Takes an image with 8-bit samples, e.g. a DynamicImage or an RgbImage, and convert to YCrCb
+#[allow(non_snake_case)]
+pub fn convert_to_YCbCr<I>(image: &I) -> YCbCrPlanes
+where
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    convert_to_YCbCr_with(image, &ColorConfig::default())
+}
+
+/// Same as [`convert_to_YCbCr_with`] and the default config, returning the planes as a tuple
 ///
 /// Return value: `(y_plane, cb_plane, cr_plane)`
+#[deprecated(note = "use convert_to_YCbCr, whose planes carry the image dimensions")]
 #[allow(non_snake_case)]
-pub fn convert_to_YCbCr(image: &DynamicImage) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+pub fn convert_to_YCbCr_tuple(image: &DynamicImage) -> (YPlane, CbPlane, CrPlane) {
+    let planes = convert_to_YCbCr_with(image, &ColorConfig::default());
+    (planes.y, planes.cb, planes.cr)
+}
+
+/// Same as [`convert_to_YCbCr`], with the given matrix, range and chroma subsampling
+#[allow(non_snake_case)]
+pub fn convert_to_YCbCr_with<I>(image: &I, config: &ColorConfig) -> YCbCrPlanes
+where
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
     let (width, height) = image.dimensions();
-    println!("convert_to_YCbCr image dimensions: {}x{}", width, height);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(width, height, subsampling = ?config.subsampling, "converting to YCbCr");
 
-    let buffer_size = (width * height) as usize;
-    let mut y: Vec<u8> = vec![0_u8; buffer_size];
-    let mut cr: Vec<u8> = vec![0_u8; buffer_size];
-    let mut cb: Vec<u8> = vec![0_u8; buffer_size];
+    let subsampled = config.subsampling == ChromaSubsampling::Yuv420;
+    let (rgb_stride, y_stride, cb_stride, cr_stride) = get_strides(width, subsampled);
+    let chroma_height = if subsampled {
+        height.div_ceil(2)
+    } else {
+        height
+    };
+    let mut y: Vec<u8> = vec![0_u8; buffer_len(y_stride, height, 1)];
+    let mut cr: Vec<u8> = vec![0_u8; buffer_len(cr_stride, chroma_height, 1)];
+    let mut cb: Vec<u8> = vec![0_u8; buffer_len(cb_stride, chroma_height, 1)];
 
     let y_plane = BufferStoreMut::Borrowed(y.as_mut_slice());
     let u_plane = BufferStoreMut::Borrowed(cb.as_mut_slice());
     let v_plane = BufferStoreMut::Borrowed(cr.as_mut_slice());
 
     // => RGB8
-    let rgb_image = image.to_rgb8();
-    let rgb = rgb_image.as_raw();
-    let (width, height) = image.dimensions();
-    let (rgb_stride, y_stride, cb_stride, cr_stride) = get_strides(width, false);
+    let rgb = rgb8_samples(image);
 
     let mut planar = YuvPlanarImageMut {
         y_plane,
@@ -38,75 +180,374 @@ pub fn convert_to_YCbCr(image: &DynamicImage) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
         height,
     };
 
-    yuvutils_rs::rgb_to_yuv444(
+    let convert = if subsampled {
+        yuvutils_rs::rgb_to_yuv420
+    } else {
+        yuvutils_rs::rgb_to_yuv444
+    };
+    convert(
         &mut planar,
-        rgb,
+        &rgb,
         rgb_stride,
-        YuvRange::Full,
-        YuvStandardMatrix::Bt709,
+        config.range,
+        config.matrix,
         YuvConversionMode::Balanced,
     )
     .unwrap();
 
-    (y, cb, cr)
+    YCbCrPlanes {
+        y: YPlane(y),
+        cb: CbPlane(cb),
+        cr: CrPlane(cr),
+        width,
+        height,
+    }
+}
+
+/// RGB samples of an image with 8-bit samples, row after row, alpha dropped and luma repeated
+pub(crate) fn rgb8_samples<I>(image: &I) -> Vec<u8>
+where
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    let (width, height) = image.dimensions();
+    let mut rgb = Vec::with_capacity(buffer_len(width, height, 3));
+    for (_, _, pixel) in image.pixels() {
+        rgb.extend_from_slice(&pixel.to_rgb().0);
+    }
+    rgb
 }
 
 /// Convert YCrCb to RGB DynamicImage
 #[allow(non_snake_case)]
-pub fn convert_to_RGB(
+pub fn convert_to_RGB(planes: &YCbCrPlanes) -> DynamicImage {
+    convert_to_RGB_with(planes, &ColorConfig::default())
+}
+
+/// Same as [`convert_to_RGB_with`] and the default config, taking the dimensions and planes separately
+#[deprecated(note = "use convert_to_RGB with the YCbCrPlanes of convert_to_YCbCr")]
+#[allow(non_snake_case)]
+pub fn convert_to_RGB_tuple(
     width: u32,
     height: u32,
-    y_plane: &[u8],
-    cb_plane: &[u8],
-    cr_plane: &[u8],
+    y_plane: &YPlane,
+    cb_plane: &CbPlane,
+    cr_plane: &CrPlane,
 ) -> DynamicImage {
+    let planes = YCbCrPlanes {
+        y: y_plane.clone(),
+        cb: cb_plane.clone(),
+        cr: cr_plane.clone(),
+        width,
+        height,
+    };
+    convert_to_RGB_with(&planes, &ColorConfig::default())
+}
+
+/// Same as [`convert_to_RGB`], with the given matrix, range and chroma subsampling
+#[allow(non_snake_case)]
+pub fn convert_to_RGB_with(planes: &YCbCrPlanes, config: &ColorConfig) -> DynamicImage {
+    let (width, height) = (planes.width, planes.height);
+    let subsampled = config.subsampling == ChromaSubsampling::Yuv420;
+    let (rgb_stride, y_stride, cb_stride, cr_stride) = get_strides(width, subsampled);
+    let mut rgb = vec![0_u8; buffer_len(width, height, 3)];
+
+    let planar = YuvPlanarImage {
+        y_plane: &planes.y.0,
+        y_stride,
+        u_plane: &planes.cb.0,
+        u_stride: cb_stride,
+        v_plane: &planes.cr.0,
+        v_stride: cr_stride,
+        width,
+        height,
+    };
+    let convert = if subsampled {
+        yuvutils_rs::yuv420_to_rgb
+    } else {
+        yuvutils_rs::yuv444_to_rgb
+    };
+    convert(
+        &planar,
+        rgb.as_mut_slice(),
+        rgb_stride,
+        config.range,
+        config.matrix,
+    )
+    .unwrap();
+
+    // The buffer holds exactly width * height RGB pixels
+    DynamicImage::ImageRgb8(
+        RgbImage::from_raw(width, height, rgb).expect("RGB buffer matches the image dimensions"),
+    )
+}
+
+/// Whether the image has 16 bits per channel and the config keeps them, see [`ChromaSubsampling::Yuv420`]
+pub fn keeps_16_bit(image: &DynamicImage, config: &ColorConfig) -> bool {
+    is_16_bit(image) && config.subsampling == ChromaSubsampling::Yuv444
+}
+
+/// Whether the image has 16 bits per channel, which the `16` conversions keep
+pub fn is_16_bit(image: &DynamicImage) -> bool {
+    matches!(
+        image.color(),
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16
+    )
+}
+
+/// Whether the image is grayscale, with or without alpha, so its luma is the Y plane
+pub fn is_luma(image: &DynamicImage) -> bool {
+    matches!(
+        image.color(),
+        ColorType::L8 | ColorType::La8 | ColorType::L16 | ColorType::La16
+    )
+}
+
+/// Same as [`convert_to_YCbCr_with`], keeping 16 bits per sample
+#[allow(non_snake_case)]
+pub fn convert_to_YCbCr16_with(image: &DynamicImage, config: &ColorConfig) -> YCbCrPlanes<u16> {
+    let (width, height) = image.dimensions();
+    #[cfg(feature = "tracing")]
+    tracing::debug!(width, height, "converting to 16-bit YCbCr");
+
+    let buffer_size = buffer_len(width, height, 1);
+    let mut y: Vec<u16> = vec![0_u16; buffer_size];
+    let mut cr: Vec<u16> = vec![0_u16; buffer_size];
+    let mut cb: Vec<u16> = vec![0_u16; buffer_size];
+
+    let rgb_image = image.to_rgb16();
     let (rgb_stride, y_stride, cb_stride, cr_stride) = get_strides(width, false);
-    let mut rgb = vec![0_u8; (width * height * 3) as usize];
+
+    let mut planar = YuvPlanarImageMut {
+        y_plane: BufferStoreMut::Borrowed(y.as_mut_slice()),
+        y_stride,
+        u_plane: BufferStoreMut::Borrowed(cb.as_mut_slice()),
+        u_stride: cb_stride,
+        v_plane: BufferStoreMut::Borrowed(cr.as_mut_slice()),
+        v_stride: cr_stride,
+        width,
+        height,
+    };
+
+    // I416 is 4:4:4 with 16-bit samples, strides count samples, not bytes
+    yuvutils_rs::rgb16_to_i416(
+        &mut planar,
+        rgb_image.as_raw(),
+        rgb_stride,
+        config.range,
+        config.matrix,
+    )
+    .unwrap();
+
+    YCbCrPlanes {
+        y: YPlane(y),
+        cb: CbPlane(cb),
+        cr: CrPlane(cr),
+        width,
+        height,
+    }
+}
+
+/// Same as [`convert_to_RGB_with`] for 16-bit planes, returns a 16-bit RGB DynamicImage
+#[allow(non_snake_case)]
+pub fn convert_to_RGB16_with(planes: &YCbCrPlanes<u16>, config: &ColorConfig) -> DynamicImage {
+    let (width, height) = (planes.width, planes.height);
+    let (rgb_stride, y_stride, cb_stride, cr_stride) = get_strides(width, false);
+    let mut rgb = vec![0_u16; buffer_len(width, height, 3)];
 
     let planar = YuvPlanarImage {
-        y_plane,
+        y_plane: &planes.y.0,
         y_stride,
-        u_plane: cb_plane,
+        u_plane: &planes.cb.0,
         u_stride: cb_stride,
-        v_plane: cr_plane,
+        v_plane: &planes.cr.0,
         v_stride: cr_stride,
         width,
         height,
     };
-    yuvutils_rs::yuv444_to_rgb(
+    yuvutils_rs::i416_to_rgb16(
         &planar,
         rgb.as_mut_slice(),
         rgb_stride,
-        YuvRange::Full,
-        YuvStandardMatrix::Bt709,
+        config.range,
+        config.matrix,
     )
     .unwrap();
 
-    let mut img = DynamicImage::new_rgb8(width, height);
+    DynamicImage::ImageRgb16(
+        ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, rgb)
+            .expect("RGB buffer matches the image dimensions"),
+    )
+}
 
-    for x in 0..height {
-        for y in 0..width {
-            let r = rgb[(x * width * 3 + y * 3) as usize];
-            let g = rgb[(x * width * 3 + y * 3 + 1) as usize];
-            let b = rgb[(x * width * 3 + y * 3 + 2) as usize];
-            let a = 255;
-            img.put_pixel(y, x, image::Rgba([r, g, b, a]));
-        }
+/// The planes of an HSV image together with its dimensions, in pixels
+///
+/// Only V is quantized, hue and saturation keep their full precision so that [`convert_HSV_to_RGB`]
+/// gives back the pixels [`convert_to_HSV`] was given, and a changed V scales its pixel keeping its color
+#[derive(Debug, Clone, PartialEq)]
+pub struct HsvPlanes {
+    /// Hue in degrees in `[0, 360)`, 0 for grays
+    pub h: Vec<f32>,
+    /// Saturation in `[0, 1]`, 0 for grays
+    pub s: Vec<f32>,
+    /// Value, the largest of the RGB samples
+    pub v: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Takes an image with 8-bit samples, e.g. a DynamicImage or an RgbImage, and convert to HSV
+#[allow(non_snake_case)]
+pub fn convert_to_HSV<I>(image: &I) -> HsvPlanes
+where
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    let (width, height) = image.dimensions();
+    #[cfg(feature = "tracing")]
+    tracing::debug!(width, height, "converting to HSV");
+
+    let len = buffer_len(width, height, 1);
+    let mut planes = HsvPlanes {
+        h: Vec::with_capacity(len),
+        s: Vec::with_capacity(len),
+        v: Vec::with_capacity(len),
+        width,
+        height,
+    };
+    for rgb in rgb8_samples(image).chunks_exact(3) {
+        let max = rgb.iter().copied().max().unwrap();
+        let min = rgb.iter().copied().min().unwrap();
+        let [r, g, b] = [rgb[0], rgb[1], rgb[2]].map(f32::from);
+        let delta = f32::from(max - min);
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == rgb[0] {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == rgb[1] {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        planes.h.push(hue);
+        planes.s.push(if max == 0 {
+            0.0
+        } else {
+            delta / f32::from(max)
+        });
+        planes.v.push(max);
     }
+    planes
+}
 
-    img
+/// Convert HSV to RGB DynamicImage
+#[allow(non_snake_case)]
+pub fn convert_HSV_to_RGB(planes: &HsvPlanes) -> DynamicImage {
+    let mut rgb = Vec::with_capacity(planes.v.len() * 3);
+    for ((&hue, &saturation), &value) in planes.h.iter().zip(&planes.s).zip(&planes.v) {
+        let value = f32::from(value);
+        let chroma = value * saturation;
+        let sector = hue / 60.0;
+        let x = chroma * (1.0 - (sector.rem_euclid(2.0) - 1.0).abs());
+        let (r, g, b) = match sector as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let min = value - chroma;
+        rgb.extend([r, g, b].map(|c| (c + min).round().clamp(0.0, 255.0) as u8));
+    }
+
+    DynamicImage::ImageRgb8(
+        RgbImage::from_raw(planes.width, planes.height, rgb)
+            .expect("RGB buffer matches the image dimensions"),
+    )
+}
+
+/// Returns the alpha plane of the image, or `None` if the image has no alpha channel
+///
+/// The YCbCr conversion only deals with color, use [`attach_alpha`] to put the plane back afterwards
+pub fn split_alpha(image: &DynamicImage) -> Option<Vec<u8>> {
+    if !image.color().has_alpha() {
+        return None;
+    }
+
+    Some(image.to_rgba8().pixels().map(|p| p[3]).collect())
+}
+
+/// Reattaches an alpha plane obtained from [`split_alpha`] to an RGB DynamicImage
+///
+/// Returns the image unchanged if `alpha` is `None`
+pub fn attach_alpha(image: DynamicImage, alpha: Option<&[u8]>) -> DynamicImage {
+    let Some(alpha) = alpha else {
+        return image;
+    };
+
+    let mut rgba: RgbaImage = image.to_rgba8();
+    for (pixel, &a) in rgba.pixels_mut().zip(alpha) {
+        pixel[3] = a;
+    }
+
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Same as [`split_alpha`], keeping 16 bits per sample
+pub fn split_alpha16(image: &DynamicImage) -> Option<Vec<u16>> {
+    if !image.color().has_alpha() {
+        return None;
+    }
+
+    Some(image.to_rgba16().pixels().map(|p| p[3]).collect())
+}
+
+/// Same as [`attach_alpha`] for an alpha plane obtained from [`split_alpha16`], returns a 16-bit image
+pub fn attach_alpha16(image: DynamicImage, alpha: Option<&[u16]>) -> DynamicImage {
+    let Some(alpha) = alpha else {
+        return image;
+    };
+
+    let mut rgba = image.to_rgba16();
+    for (pixel, &a) in rgba.pixels_mut().zip(alpha) {
+        pixel[3] = a;
+    }
+
+    DynamicImage::ImageRgba16(rgba)
+}
+
+/// Number of samples of a plane of `rows` rows of `stride` pixels with `channels` samples each
+///
+/// Computed in `usize`, as `u32` products overflow for large images. Panics if it overflows `usize` too,
+/// hosts checked against [`crate::MAX_HOST_PIXELS`] never do
+fn buffer_len(stride: u32, rows: u32, channels: usize) -> usize {
+    (stride as usize)
+        .checked_mul(rows as usize)
+        .and_then(|samples| samples.checked_mul(channels))
+        .expect("plane size overflows usize")
 }
 
 /// Calculates and returns the strides needed for colorspace conversion
 ///
-/// Return value: `(rgb_stride, y_stride, cb_stride, cr_stride)`
+/// Return value: `(rgb_stride, y_stride, cb_stride, cr_stride)`, in samples rather than bytes, so the
+/// same for 8-bit and 16-bit planes. Rows are packed without padding, as in the planes of [`YCbCrPlanes`]
+///
+/// set downsample to true when using 420 conversion, false when using 444. With 4:4:4 every plane has a
+/// sample per pixel, with 4:2:0 the chroma planes have one per 2 pixels of a row, rounded up for odd widths,
+/// and one row per 2 rows of the image, so `height.div_ceil(2)` rows
+///
+/// ```
+/// use color_watermark::colorspace::get_strides;
 ///
-/// set downsample to true when using 422 conversion, false when using 444
-fn get_strides(width: u32, downsample: bool) -> (u32, u32, u32, u32) {
+/// assert_eq!(get_strides(101, false), (303, 101, 101, 101));
+/// assert_eq!(get_strides(101, true), (303, 101, 51, 51));
+/// ```
+pub fn get_strides(width: u32, downsample: bool) -> (u32, u32, u32, u32) {
     let rgb_stride = width * 3; // 3 bytes per pixel for RGB
     let y_stride = width; // 1 byte per pixel for Y
-    let cb_stride = if downsample { (width + 1) / 2 } else { width }; // subsampled horizontally
-    let cr_stride = if downsample { (width + 1) / 2 } else { width }; // subsampled horizontally
+    let cb_stride = if downsample { width.div_ceil(2) } else { width }; // subsampled horizontally
+    let cr_stride = if downsample { width.div_ceil(2) } else { width }; // subsampled horizontally
 
     (rgb_stride, y_stride, cb_stride, cr_stride)
 }
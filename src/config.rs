@@ -0,0 +1,427 @@
+use image::math::Rect;
+
+use crate::color_recode::WatermarkKind;
+use crate::colorspace::{ColorConfig, YuvRange};
+use crate::dct::{QuantTable, BLK_WIDTH};
+#[cfg(feature = "ecc")]
+use crate::ecc::Ecc;
+use crate::qim::{AdaptiveStep, CoefficientMask, Preset};
+
+/// Step size of [`EmbedConfig::fragile`]
+pub const FRAGILE_STEP: f32 = 32.0;
+
+/// The YCbCr planes the watermark can be embedded into
+///
+/// Chroma planes add capacity, but are less robust: chroma subsampling, as done by JPEG or
+/// video codecs, averages neighbouring chroma samples and wipes most of their embedded bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Channel {
+    /// Luminance, the most robust choice and the default
+    Y,
+    /// Blue-difference chroma
+    Cb,
+    /// Red-difference chroma
+    Cr,
+}
+
+/// Deserializes the planes of [`EmbedConfig::channels`], rejecting lists that are empty,
+/// out of Y, Cb, Cr order or with duplicates, as [`EmbedConfig::channels`] never builds them
+#[cfg(feature = "serde")]
+fn deserialize_channels<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Channel>, D::Error> {
+    let channels = <Vec<Channel> as serde::Deserialize>::deserialize(deserializer)?;
+    if channels.is_empty() {
+        return Err(serde::de::Error::custom("no channel to embed into"));
+    }
+    if channels.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(serde::de::Error::custom(format!(
+            "channels {:?} not in Y, Cb, Cr order without duplicates",
+            channels
+        )));
+    }
+    Ok(channels)
+}
+
+/// Color space whose brightness plane carries the watermark
+///
+/// Color grading rarely moves both the same way: a saturation or hue edit keeps V, the largest of the
+/// RGB samples, but shifts Y, while a white balance or channel curve keeps Y about the same but can move V
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorDomain {
+    /// The YCbCr planes of [`EmbedConfig::channels`], the default
+    ///
+    /// JPEG and video codecs quantize these same planes, so the watermark degrades gracefully under them
+    YCbCr,
+    /// The V plane of HSV, [`EmbedConfig::channels`] must be `[Channel::Y]`
+    ///
+    /// Survives saturation and hue edits untouched, and V round trips through 8-bit RGB without the
+    /// rounding of the YCbCr conversion. But V follows a single channel per pixel, so JPEG, which
+    /// quantizes YCbCr, damages it more, and its changes are more visible in saturated blue areas.
+    /// Only 8-bit samples, 16-bit hosts come out 8-bit
+    Hsv,
+}
+
+/// Block transform whose coefficients carry the watermark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Transform {
+    /// Discrete cosine transform, the default
+    Dct,
+    /// Walsh-Hadamard transform, see [`crate::wht`]
+    ///
+    /// Only adds and subtracts samples, so it is faster, but its square basis functions leave blockier
+    /// artifacts and JPEG, which quantizes DCT coefficients, damages its bits more
+    Hadamard,
+    /// Block DCT of sub-blocks, then DCT of their DC coefficients, see [`crate::multilevel`]
+    ///
+    /// Embeds into the coarse scale of `block_size * block_size` areas, so it survives downscaling better
+    /// at the cost of capacity. Meant for blocks of 64 * 64 or more, 8 * 8 blocks are the plain DCT
+    MultiLevel,
+}
+
+/// How many times the watermark is written into the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Redundancy {
+    /// A single copy, the default
+    None,
+    /// `n` consecutive copies, each bit is majority voted over the copies at extraction
+    ///
+    /// Trades payload size for robustness against local damage. Use an odd `n` so votes can't tie,
+    /// `Repeat(0)` behaves like `Repeat(1)`
+    Repeat(usize),
+}
+
+impl Redundancy {
+    /// Number of copies written
+    pub fn copies(&self) -> usize {
+        match self {
+            Redundancy::None => 1,
+            Redundancy::Repeat(n) => (*n).max(1),
+        }
+    }
+}
+
+/// How the bit stream is laid out over the planes of [`EmbedConfig::channels`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelMode {
+    /// The stream fills the planes one after another, each plane adds the capacity of the host, the default
+    Capacity,
+    /// Every plane carries the whole stream, the capacity stays that of a single plane
+    ///
+    /// Extraction soft votes over the planes, each weighted by how close its coefficients sit to the
+    /// lattices, so a plane wiped by chroma subsampling or a color edit barely counts and a single
+    /// surviving plane still recovers the watermark
+    Diversity,
+}
+
+/// Parameters of the embedding, the same config must be used for extraction
+///
+/// ```
+/// use color_watermark::{Channel, EmbedConfig};
+///
+/// let config = EmbedConfig::new(123456).step_size(100.0).channel(Channel::Y);
+/// assert_eq!(config.step_size, 100.0);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EmbedConfig {
+    /// Seed of the dither signal
+    pub key: u64,
+    /// Quantization step of the QIM, higher is more robust but more visible
+    pub step_size: f32,
+    /// Distortion compensation factor in `(0, 1]`, 1 is plain QIM
+    ///
+    /// Each coefficient only moves `alpha` of the way to its lattice point, so dividing the step size
+    /// by `alpha` keeps the distortion about the same while gaining robustness against noise
+    pub alpha: f32,
+    /// Number of interleaved QIM lattices, a power of two, each coefficient carries `log2(levels)` bits
+    ///
+    /// 2 is binary QIM, the default. 4 levels double the bits per block, but the lattices get twice as
+    /// close, so the step size must double for the same robustness, see [`crate::qim::embed_wm_mary`]
+    pub levels: usize,
+    /// Decision boundary of binary extraction, in steps from the lattice of bit 0, see [`crate::qim::extract_wm_with_range`]
+    ///
+    /// `None`, the default, decodes each coefficient from the closest lattice like M-ary QIM does, the same as
+    /// a range of 0.25. The 0.1 default of the plain QIM function reads every 0 moved a tenth of a step by
+    /// the rounding to 8-bit samples as a 1. Only with 2 levels
+    pub acceptable_range: Option<f32>,
+    /// Derive distinct dithers for every block from the key and the block index
+    ///
+    /// With a single dither array for all blocks, an attacker averaging many blocks can estimate it
+    /// and forge or remove the watermark. Off by default, as deriving the dithers per block is slower
+    pub per_block_dither: bool,
+    /// Scale the step of each block by its energy, a single global step when `None`
+    pub adaptive_step: Option<AdaptiveStep>,
+    /// Block transform, the DCT by default
+    pub transform: Transform,
+    /// Quantize the 8 * 8 DCT blocks with this JPEG-style table before embedding, and dequantize after
+    ///
+    /// The QIM then works on the coefficients a JPEG encoder with the same table rounds to integers,
+    /// so the step size counts quantization steps instead of DCT units: each coefficient gets a step
+    /// proportional to its table entry, and JPEG moves it by at most half a step of the table. A step
+    /// size of 2 or more survives a JPEG encode with the same table, up to the rounding of the pixels.
+    /// Not with the Hadamard transform or other block sizes, `None` by default
+    pub quant_table: Option<QuantTable>,
+    /// Keep the mean of every watermarked block, so flat regions don't drift into visible blockiness
+    ///
+    /// Masks can't select the DC coefficient, and the samples of each block are adjusted after the inverse
+    /// transform so that, once rounded, they keep their original mean. Clamped samples of saturated blocks
+    /// can still shift it. Off by default
+    pub preserve_dc: bool,
+    /// Planes carrying the watermark, always in Y, Cb, Cr order without duplicates
+    ///
+    /// The bit stream fills the planes one after another, each plane adds the capacity of the host,
+    /// unless [`Self::channel_mode`] repeats it in every plane
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_channels"))]
+    pub channels: Vec<Channel>,
+    /// Whether the planes add capacity or carry copies of the stream
+    pub channel_mode: ChannelMode,
+    /// Width of the square DCT blocks, a power of two of at least 8
+    ///
+    /// Only whole blocks of the host carry bits, larger blocks carry the same number of bits,
+    /// so capacity drops with the square of the block size. Coefficients grow with the block size,
+    /// about 4 times the step size keeps 16 * 16 blocks as robust as 8 * 8 ones
+    pub block_size: usize,
+    /// Only the blocks lying entirely inside this rectangle of the host carry bits, all of them when `None`
+    ///
+    /// Blocks stay aligned to the block grid of the whole image, so the region should start at multiples
+    /// of the block size to not waste capacity. Pixels outside it are left untouched, and the capacity
+    /// is that of the blocks inside it
+    pub region: Option<Rect>,
+    /// Spread the bit stream over the blocks in a key-derived order instead of the raster order
+    ///
+    /// The watermark no longer sits in the top rows of the host, where a crop removes it, and damage to
+    /// a region scatters over the watermark instead of wiping a part of it. Off by default
+    pub permute_blocks: bool,
+    /// Skip the blocks whose [energy](crate::qim::block_energy) is below this level, every block carries bits when `None`
+    ///
+    /// Flat blocks, such as skies, show the embedding the most. The energy leaves out the masked coefficients,
+    /// so extraction skips the same blocks of the watermarked image, but the capacity then depends on the
+    /// host, and rounding the embedded samples moves the energy of the carriers by a fraction of a level,
+    /// enough to judge blocks right at the threshold differently. It grows with the block size, 8 * 8
+    /// blocks of photographs are mostly between 2 and 40. Only with the Y channel of grayscale hosts, the
+    /// YCbCr round trip of color hosts also moves the energy of the skipped blocks
+    pub min_block_energy: Option<f32>,
+    /// Coefficients of each block carrying the watermark bits, scaled to the block size
+    ///
+    /// Several watermarks can be layered by embedding them one after another with their own key and
+    /// masks that don't [overlap](CoefficientMask::overlaps). Each layer keeps the full capacity, but
+    /// every layer modifies its positions in every block, costing PSNR, and the 63 AC coefficients
+    /// bound the number of layers, 5 with masks of [`crate::qim::MASK_LEN`] positions. Layers can't use [`Self::adaptive_step`], whose block energy
+    /// includes the coefficients of the other layers. [`crate::embed_with_usage`] rejects overlapping layers
+    pub mask: CoefficientMask,
+    /// Matrix and range of the YCbCr conversion
+    pub color: ColorConfig,
+    /// Color space carrying the watermark, YCbCr by default
+    pub color_domain: ColorDomain,
+    /// Store the watermark dimensions in the leading blocks, see [`crate::extract_with_header`]
+    ///
+    /// The header uses a few blocks of capacity, so it is off by default
+    pub header: bool,
+    /// Append an authentication tag of the watermark bits, keyed by the key, see [`crate::extract_authenticated`]
+    ///
+    /// Tells a recovered watermark from the noise read out of an image without it, at the cost of
+    /// 64 bits of capacity. Off by default
+    pub authenticate: bool,
+    /// How the watermark is recoded into bits
+    ///
+    /// When `None`, embedding detects grayscale watermarks and extraction assumes RGB unless
+    /// the header says otherwise
+    pub watermark_kind: Option<WatermarkKind>,
+    /// Top bits of each watermark channel embedded, in `1..=8`
+    ///
+    /// 1 reduces each channel to 0 or 255, more bits keep gradients at the cost of capacity
+    pub bits_per_channel: u8,
+    /// Number of copies of the header and watermark
+    pub redundancy: Redundancy,
+    /// Error correcting code over the watermark bits, the header is left uncoded
+    #[cfg(feature = "ecc")]
+    pub ecc: Ecc,
+}
+
+impl EmbedConfig {
+    /// Default config with the given key: step size 50, plain binary QIM, DCT, Y channel, 8 * 8 blocks, mid-frequency mask, Bt709 full range
+    pub fn new(key: u64) -> Self {
+        Self {
+            key,
+            step_size: 50.0,
+            alpha: 1.0,
+            levels: 2,
+            acceptable_range: None,
+            per_block_dither: false,
+            adaptive_step: None,
+            transform: Transform::Dct,
+            quant_table: None,
+            preserve_dc: false,
+            channels: vec![Channel::Y],
+            channel_mode: ChannelMode::Capacity,
+            block_size: BLK_WIDTH,
+            region: None,
+            permute_blocks: false,
+            min_block_energy: None,
+            mask: CoefficientMask::default(),
+            color: ColorConfig::default(),
+            color_domain: ColorDomain::YCbCr,
+            header: false,
+            authenticate: false,
+            watermark_kind: None,
+            bits_per_channel: 1,
+            redundancy: Redundancy::None,
+            #[cfg(feature = "ecc")]
+            ecc: Ecc::None,
+        }
+    }
+
+    /// Config of fragile watermarks with the given key: step size 32 on the low-frequency mask
+    ///
+    /// About the smallest step rising above the noise of 8-bit samples, so that any edit flips bits,
+    /// see [`crate::embed_fragile`]
+    pub fn fragile(key: u64) -> Self {
+        Self::new(key)
+            .step_size(FRAGILE_STEP)
+            .mask(CoefficientMask::preset(Preset::LowFrequency))
+    }
+
+    pub fn step_size(mut self, step_size: f32) -> Self {
+        self.step_size = step_size;
+        self
+    }
+
+    pub fn alpha(mut self, alpha: f32) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    pub fn levels(mut self, levels: usize) -> Self {
+        self.levels = levels;
+        self
+    }
+
+    pub fn acceptable_range(mut self, acceptable_range: f32) -> Self {
+        self.acceptable_range = Some(acceptable_range);
+        self
+    }
+
+    pub fn per_block_dither(mut self, per_block_dither: bool) -> Self {
+        self.per_block_dither = per_block_dither;
+        self
+    }
+
+    pub fn adaptive_step(mut self, adaptive_step: AdaptiveStep) -> Self {
+        self.adaptive_step = Some(adaptive_step);
+        self
+    }
+
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    pub fn quant_table(mut self, quant_table: QuantTable) -> Self {
+        self.quant_table = Some(quant_table);
+        self
+    }
+
+    pub fn preserve_dc(mut self, preserve_dc: bool) -> Self {
+        self.preserve_dc = preserve_dc;
+        self
+    }
+
+    /// Embeds into a single plane
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channels = vec![channel];
+        self
+    }
+
+    /// Embeds into several planes, filled in Y, Cb, Cr order whatever the order given
+    pub fn channels(mut self, channels: &[Channel]) -> Self {
+        self.channels = channels.to_vec();
+        self.channels.sort();
+        self.channels.dedup();
+        self
+    }
+
+    pub fn channel_mode(mut self, channel_mode: ChannelMode) -> Self {
+        self.channel_mode = channel_mode;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Restricts the watermark to the blocks inside `region`
+    pub fn region(mut self, region: Rect) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    pub fn permute_blocks(mut self, permute_blocks: bool) -> Self {
+        self.permute_blocks = permute_blocks;
+        self
+    }
+
+    pub fn min_block_energy(mut self, min_block_energy: f32) -> Self {
+        self.min_block_energy = Some(min_block_energy);
+        self
+    }
+
+    pub fn mask(mut self, mask: CoefficientMask) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    pub fn color(mut self, color: ColorConfig) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn color_domain(mut self, color_domain: ColorDomain) -> Self {
+        self.color_domain = color_domain;
+        self
+    }
+
+    /// Sets the range of the YCbCr conversion, keeping its matrix and subsampling
+    pub fn yuv_range(mut self, range: YuvRange) -> Self {
+        self.color.range = range;
+        self
+    }
+
+    pub fn header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn authenticate(mut self, authenticate: bool) -> Self {
+        self.authenticate = authenticate;
+        self
+    }
+
+    pub fn watermark_kind(mut self, kind: WatermarkKind) -> Self {
+        self.watermark_kind = Some(kind);
+        self
+    }
+
+    pub fn bits_per_channel(mut self, bits_per_channel: u8) -> Self {
+        self.bits_per_channel = bits_per_channel;
+        self
+    }
+
+    pub fn redundancy(mut self, redundancy: Redundancy) -> Self {
+        self.redundancy = redundancy;
+        self
+    }
+
+    /// Code rate of the error correction, see [`Ecc::rate`]
+    #[cfg(feature = "ecc")]
+    pub fn ecc(mut self, ecc: Ecc) -> Self {
+        self.ecc = ecc;
+        self
+    }
+}
@@ -1,34 +1,99 @@
+use std::sync::Arc;
+
 use image::{DynamicImage, GenericImage, GenericImageView};
-use rustdct::DctPlanner;
+use num_traits::Float;
+use rustdct::{DctNum, DctPlanner, TransformType2And3};
+
+use crate::WatermarkError;
+
+/// Default block width, see [`crate::EmbedConfig::block_size`]
+pub(crate) const BLK_WIDTH: usize = 8;
+
+/// Panics unless `block_size` is a power of two
+fn assert_power_of_two(block_size: usize) {
+    assert!(
+        block_size.is_power_of_two(),
+        "block size {} is not a power of two",
+        block_size
+    );
+}
+
+/// Panics unless `block_size` is a power of two dividing both dimensions
+fn assert_block_size(block_size: usize, width: usize, height: usize) {
+    assert_power_of_two(block_size);
+    assert!(
+        width.is_multiple_of(block_size) && height.is_multiple_of(block_size),
+        "block size {} doesn't divide {} * {}",
+        block_size,
+        width,
+        height
+    );
+}
 
-/// This parameter is just for future possible reuse
-const BLK_WIDTH: usize = 8;
+/// Samples of a color plane, mapped onto the 8-bit `[0, 255]` scale in the DCT blocks
+///
+/// Deeper samples keep their extra precision as fractions, while step sizes keep the same meaning
+/// for every bit depth
+pub trait Sample: Copy {
+    /// Distance between two consecutive samples on the `[0, 255]` scale
+    const STEP: f64;
+    /// The sample on the `[0, 255]` scale
+    fn to_level(self) -> f64;
+    /// Rounds and clamps a value on the `[0, 255]` scale back to a sample
+    fn from_level(level: f64) -> Self;
+}
+
+impl Sample for u8 {
+    const STEP: f64 = 1.0;
+
+    fn to_level(self) -> f64 {
+        self as f64
+    }
+
+    fn from_level(level: f64) -> Self {
+        level.round().clamp(0.0, 255.0) as u8
+    }
+}
+
+impl Sample for u16 {
+    const STEP: f64 = 1.0 / 257.0;
+
+    fn to_level(self) -> f64 {
+        self as f64 / 257.0
+    }
+
+    fn from_level(level: f64) -> Self {
+        (level * 257.0).round().clamp(0.0, 65535.0) as u16
+    }
+}
 
 type BlocksType = Vec<Vec<f32>>;
 type ImageBlocks = (BlocksType, BlocksType, BlocksType);
 
-/// Splits a dynamic image into 8 * 8 blocks
+/// Splits a dynamic image into `block_size * block_size` blocks
 ///
-/// Returns (r, g, b) in form of Vec of (Vec of 64 * f32)
+/// Returns (r, g, b) in form of Vec of (Vec of `block_size * block_size` f32)
 ///
 /// For test purpose only
-pub fn split_image_into_blocks(image: &DynamicImage) -> ImageBlocks {
+pub fn split_image_into_blocks(image: &DynamicImage, block_size: usize) -> ImageBlocks {
     let (width, height) = image.dimensions();
-    println!("Processing a {} * {} image", width, height);
+    assert_block_size(block_size, width as usize, height as usize);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(width, height, block_size, "splitting image into blocks");
 
     let mut blocks_r = Vec::new();
     let mut blocks_g = Vec::new();
     let mut blocks_b = Vec::new();
 
     // ordering by y then x to flush less cache
-    for y in (0..height).step_by(BLK_WIDTH) {
-        for x in (0..width).step_by(BLK_WIDTH) {
+    for y in (0..height).step_by(block_size) {
+        for x in (0..width).step_by(block_size) {
             let mut block_r = Vec::new();
             let mut block_g = Vec::new();
             let mut block_b = Vec::new();
 
-            for j in 0..BLK_WIDTH as u32 {
-                for i in 0..BLK_WIDTH as u32 {
+            for j in 0..block_size as u32 {
+                for i in 0..block_size as u32 {
                     let pixel = image.get_pixel(x + i, y + j).0;
                     block_r.push(pixel[0] as f32);
                     block_g.push(pixel[1] as f32);
@@ -45,17 +110,54 @@ pub fn split_image_into_blocks(image: &DynamicImage) -> ImageBlocks {
     (blocks_r, blocks_g, blocks_b)
 }
 
-/// Splits a color plane into 8 * 8 blocks
-pub fn split_into_blocks(plane: &mut [u8], width: usize, height: usize) -> BlocksType {
+/// The `block_size * block_size` blocks of a `width * height` plane, left to right then top to bottom
+///
+/// Returned by [`split_into_blocks`] and taken back by [`merge_into_plane`], so the plane is merged back
+/// with the dimensions it was split with
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockGrid<T = f32> {
+    pub blocks: Vec<Vec<T>>,
+    pub width: usize,
+    pub height: usize,
+    pub block_size: usize,
+}
+
+impl<T> BlockGrid<T> {
+    /// Number of blocks per row, the last one padded when the width isn't a multiple of the block size
+    pub fn blocks_per_row(&self) -> usize {
+        self.width.div_ceil(self.block_size)
+    }
+
+    /// Number of rows of blocks, the last one padded when the height isn't a multiple of the block size
+    pub fn blocks_per_column(&self) -> usize {
+        self.height.div_ceil(self.block_size)
+    }
+}
+
+/// Splits a color plane into `block_size * block_size` blocks of `f32`, or `f64` for more precision
+///
+/// Dimensions that aren't multiples of `block_size` are padded to the next multiple by replicating
+/// the last row and column, [`merge_into_plane`] crops the padding back
+///
+/// Panics unless `block_size` is a power of two
+pub fn split_into_blocks<S: Sample, T: DctNum>(
+    plane: &mut [S],
+    width: usize,
+    height: usize,
+    block_size: usize,
+) -> BlockGrid<T> {
+    assert_power_of_two(block_size);
     let mut blocks = Vec::new();
 
-    for y in (0..height).step_by(BLK_WIDTH) {
-        for x in (0..width).step_by(BLK_WIDTH) {
+    for y in (0..height).step_by(block_size) {
+        for x in (0..width).step_by(block_size) {
             let mut block = Vec::new();
 
-            for j in 0..BLK_WIDTH {
-                for i in 0..BLK_WIDTH {
-                    block.push(plane[(y + j) * width + (x + i)] as f32);
+            for j in 0..block_size {
+                let row = (y + j).min(height - 1) * width;
+                for i in 0..block_size {
+                    let sample = plane[row + (x + i).min(width - 1)];
+                    block.push(T::from_f64(sample.to_level()).unwrap());
                 }
             }
 
@@ -63,20 +165,48 @@ pub fn split_into_blocks(plane: &mut [u8], width: usize, height: usize) -> Block
         }
     }
 
-    blocks
+    BlockGrid {
+        blocks,
+        width,
+        height,
+        block_size,
+    }
 }
 
-/// Merge a Vec of 8 * 8 blocks back to a color plane
-pub fn merge_into_plane(blocks: &[Vec<f32>], width: usize, height: usize) -> Vec<u8> {
-    let mut plane = vec![0_u8; width * height];
+/// Merges the blocks of a grid back to its `width * height` color plane
+///
+/// Values are rounded and clamped to the range of the samples, the padding added by [`split_into_blocks`] is cropped
+///
+/// Panics unless the block size is a power of two and the grid holds the blocks covering the plane
+pub fn merge_into_plane<S: Sample, T: DctNum + Float>(grid: &BlockGrid<T>) -> Vec<S> {
+    let BlockGrid {
+        blocks,
+        width,
+        height,
+        block_size,
+    } = grid;
+    let (width, height, block_size) = (*width, *height, *block_size);
+    assert_power_of_two(block_size);
+    let blocks_per_row = grid.blocks_per_row();
+    assert_eq!(
+        blocks.len(),
+        blocks_per_row * grid.blocks_per_column(),
+        "{} * {} plane needs {} * {} blocks",
+        width,
+        height,
+        blocks_per_row,
+        grid.blocks_per_column()
+    );
+    let mut plane = vec![S::from_level(0.0); width * height];
 
     for (block_idx, block) in blocks.iter().enumerate() {
-        let x = (block_idx % (width / BLK_WIDTH)) * BLK_WIDTH;
-        let y = (block_idx / (width / BLK_WIDTH)) * BLK_WIDTH;
+        let x = (block_idx % blocks_per_row) * block_size;
+        let y = (block_idx / blocks_per_row) * block_size;
 
-        for j in 0..BLK_WIDTH {
-            for i in 0..BLK_WIDTH {
-                plane[(y + j) * width + (x + i)] = block[j * BLK_WIDTH + i] as u8;
+        for j in 0..block_size.min(height - y) {
+            for i in 0..block_size.min(width - x) {
+                let level = block[j * block_size + i].to_f64().unwrap();
+                plane[(y + j) * width + (x + i)] = S::from_level(level);
             }
         }
     }
@@ -84,78 +214,315 @@ pub fn merge_into_plane(blocks: &[Vec<f32>], width: usize, height: usize) -> Vec
     plane
 }
 
-/// Applies 2D DCT2 on a Vec of 8 * 8 blocks
+/// Planned 2D DCT2 and DCT3 (IDCT) over square blocks, reusable across any number of images
 ///
-/// Changes are made in-place
-pub fn apply_2d_dct(blocks: &mut [Vec<f32>]) {
-    let mut planner = DctPlanner::new();
-    let dct = planner.plan_dct2(BLK_WIDTH);
+/// Planning is done once in [`BlockDct::new`], so prefer keeping one around when processing many images.
+/// `BlockDct<f64>` trades twice the memory for less rounding error accumulated through the transforms
+pub struct BlockDct<T: DctNum = f32> {
+    block_size: usize,
+    dct: Arc<dyn TransformType2And3<T>>,
+    idct: Arc<dyn TransformType2And3<T>>,
+}
+
+impl<T: DctNum> BlockDct<T> {
+    /// Plans the transforms for `block_size * block_size` blocks
+    ///
+    /// Panics unless `block_size` is a power of two
+    pub fn new(block_size: usize) -> Self {
+        assert!(
+            block_size.is_power_of_two(),
+            "block size {} is not a power of two",
+            block_size
+        );
+        let mut planner = DctPlanner::new();
+        Self {
+            block_size,
+            dct: planner.plan_dct2(block_size),
+            idct: planner.plan_dct3(block_size),
+        }
+    }
+
+    /// Width of the blocks the transforms were planned for
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Applies 2D DCT2 on a Vec of blocks
+    ///
+    /// Changes are made in-place, without allocating per block
+    pub fn forward(&self, blocks: &mut [Vec<T>]) {
+        let mut scratch = vec![T::zero(); self.dct.get_scratch_len()];
+        for block in blocks.iter_mut() {
+            self.forward_with_scratch(block, &mut scratch);
+        }
+    }
+
+    /// Applies 2D DCT3 (IDCT) on a Vec of blocks
+    ///
+    /// Changes are made in-place, without allocating per block
+    pub fn inverse(&self, blocks: &mut [Vec<T>]) {
+        let mut scratch = vec![T::zero(); self.idct.get_scratch_len()];
+        for block in blocks.iter_mut() {
+            self.inverse_with_scratch(block, &mut scratch);
+        }
+    }
+
+    /// Applies 2D DCT2 on a single block in place
+    ///
+    /// Panics unless the block holds `block_size * block_size` values
+    pub fn forward_block(&self, block: &mut [T]) {
+        let mut scratch = vec![T::zero(); self.dct.get_scratch_len()];
+        self.forward_with_scratch(block, &mut scratch);
+    }
+
+    /// Applies 2D DCT3 (IDCT) on a single block in place
+    ///
+    /// Panics unless the block holds `block_size * block_size` values
+    pub fn inverse_block(&self, block: &mut [T]) {
+        let mut scratch = vec![T::zero(); self.idct.get_scratch_len()];
+        self.inverse_with_scratch(block, &mut scratch);
+    }
+
+    fn forward_with_scratch(&self, block: &mut [T], scratch: &mut [T]) {
+        let n = self.block_size;
+        assert_eq!(block.len(), n * n, "block is not {} * {}", n, n);
 
-    for block in blocks.iter_mut() {
         // Apply DCT to each row
-        for row in block.chunks_mut(BLK_WIDTH) {
-            dct.process_dct2(row);
+        for row in block.chunks_mut(n) {
+            self.dct.process_dct2_with_scratch(row, scratch);
         }
 
         // Transpose the block
-        let mut transposed_block = vec![0f32; 64];
-        for i in 0..BLK_WIDTH {
-            for j in 0..BLK_WIDTH {
-                transposed_block[i * BLK_WIDTH + j] = block[j * BLK_WIDTH + i];
-            }
-        }
+        transpose(block, n);
 
         // Apply DCT to each column (which are now rows of the transposed block)
-        for row in transposed_block.chunks_mut(BLK_WIDTH) {
-            dct.process_dct2(row);
+        for row in block.chunks_mut(n) {
+            self.dct.process_dct2_with_scratch(row, scratch);
         }
 
         // Transpose the block back to its original orientation
-        for i in 0..BLK_WIDTH {
-            for j in 0..BLK_WIDTH {
-                block[j * BLK_WIDTH + i] = transposed_block[i * BLK_WIDTH + j];
-            }
-        }
+        transpose(block, n);
     }
-}
 
-/// Applies 2D DCT3 (IDCT) on a Vec of 8 * 8 blocks
-///
-/// Changes are made in-place
-pub fn apply_2d_idct(blocks: &mut [Vec<f32>]) {
-    let mut planner = DctPlanner::new();
-    let idct = planner.plan_dct3(BLK_WIDTH);
+    fn inverse_with_scratch(&self, block: &mut [T], scratch: &mut [T]) {
+        let n = self.block_size;
+        assert_eq!(block.len(), n * n, "block is not {} * {}", n, n);
+        // Normalization coefficient, 4 / (height * width)
+        let coeff = T::from_u8(4).unwrap() / T::from_usize(n * n).unwrap();
 
-    for block in blocks.iter_mut() {
         // Apply IDCT to each row
-        for row in block.chunks_mut(BLK_WIDTH) {
-            idct.process_dct3(row);
+        for row in block.chunks_mut(n) {
+            self.idct.process_dct3_with_scratch(row, scratch);
         }
 
         // Transpose the block
-        let mut transposed_block = vec![0f32; 64];
-        for i in 0..BLK_WIDTH {
-            for j in 0..BLK_WIDTH {
-                transposed_block[i * BLK_WIDTH + j] = block[j * BLK_WIDTH + i];
-            }
-        }
+        transpose(block, n);
 
         // Apply IDCT to each column (which are now rows of the transposed block)
-        for row in transposed_block.chunks_mut(BLK_WIDTH) {
-            idct.process_dct3(row);
+        for row in block.chunks_mut(n) {
+            self.idct.process_dct3_with_scratch(row, scratch);
         }
 
-        // Transpose the block back to its original orientation
-        // and apply the normalization coefficient along the way, 4 / (height * width)
-        let coeff = 4.0 / (BLK_WIDTH * BLK_WIDTH) as f32;
-        for i in 0..BLK_WIDTH {
-            for j in 0..BLK_WIDTH {
-                block[j * BLK_WIDTH + i] = transposed_block[i * BLK_WIDTH + j] * coeff;
-            }
+        // Transpose the block back to its original orientation and normalize
+        transpose(block, n);
+        for value in block.iter_mut() {
+            *value = *value * coeff;
+        }
+    }
+}
+
+/// Transposes a square `n * n` block in place
+pub(crate) fn transpose<T>(block: &mut [T], n: usize) {
+    for i in 0..n {
+        for j in i + 1..n {
+            block.swap(i * n + j, j * n + i);
         }
     }
 }
 
+impl<T: DctNum> Default for BlockDct<T> {
+    fn default() -> Self {
+        Self::new(BLK_WIDTH)
+    }
+}
+
+/// Applies 2D DCT2 on a Vec of `block_size * block_size` blocks
+///
+/// Changes are made in-place. Plans the DCT on every call, use [`BlockDct`] to reuse the plan
+pub fn apply_2d_dct<T: DctNum>(blocks: &mut [Vec<T>], block_size: usize) {
+    BlockDct::new(block_size).forward(blocks);
+}
+
+/// Applies 2D DCT3 (IDCT) on a Vec of `block_size * block_size` blocks
+///
+/// Changes are made in-place. Plans the IDCT on every call, use [`BlockDct`] to reuse the plan
+pub fn apply_2d_idct<T: DctNum>(blocks: &mut [Vec<T>], block_size: usize) {
+    BlockDct::new(block_size).inverse(blocks);
+}
+
+/// Factor turning a coefficient of the unnormalized DCT2 at row-major `index` into the orthonormal one
+///
+/// The orthonormal DCT preserves energy, so scaled coefficient changes add up to the pixel squared error
+pub(crate) fn orthonormal_scale(index: usize, block_size: usize) -> f64 {
+    let axis = |i: usize| if i == 0 { 1.0 } else { 2.0 } / block_size as f64;
+    (axis(index / block_size) * axis(index % block_size)).sqrt()
+}
+
+/// Luma quantization table of the JPEG standard, row-major, the one of quality 50
+#[rustfmt::skip]
+const JPEG_LUMA_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// JPEG-style quantization table of 8 * 8 blocks, see [`crate::EmbedConfig::quant_table`]
+///
+/// Quantized coefficients are the orthonormal DCT coefficients divided by the entry at their position,
+/// the values a JPEG encoder with the same table rounds to integers
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct QuantTable {
+    values: Vec<u16>,
+}
+
+impl QuantTable {
+    /// Builds a table from its 64 row-major entries
+    ///
+    /// Errors unless there are 64 entries, all non-zero
+    pub fn new(values: &[u16]) -> Result<Self, WatermarkError> {
+        if values.len() != BLK_WIDTH * BLK_WIDTH {
+            return Err(WatermarkError::InvalidQuantTable(format!(
+                "expected {} entries, got {}",
+                BLK_WIDTH * BLK_WIDTH,
+                values.len()
+            )));
+        }
+        if let Some(i) = values.iter().position(|&value| value == 0) {
+            return Err(WatermarkError::InvalidQuantTable(format!(
+                "entry {} is 0",
+                i
+            )));
+        }
+        Ok(Self {
+            values: values.to_vec(),
+        })
+    }
+
+    /// Standard JPEG luma table scaled to `quality` (1 to 100) like libjpeg does, 50 being the table itself
+    ///
+    /// Panics unless `quality` is between 1 and 100
+    pub fn luma(quality: u8) -> Self {
+        assert!(
+            (1..=100).contains(&quality),
+            "JPEG quality {} is not between 1 and 100",
+            quality
+        );
+        let quality = quality as u32;
+        let scale = if quality < 50 {
+            5000 / quality
+        } else {
+            200 - 2 * quality
+        };
+        let values = JPEG_LUMA_TABLE
+            .iter()
+            .map(|&value| ((value as u32 * scale + 50) / 100).clamp(1, 255) as u16)
+            .collect();
+        Self { values }
+    }
+
+    /// The 64 row-major entries
+    pub fn values(&self) -> &[u16] {
+        &self.values
+    }
+
+    /// Turns the coefficients of a block transformed by [`BlockDct`] into quantized ones, in place
+    ///
+    /// Nothing is rounded, so [`Self::dequantize`] gives the coefficients back
+    pub fn quantize(&self, block: &mut [f32]) {
+        for (i, value) in block.iter_mut().enumerate() {
+            *value *= (orthonormal_scale(i, BLK_WIDTH) / self.values[i] as f64) as f32;
+        }
+    }
+
+    /// Inverse of [`Self::quantize`]
+    pub fn dequantize(&self, block: &mut [f32]) {
+        for (i, value) in block.iter_mut().enumerate() {
+            *value *= (self.values[i] as f64 / orthonormal_scale(i, BLK_WIDTH)) as f32;
+        }
+    }
+}
+
+/// Deserializes the entries through [`QuantTable::new`], so invalid tables are rejected
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for QuantTable {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = Vec::<u16>::deserialize(deserializer)?;
+        Self::new(&values).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The standard JPEG luma table, that of quality 50
+impl Default for QuantTable {
+    fn default() -> Self {
+        Self::luma(50)
+    }
+}
+
+/// Applies 2D DCT2 on a single 8 * 8 block in place
+///
+/// Plans the DCT on every call, use [`BlockDct::forward_block`] to reuse the plan
+pub fn dct_block(block: &mut [f32; 64]) {
+    BlockDct::new(BLK_WIDTH).forward_block(block);
+}
+
+/// Applies 2D DCT3 (IDCT) on a single 8 * 8 block in place
+///
+/// Plans the IDCT on every call, use [`BlockDct::inverse_block`] to reuse the plan
+pub fn idct_block(block: &mut [f32; 64]) {
+    BlockDct::new(BLK_WIDTH).inverse_block(block);
+}
+
+/// Applies the IDCT on 8 * 8 blocks of DCT coefficients and merges them into a `width * height` plane
+///
+/// Samples are rounded and clamped to `[0, 255]`, the padding of the blocks past the edges is cropped.
+/// Blocks are left to right then top to bottom, as from [`split_into_blocks`] or [`dct_block`]
+///
+/// Panics unless there are `width.div_ceil(8) * height.div_ceil(8)` blocks of 64 coefficients
+pub fn blocks_to_plane_clamped(blocks: &[Vec<f32>], width: usize, height: usize) -> Vec<u8> {
+    let expected = width.div_ceil(BLK_WIDTH) * height.div_ceil(BLK_WIDTH);
+    assert_eq!(
+        blocks.len(),
+        expected,
+        "{} * {} plane needs {} blocks",
+        width,
+        height,
+        expected
+    );
+    assert!(
+        blocks
+            .iter()
+            .all(|block| block.len() == BLK_WIDTH * BLK_WIDTH),
+        "blocks are not {} * {}",
+        BLK_WIDTH,
+        BLK_WIDTH
+    );
+
+    let mut grid = BlockGrid {
+        blocks: blocks.to_vec(),
+        width,
+        height,
+        block_size: BLK_WIDTH,
+    };
+    BlockDct::new(BLK_WIDTH).inverse(&mut grid.blocks);
+    merge_into_plane(&grid)
+}
+
 /// For test purpose only.
 #[doc(hidden)]
 pub fn reconstruct_image_from_rgb(
@@ -164,7 +531,9 @@ pub fn reconstruct_image_from_rgb(
     blocks_b: &[Vec<f32>],
     width: u32,
     height: u32,
+    block_size: usize,
 ) -> DynamicImage {
+    assert_block_size(block_size, width as usize, height as usize);
     let mut image = DynamicImage::new_rgb8(width, height);
 
     for (block_idx, ((block_r, block_g), block_b)) in blocks_r
@@ -173,14 +542,14 @@ pub fn reconstruct_image_from_rgb(
         .zip(blocks_b.iter())
         .enumerate()
     {
-        let x = (block_idx % (width as usize / BLK_WIDTH)) * BLK_WIDTH;
-        let y = (block_idx / (width as usize / BLK_WIDTH)) * BLK_WIDTH;
-
-        for j in 0..BLK_WIDTH {
-            for i in 0..BLK_WIDTH {
-                let r = block_r[j * BLK_WIDTH + i] as u8;
-                let g = block_g[j * BLK_WIDTH + i] as u8;
-                let b = block_b[j * BLK_WIDTH + i] as u8;
+        let x = (block_idx % (width as usize / block_size)) * block_size;
+        let y = (block_idx / (width as usize / block_size)) * block_size;
+
+        for j in 0..block_size {
+            for i in 0..block_size {
+                let r = block_r[j * block_size + i] as u8;
+                let g = block_g[j * block_size + i] as u8;
+                let b = block_b[j * block_size + i] as u8;
                 let a = 255_u8;
                 image.put_pixel(
                     x as u32 + i as u32,
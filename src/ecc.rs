@@ -0,0 +1,128 @@
+//! Hamming codes over the watermark bit stream, correcting one flipped bit per codeword
+//!
+//! Codewords are interleaved, so the bits of one codeword end up in different blocks and a
+//! damaged block costs at most one bit to each of the codewords it carries
+
+use bitvec::prelude::{BitSlice, BitVec};
+
+/// Error correcting code applied to the watermark before embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Ecc {
+    /// No correction, the default
+    None,
+    /// Hamming(7, 4), code rate 4/7
+    Hamming7_4,
+    /// Hamming(15, 11), code rate 11/15
+    Hamming15_11,
+    /// Hamming(31, 26), code rate 26/31
+    Hamming31_26,
+}
+
+impl Ecc {
+    /// Number of parity bits per codeword, 0 without correction
+    fn parity_bits(&self) -> u32 {
+        match self {
+            Ecc::None => 0,
+            Ecc::Hamming7_4 => 3,
+            Ecc::Hamming15_11 => 4,
+            Ecc::Hamming31_26 => 5,
+        }
+    }
+
+    /// Codeword and data lengths in bits
+    fn lengths(&self) -> (usize, usize) {
+        let r = self.parity_bits();
+        let n = (1 << r) - 1;
+        (n, n - r as usize)
+    }
+
+    /// Ratio of data bits to embedded bits
+    pub fn rate(&self) -> f64 {
+        match self {
+            Ecc::None => 1.0,
+            _ => {
+                let (n, k) = self.lengths();
+                k as f64 / n as f64
+            }
+        }
+    }
+
+    /// Number of embedded bits for `len` data bits
+    pub(crate) fn encoded_len(&self, len: usize) -> usize {
+        match self {
+            Ecc::None => len,
+            _ => {
+                let (n, k) = self.lengths();
+                len.div_ceil(k) * n
+            }
+        }
+    }
+
+    /// Encodes `bits`, padding the last codeword with zeros
+    pub(crate) fn encode(&self, bits: &BitSlice) -> BitVec {
+        if *self == Ecc::None {
+            return bits.to_bitvec();
+        }
+        let (n, k) = self.lengths();
+        let num_codewords = bits.len().div_ceil(k);
+
+        let mut encoded = BitVec::repeat(false, num_codewords * n);
+        for (i, data) in bits.chunks(k).enumerate() {
+            // Positions are 1-based, parity bits sit at the powers of two
+            let mut codeword = vec![false; n + 1];
+            let mut data = data.iter().by_vals();
+            for (pos, bit) in codeword.iter_mut().enumerate().skip(1) {
+                if !pos.is_power_of_two() {
+                    *bit = data.next().unwrap_or(false);
+                }
+            }
+            let syndrome = syndrome(&codeword);
+            for p in 0..self.parity_bits() {
+                codeword[1 << p] = (syndrome >> p) & 1 == 1;
+            }
+
+            for (j, bit) in codeword[1..].iter().enumerate() {
+                encoded.set(j * num_codewords + i, *bit);
+            }
+        }
+        encoded
+    }
+
+    /// Decodes the first `len` data bits, correcting up to one flipped bit per codeword
+    pub(crate) fn decode(&self, bits: &BitSlice, len: usize) -> BitVec {
+        if *self == Ecc::None {
+            return bits[..len].to_bitvec();
+        }
+        let (n, k) = self.lengths();
+        let num_codewords = len.div_ceil(k);
+
+        let mut decoded = BitVec::with_capacity(num_codewords * k);
+        for i in 0..num_codewords {
+            let mut codeword = vec![false; n + 1];
+            for (j, bit) in codeword[1..].iter_mut().enumerate() {
+                *bit = bits[j * num_codewords + i];
+            }
+            let error_pos = syndrome(&codeword);
+            if error_pos != 0 {
+                codeword[error_pos] = !codeword[error_pos];
+            }
+            for (pos, bit) in codeword.iter().enumerate().skip(1) {
+                if !pos.is_power_of_two() {
+                    decoded.push(*bit);
+                }
+            }
+        }
+        decoded.truncate(len);
+        decoded
+    }
+}
+
+/// XOR of the 1-based positions of the set bits, the position of the flipped bit if any
+fn syndrome(codeword: &[bool]) -> usize {
+    codeword
+        .iter()
+        .enumerate()
+        .filter(|(_, bit)| **bit)
+        .fold(0, |acc, (pos, _)| acc ^ pos)
+}
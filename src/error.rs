@@ -0,0 +1,201 @@
+use std::fmt;
+
+use image::{ColorType, ImageError, ImageFormat};
+
+use crate::Channel;
+
+/// Errors that can occur while embedding or extracting a watermark
+#[derive(Debug)]
+pub enum WatermarkError {
+    /// The host image is smaller than a single block
+    HostDimensions { actual: (u32, u32), block_size: u32 },
+    /// The host image has more pixels than [`crate::MAX_HOST_PIXELS`]
+    HostTooLarge { actual: (u32, u32), max_pixels: u64 },
+    /// The block size is not a power of two of at least 8
+    BlockSize(usize),
+    /// The watermark dimensions can't be stored in the header
+    WatermarkDimensions { actual: (u32, u32), max: u32 },
+    /// The watermark needs more bits than the host image can carry
+    Capacity { required: usize, available: usize },
+    /// Two bit streams that should be compared have different lengths
+    LengthMismatch { expected: usize, actual: usize },
+    /// The step size is not a positive finite number
+    InvalidStepSize(f32),
+    /// The distortion compensation factor is not in `(0, 1]`
+    InvalidAlpha(f32),
+    /// The number of QIM levels is not a power of two between 2 and 256
+    Levels(usize),
+    /// The acceptable range of binary extraction is not in `(0, 0.5)`, or is set with more than 2 levels
+    InvalidAcceptableRange(f32),
+    /// The watermark recode must keep between 1 and 8 bits per channel
+    BitsPerChannel(u8),
+    /// The config selects no plane to embed into
+    NoChannels,
+    /// Even the smallest step size can't keep the PSNR at or above the target, in dB
+    TargetPsnr(f64),
+    /// A coefficient mask doesn't select a valid set of positions
+    InvalidMask(String),
+    /// A quantization table doesn't hold 64 non-zero entries, or the config can't quantize its blocks
+    InvalidQuantTable(String),
+    /// The chroma planes of 4:2:0 conversions can't carry bits, only the Y plane can
+    SubsampledChroma,
+    /// HSV embedding only carries bits in the V plane, selected by [`Channel::Y`]
+    HsvChannels,
+    /// Skipping low energy blocks only works with the Y plane, see [`crate::EmbedConfig::min_block_energy`]
+    EnergyChannels,
+    /// Skipping low energy blocks only works with grayscale hosts, see [`crate::EmbedConfig::min_block_energy`]
+    EnergyColorHost,
+    /// The embed would overwrite coefficients a previous embed wrote, first at `block` of the `channel` plane
+    MaskConflict { channel: Channel, block: usize },
+    /// The original host of a non-blind extraction doesn't have the dimensions of the watermarked image
+    OriginalDimensions {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// The extracted text is not valid UTF-8
+    InvalidText(std::string::FromUtf8Error),
+    /// The output format is lossy and would wipe the watermark
+    LossyFormat(ImageFormat),
+    /// The output format would convert the samples of the watermarked image to a lower bit depth
+    SampleDepth {
+        format: ImageFormat,
+        color: ColorType,
+    },
+    /// Failed to read or write an image file
+    Io(std::io::Error),
+    /// Failed to decode or encode an image
+    Decode(ImageError),
+}
+
+impl From<ImageError> for WatermarkError {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::IoError(e) => WatermarkError::Io(e),
+            e => WatermarkError::Decode(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for WatermarkError {
+    fn from(err: std::io::Error) -> Self {
+        WatermarkError::Io(err)
+    }
+}
+
+impl fmt::Display for WatermarkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatermarkError::HostTooLarge { actual, max_pixels } => write!(
+                f,
+                "host is {}x{}, more than the {} pixels supported",
+                actual.0, actual.1, max_pixels
+            ),
+            WatermarkError::HostDimensions { actual, block_size } => write!(
+                f,
+                "host image is {} * {}, smaller than a {} * {} block",
+                actual.0, actual.1, block_size, block_size
+            ),
+            WatermarkError::BlockSize(size) => {
+                write!(f, "block size {} is not a power of two of at least 8", size)
+            }
+            WatermarkError::WatermarkDimensions { actual, max } => write!(
+                f,
+                "watermark is {} * {}, sides can't exceed {}",
+                actual.0, actual.1, max
+            ),
+            WatermarkError::Capacity {
+                required,
+                available,
+            } => write!(
+                f,
+                "watermark needs {} bits but the host only carries {}",
+                required, available
+            ),
+            WatermarkError::LengthMismatch { expected, actual } => {
+                write!(f, "expected {} bits but got {}", expected, actual)
+            }
+            WatermarkError::InvalidStepSize(step_size) => {
+                write!(f, "step size {} is not a positive finite number", step_size)
+            }
+            WatermarkError::InvalidAlpha(alpha) => {
+                write!(f, "alpha {} is not in (0, 1]", alpha)
+            }
+            WatermarkError::Levels(levels) => write!(
+                f,
+                "{} QIM levels is not a power of two between 2 and 256",
+                levels
+            ),
+            WatermarkError::InvalidAcceptableRange(range) => write!(
+                f,
+                "acceptable range {} is not in (0, 0.5) with 2 QIM levels",
+                range
+            ),
+            WatermarkError::BitsPerChannel(bits) => {
+                write!(f, "{} bits per channel is not between 1 and 8", bits)
+            }
+            WatermarkError::NoChannels => write!(f, "no plane selected to embed into"),
+            WatermarkError::TargetPsnr(target) => write!(
+                f,
+                "even the smallest step size can't reach a PSNR of {} dB",
+                target
+            ),
+            WatermarkError::InvalidMask(reason) => {
+                write!(f, "invalid coefficient mask: {}", reason)
+            }
+            WatermarkError::InvalidQuantTable(reason) => {
+                write!(f, "invalid quantization table: {}", reason)
+            }
+            WatermarkError::SubsampledChroma => write!(
+                f,
+                "chroma planes of 4:2:0 conversions can't carry bits, only Y can"
+            ),
+            WatermarkError::EnergyChannels => {
+                write!(f, "low energy blocks can only be skipped in the Y plane")
+            }
+            WatermarkError::EnergyColorHost => {
+                write!(
+                    f,
+                    "low energy blocks can only be skipped in grayscale hosts"
+                )
+            }
+            WatermarkError::HsvChannels => {
+                write!(
+                    f,
+                    "HSV embedding only carries bits in V, select the Y channel"
+                )
+            }
+            WatermarkError::MaskConflict { channel, block } => write!(
+                f,
+                "embed would overwrite a previous watermark at block {} of the {:?} plane",
+                block, channel
+            ),
+            WatermarkError::OriginalDimensions { expected, actual } => write!(
+                f,
+                "original host is {} * {} but the watermarked image is {} * {}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+            WatermarkError::InvalidText(e) => write!(f, "extracted text is not UTF-8: {}", e),
+            WatermarkError::LossyFormat(format) => {
+                write!(f, "{:?} is lossy and would wipe the watermark", format)
+            }
+            WatermarkError::SampleDepth { format, color } => write!(
+                f,
+                "{:?} can't store {:?} samples without reducing their bit depth",
+                format, color
+            ),
+            WatermarkError::Io(e) => write!(f, "I/O error: {}", e),
+            WatermarkError::Decode(e) => write!(f, "image error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WatermarkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WatermarkError::InvalidText(e) => Some(e),
+            WatermarkError::Io(e) => Some(e),
+            WatermarkError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -1,314 +1,5613 @@
+pub mod attack;
 pub mod color_recode;
 pub mod colorspace;
+pub mod config;
 pub mod dct;
+#[cfg(feature = "ecc")]
+pub mod ecc;
+pub mod error;
+pub mod metrics;
+pub mod multilevel;
+pub mod overlay;
+mod payload;
 pub mod qim;
+pub mod report;
+pub mod testing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod wht;
 
-use bitvec::prelude::{BitVec, Lsb0};
-use image::{DynamicImage, GenericImageView};
+pub use config::{Channel, ChannelMode, ColorDomain, EmbedConfig, Redundancy, Transform};
+#[cfg(feature = "ecc")]
+pub use ecc::Ecc;
+pub use error::WatermarkError;
+pub use qim::key_from_passphrase;
+pub use report::{Authentication, CapacityReport, EmbedReport, PartialExtraction};
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use bitvec::prelude::{BitSlice, BitVec, Lsb0};
+use color_recode::WatermarkKind;
+use image::{DynamicImage, GenericImageView, GrayImage, ImageBuffer, ImageFormat, Pixel};
+
+/// Bits storing the byte length of an embedded text
+const TEXT_LEN_BITS: usize = 32;
+
+/// Smallest step size tried by [`auto_step_size`]
+const MIN_AUTO_STEP: f32 = 1.0;
+
+/// Largest step size tried by [`auto_step_size`]
+const MAX_AUTO_STEP: f32 = 65536.0;
+
+/// Bisections of the step size done by [`auto_step_size`], each halving the interval holding the step
+const AUTO_STEP_BISECTIONS: usize = 16;
+
+/// Checks that the step size is positive and finite, dithers and quantization are NaN otherwise
+fn check_step_size(step_size: f32) -> Result<(), WatermarkError> {
+    if !(step_size > 0.0 && step_size.is_finite()) {
+        return Err(WatermarkError::InvalidStepSize(step_size));
+    }
+    Ok(())
+}
+
+/// Checks that the distortion compensation factor is in `(0, 1]`
+fn check_alpha(alpha: f32) -> Result<(), WatermarkError> {
+    if !(alpha > 0.0 && alpha <= 1.0) {
+        return Err(WatermarkError::InvalidAlpha(alpha));
+    }
+    Ok(())
+}
+
+/// Checks that the number of QIM levels is a power of two between 2 and 256
+fn check_levels(levels: usize) -> Result<(), WatermarkError> {
+    if !(2..=256).contains(&levels) || !levels.is_power_of_two() {
+        return Err(WatermarkError::Levels(levels));
+    }
+    Ok(())
+}
+
+/// Checks that the acceptable range of the config, if any, is in `(0, 0.5)` and comes with binary QIM
+fn check_acceptable_range(config: &EmbedConfig) -> Result<(), WatermarkError> {
+    match config.acceptable_range {
+        Some(range) if !(range > 0.0 && range < 0.5) || config.levels != 2 => {
+            Err(WatermarkError::InvalidAcceptableRange(range))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that 4:2:0 conversions, the HSV domain and block energy thresholds only embed into the Y plane, V for HSV
+fn check_channels(config: &EmbedConfig) -> Result<(), WatermarkError> {
+    if config.channels == [Channel::Y] {
+        return Ok(());
+    }
+    if config.color_domain == ColorDomain::Hsv {
+        return Err(WatermarkError::HsvChannels);
+    }
+    if config.min_block_energy.is_some() {
+        return Err(WatermarkError::EnergyChannels);
+    }
+    if config.color.subsampling == colorspace::ChromaSubsampling::Yuv420 {
+        return Err(WatermarkError::SubsampledChroma);
+    }
+    Ok(())
+}
+
+/// Checks that block energy thresholds only come with grayscale hosts, whose Y plane round trips exactly
+fn check_energy_host(image: &DynamicImage, config: &EmbedConfig) -> Result<(), WatermarkError> {
+    if config.min_block_energy.is_some() && image.color().has_color() {
+        return Err(WatermarkError::EnergyColorHost);
+    }
+    Ok(())
+}
+
+/// Checks that a quantization table only applies to 8 * 8 DCT blocks, the ones JPEG quantizes
+fn check_quant_table(config: &EmbedConfig) -> Result<(), WatermarkError> {
+    if config.quant_table.is_some()
+        && (config.transform != Transform::Dct || config.block_size != dct::BLK_WIDTH)
+    {
+        return Err(WatermarkError::InvalidQuantTable(format!(
+            "only 8 * 8 DCT blocks can be quantized, not {} * {} {:?} blocks",
+            config.block_size, config.block_size, config.transform
+        )));
+    }
+    Ok(())
+}
+
+/// Number of watermark bits carried by each block: a symbol of `log2(levels)` bits per coefficient of the mask,
+/// the config levels must be valid
+fn block_bits(config: &EmbedConfig) -> usize {
+    config.mask.len() * config.levels.trailing_zeros() as usize
+}
+
+/// Checks that the watermark recode keeps between 1 and 8 bits per channel
+fn check_bits_per_channel(bits_per_channel: u8) -> Result<(), WatermarkError> {
+    if !(1..=8).contains(&bits_per_channel) {
+        return Err(WatermarkError::BitsPerChannel(bits_per_channel));
+    }
+    Ok(())
+}
+
+/// Whether saving a watermarked image in `format` keeps the watermark
+///
+/// Lossy formats such as JPEG quantize the DCT coefficients again, which wipes the mid-frequency
+/// QIM modifications, and GIF reduces the colors to a palette. The `image` crate only writes lossless
+/// WebP, so WebP is safe. PNG, TIFF, BMP, TGA, PNM, QOI and farbfeld are lossless
+pub fn is_format_watermark_safe(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Png
+            | ImageFormat::Tiff
+            | ImageFormat::Bmp
+            | ImageFormat::Tga
+            | ImageFormat::Pnm
+            | ImageFormat::Qoi
+            | ImageFormat::Farbfeld
+            | ImageFormat::WebP
+    )
+}
+
+/// Errors unless `format` is safe for a watermarked image, see [`is_format_watermark_safe`]
+fn check_output_format(format: ImageFormat) -> Result<(), WatermarkError> {
+    if !is_format_watermark_safe(format) {
+        return Err(WatermarkError::LossyFormat(format));
+    }
+    Ok(())
+}
+
+/// Errors if saving `color` samples as `format` would silently reduce them to 8 bits
+///
+/// Only PNG, TIFF, PNM and farbfeld keep 16 bit samples, the `image` crate converts them for the other
+/// formats, BMP included, and the rounding moves the coefficients off their lattices
+fn check_sample_depth(format: ImageFormat, color: image::ColorType) -> Result<(), WatermarkError> {
+    let wide = color.bytes_per_pixel() > color.channel_count();
+    let keeps_wide = matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Tiff | ImageFormat::Pnm | ImageFormat::Farbfeld
+    );
+    if wide && !keeps_wide {
+        return Err(WatermarkError::SampleDepth { format, color });
+    }
+    Ok(())
+}
+
+/// Saves a watermarked image, the format is deduced from the extension of `path`
+///
+/// Errors if the format is lossy, see [`is_format_watermark_safe`], if it can't keep the bit depth
+/// of 16 bit images, or if the image can't be written
+pub fn save_watermarked(
+    image: &DynamicImage,
+    path: impl AsRef<Path>,
+) -> Result<(), WatermarkError> {
+    let format = ImageFormat::from_path(&path)?;
+    check_output_format(format)?;
+    check_sample_depth(format, image.color())?;
+    image.save_with_format(path, format)?;
+    Ok(())
+}
+
+/// Encodes the image to `format` in memory
+fn encode_to_bytes(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, WatermarkError> {
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), format)?;
+    Ok(bytes)
+}
+
+/// Bytes taken by each pixel of a host while embedding, its 8-bit RGB samples and planes and the `f32`
+/// samples of its blocks, rounded up
+const BYTES_PER_PIXEL: u64 = 24;
+
+/// Largest host image accepted, in pixels
+///
+/// The buffers of larger hosts would take more than `usize::MAX` bytes, so their sizes overflow. That is
+/// some 179 million pixels on 32-bit targets such as wasm32, where hosts well under it already exhaust
+/// the 4 GiB address space, while 64-bit targets accept every image up to the memory of the machine
+pub const MAX_HOST_PIXELS: u64 = usize::MAX as u64 / BYTES_PER_PIXEL;
+
+/// Checks that the block size is valid and that the host image holds at least one whole block,
+/// and at most [`MAX_HOST_PIXELS`] pixels
+fn check_host_dimensions(width: u32, height: u32, block_size: usize) -> Result<(), WatermarkError> {
+    if !block_size.is_power_of_two() || block_size < dct::BLK_WIDTH {
+        return Err(WatermarkError::BlockSize(block_size));
+    }
+    if width as u64 * height as u64 > MAX_HOST_PIXELS {
+        return Err(WatermarkError::HostTooLarge {
+            actual: (width, height),
+            max_pixels: MAX_HOST_PIXELS,
+        });
+    }
+    let blk = block_size as u32;
+    if width < blk || height < blk {
+        return Err(WatermarkError::HostDimensions {
+            actual: (width, height),
+            block_size: blk,
+        });
+    }
+    Ok(())
+}
+
+/// Number of watermark bits a single `width * height` plane can carry
+fn plane_capacity(width: u32, height: u32, config: &EmbedConfig) -> usize {
+    let (cols, rows) = block_span(width, height, config);
+    cols.len() * rows.len() * block_bits(config)
+}
+
+/// Columns and rows of the blocks carrying bits: the whole blocks of the plane inside the region of the config
+fn block_span(
+    width: u32,
+    height: u32,
+    config: &EmbedConfig,
+) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+    let block_size = config.block_size;
+    let (width, height) = (width as usize, height as usize);
+    match config.region {
+        None => (0..width / block_size, 0..height / block_size),
+        Some(region) => {
+            let span = |start: u32, len: u32, size: usize| {
+                let end = (start as usize).saturating_add(len as usize).min(size);
+                (start as usize).div_ceil(block_size)..end / block_size
+            };
+            (
+                span(region.x, region.width, width),
+                span(region.y, region.height, height),
+            )
+        }
+    }
+}
+
+/// Block transform selected by [`EmbedConfig::transform`], planned for the block size of the config
+enum BlockTransform {
+    /// The DCT, followed by the quantization of the config if any
+    Dct(dct::BlockDct, Option<dct::QuantTable>),
+    Hadamard(wht::BlockWht),
+    MultiLevel(multilevel::MultiLevelDct),
+}
+
+impl BlockTransform {
+    fn new(config: &EmbedConfig) -> Self {
+        match config.transform {
+            Transform::Dct => BlockTransform::Dct(
+                dct::BlockDct::new(config.block_size),
+                config.quant_table.clone(),
+            ),
+            Transform::Hadamard => BlockTransform::Hadamard(wht::BlockWht::new(config.block_size)),
+            Transform::MultiLevel => {
+                BlockTransform::MultiLevel(multilevel::MultiLevelDct::new(config.block_size))
+            }
+        }
+    }
+
+    fn forward(&self, blocks: &mut [Vec<f32>]) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            blocks = blocks.len(),
+            transform = self.name(),
+            "forward transform"
+        );
+        match self {
+            BlockTransform::Dct(block_dct, quant_table) => {
+                block_dct.forward(blocks);
+                if let Some(quant_table) = quant_table {
+                    blocks
+                        .iter_mut()
+                        .for_each(|block| quant_table.quantize(block));
+                }
+            }
+            BlockTransform::Hadamard(block_wht) => block_wht.forward(blocks),
+            BlockTransform::MultiLevel(multilevel_dct) => multilevel_dct.forward(blocks),
+        }
+    }
+
+    fn inverse(&self, blocks: &mut [Vec<f32>]) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            blocks = blocks.len(),
+            transform = self.name(),
+            "inverse transform"
+        );
+        match self {
+            BlockTransform::Dct(block_dct, quant_table) => {
+                if let Some(quant_table) = quant_table {
+                    blocks
+                        .iter_mut()
+                        .for_each(|block| quant_table.dequantize(block));
+                }
+                block_dct.inverse(blocks);
+            }
+            BlockTransform::Hadamard(block_wht) => block_wht.inverse(blocks),
+            BlockTransform::MultiLevel(multilevel_dct) => multilevel_dct.inverse(blocks),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> &'static str {
+        match self {
+            BlockTransform::Dct(..) => "dct",
+            BlockTransform::Hadamard(_) => "hadamard",
+            BlockTransform::MultiLevel(_) => "multilevel",
+        }
+    }
+
+    /// Factor turning the coefficient at row-major `index` into the orthonormal one
+    fn orthonormal_scale(&self, index: usize) -> f64 {
+        match self {
+            BlockTransform::Dct(_, Some(quant_table)) => quant_table.values()[index] as f64,
+            BlockTransform::Dct(block_dct, None) => {
+                dct::orthonormal_scale(index, block_dct.block_size())
+            }
+            BlockTransform::Hadamard(block_wht) => wht::orthonormal_scale(block_wht.block_size()),
+            BlockTransform::MultiLevel(multilevel_dct) => {
+                multilevel::orthonormal_scale(index, multilevel_dct.block_size())
+            }
+        }
+    }
+}
+
+/// Largest square watermark whose 3-bit recode fits a `host_w * host_h` plane
+///
+/// `block` is the block size and `bits_per_block` the bits each block carries, 12 for the default config.
+/// Headers, redundancy and extra channels or bits per channel change the capacity, see [`EmbedConfig`]
+///
+/// ```
+/// assert_eq!(color_watermark::max_watermark_dims(512, 512, 8, 12), (128, 128));
+/// ```
+pub fn max_watermark_dims(
+    host_w: u32,
+    host_h: u32,
+    block: usize,
+    bits_per_block: usize,
+) -> (u32, u32) {
+    let capacity = (host_w as usize / block) * (host_h as usize / block) * bits_per_block;
+    let pixels = capacity / 3;
+    let mut side = (pixels as f64).sqrt() as usize;
+    // Correct the rounding of the square root
+    while side * side > pixels {
+        side -= 1;
+    }
+    while (side + 1) * (side + 1) <= pixels {
+        side += 1;
+    }
+    (side as u32, side as u32)
+}
+
+/// Dry run of an embed: the capacity a `wm_dims` watermark would use in a `host_dims` host with the config
+///
+/// Only looks at the dimensions, nothing is decoded or transformed. Watermarks are assumed RGB unless
+/// the config sets [`EmbedConfig::watermark_kind`], and the config isn't validated, the embed still can fail.
+/// Hosts smaller than a block or over [`MAX_HOST_PIXELS`] have no capacity and never fit
+///
+/// ```
+/// use color_watermark::{capacity_report, EmbedConfig};
+///
+/// let report = capacity_report((512, 512), (64, 64), &EmbedConfig::new(1));
+/// assert!(report.fits);
+/// assert_eq!(report.utilization, 0.25);
+/// ```
+pub fn capacity_report(
+    (host_width, host_height): (u32, u32),
+    (wm_width, wm_height): (u32, u32),
+    config: &EmbedConfig,
+) -> CapacityReport {
+    // Hosts the embed rejects have no capacity, and the blocks of hosts over the limit can overflow
+    let total_bits = if check_host_dimensions(host_width, host_height, config.block_size).is_ok() {
+        plane_capacity(host_width, host_height, config) * stream_planes(config)
+    } else {
+        0
+    };
+    let kind = config.watermark_kind.unwrap_or(WatermarkKind::Rgb);
+    let wm_len =
+        wm_width as usize * wm_height as usize * kind.bits_per_pixel(config.bits_per_channel);
+    let copies = config.redundancy.copies();
+    let wm_blocks = payload::segment_blocks(
+        encoded_watermark_len(wm_len, config),
+        block_bits(config),
+        copies,
+    );
+    let header_blocks = if config.header {
+        header_blocks(config)
+    } else {
+        0
+    };
+    let needed_bits = (header_blocks + wm_blocks) * block_bits(config);
+    CapacityReport {
+        total_bits,
+        needed_bits,
+        fits: total_bits > 0 && needed_bits <= total_bits,
+        utilization: if total_bits == 0 {
+            f64::INFINITY
+        } else {
+            needed_bits as f64 / total_bits as f64
+        },
+    }
+}
+
+/// Largest number of bytes [`embed_text_with`] can store in a `host_dims` host with the config
+///
+/// Accounts for the length prefix of the text, the error correcting code, the authentication tag, the padding
+/// to whole blocks and the repeated copies. Like [`capacity_report`] only the dimensions are looked at, so
+/// blocks skipped by [`EmbedConfig::min_block_energy`] aren't, and the config isn't validated
+///
+/// ```
+/// use color_watermark::{capacity_bytes, EmbedConfig};
+///
+/// // 4096 blocks of 12 bits, less 3 blocks for the length
+/// assert_eq!(capacity_bytes((512, 512), &EmbedConfig::new(1)), 4093 * 12 / 8);
+/// ```
+pub fn capacity_bytes(host_dims: (u32, u32), config: &EmbedConfig) -> usize {
+    let total_blocks = plane_capacity(host_dims.0, host_dims.1, config) * stream_planes(config)
+        / block_bits(config);
+    let copies = config.redundancy.copies();
+    let len_blocks = payload::segment_blocks(TEXT_LEN_BITS, block_bits(config), copies);
+    let fits = |len: usize| {
+        let text_blocks = payload::segment_blocks(
+            encoded_watermark_len(len * 8, config),
+            block_bits(config),
+            copies,
+        );
+        len_blocks + text_blocks <= total_blocks
+    };
+    // The encoded length grows with the text, search the largest text that fits
+    let (mut low, mut high) = (0, total_blocks * block_bits(config) / 8 + 1);
+    if !fits(low) {
+        return 0;
+    }
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// Indices, in the order of [`dct::split_into_blocks`], of the blocks of `image` carrying the bit stream, in stream order
+///
+/// The full blocks of the host, less those under [`EmbedConfig::min_block_energy`]. Embedding leaves the
+/// skipped blocks of grayscale hosts untouched and the energy of the carriers up to the rounding of their
+/// samples, so a host and its watermarked image give the same blocks unless some lie right at the threshold
+///
+/// Errors if the image is smaller than a block, or with a threshold if the config selects other planes
+/// than Y or the image has color
+pub fn carrier_blocks(
+    image: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<Vec<usize>, WatermarkError> {
+    let (width, height) = image.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_channels(config)?;
+    check_energy_host(image, config)?;
+    check_quant_table(config)?;
+    let full_blocks = full_blocks(width, height, config);
+    if config.min_block_energy.is_none() {
+        return Ok(full_blocks);
+    }
+    // Without the threshold, the single plane comes back as all of its full blocks, in the same order
+    let unfiltered = EmbedConfig {
+        min_block_energy: None,
+        ..config.clone()
+    };
+    let blocks = transform_channels(image, &unfiltered);
+    Ok(full_blocks
+        .into_iter()
+        .zip(&blocks)
+        .filter(|(_, block)| carries_bits(block, config))
+        .map(|(i, _)| i)
+        .collect())
+}
+
+/// Indices, in the order of [`dct::split_into_blocks`], of the blocks lying entirely inside the plane
+/// and the region of the config, in the order they carry the bit stream
+///
+/// Only those carry bits: the padding of edge blocks is cropped once watermarked and can't be read back
+fn full_blocks(width: u32, height: u32, config: &EmbedConfig) -> Vec<usize> {
+    let blocks_per_row = (width as usize).div_ceil(config.block_size);
+    let (cols, rows) = block_span(width, height, config);
+    let blocks: Vec<usize> = rows
+        .flat_map(|row| cols.clone().map(move |col| row * blocks_per_row + col))
+        .collect();
+    if config.permute_blocks {
+        qim::block_permutation(blocks.len(), config.key)
+            .into_iter()
+            .map(|i| blocks[i])
+            .collect()
+    } else {
+        blocks
+    }
+}
+
+/// Number of planes the bit stream is spread over, 1 when every plane carries all of it
+fn stream_planes(config: &EmbedConfig) -> usize {
+    match config.channel_mode {
+        ChannelMode::Capacity => config.channels.len(),
+        ChannelMode::Diversity => 1,
+    }
+}
+
+/// Bits of the stream carried by each plane selected by the config, in order
+fn plane_streams<'a>(
+    wm_bits: &'a BitSlice,
+    plane_capacity: usize,
+    config: &EmbedConfig,
+) -> Vec<&'a BitSlice> {
+    match config.channel_mode {
+        ChannelMode::Capacity => wm_bits.chunks(plane_capacity).collect(),
+        ChannelMode::Diversity => vec![wm_bits; config.channels.len()],
+    }
+}
+
+/// Checks that `required` watermark bits fit into the planes of a `width * height` host image selected by the config,
+/// which must select at least one
+fn check_capacity(
+    width: u32,
+    height: u32,
+    config: &EmbedConfig,
+    required: usize,
+) -> Result<(), WatermarkError> {
+    if config.channels.is_empty() {
+        return Err(WatermarkError::NoChannels);
+    }
+    let available = plane_capacity(width, height, config) * stream_planes(config);
+    if required > available {
+        return Err(WatermarkError::Capacity {
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
 
 /// Uses DCT together with QIM-DM to embed the colored watermark image into the host image
 ///
 /// Higher `step_size` generally yields better extraction result, but might reduce the imperceptability of the watermark
 ///
-/// Panics if the host image is not 512 * 512 or the watermark image is not 128 * 128
+/// Every whole 8 * 8 block of the host carries 12 bits, partial blocks along the right and bottom edges
+/// carry none, and the watermark takes 3 bits per pixel, e.g. a 512 * 512 host fits a 128 * 128 watermark.
+/// The bits are padded with zeros to whole blocks, the blocks past them are left untouched
+///
+/// Errors if the images can't be opened, if the host is smaller than a block
+/// or if the padded watermark needs more blocks than the host has, before modifying anything
 pub fn embed_watermark(
     host_image: &str,
     watermark_image: &str,
     key: u64,
     step_size: f32,
-) -> DynamicImage {
-    let host = image::open(host_image).expect("Failed to open host image");
+) -> Result<DynamicImage, WatermarkError> {
+    let host = image::open(host_image)?;
+    let wm = image::open(watermark_image)?;
+    embed_watermark_image(&host, &wm, key, step_size)
+}
+
+/// Same as [`embed_watermark`], but works on already decoded images
+///
+/// Decoding the inputs and saving the result are left to the caller
+///
+/// Errors if the host is smaller than a block or if the watermark doesn't fit into the host
+pub fn embed_watermark_image(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<DynamicImage, WatermarkError> {
+    embed_with(host, watermark, &EmbedConfig::new(key).step_size(step_size))
+}
+
+/// Same as [`embed_watermark`], but decodes the images from memory and encodes the result to `format`
+///
+/// Nothing touches the disk, which suits services receiving and returning image bytes
+///
+/// Errors if `format` is lossy, since its compression wipes the watermark, if it can't keep the bit depth
+/// of a 16 bit host, if the images can't be decoded or encoded, or for the same reasons as [`embed_watermark_image`]
+pub fn embed_watermark_bytes(
+    host: &[u8],
+    watermark: &[u8],
+    key: u64,
+    step_size: f32,
+    format: ImageFormat,
+) -> Result<Vec<u8>, WatermarkError> {
+    check_output_format(format)?;
+    let host = image::load_from_memory(host)?;
+    let wm = image::load_from_memory(watermark)?;
+    let watermarked = embed_watermark_image(&host, &wm, key, step_size)?;
+    check_sample_depth(format, watermarked.color())?;
+    encode_to_bytes(&watermarked, format)
+}
+
+/// Path of an image of [`embed_directory`] with the path of its watermarked copy or why it failed
+pub type DirectoryEntry = (PathBuf, Result<PathBuf, WatermarkError>);
+
+/// Embeds the watermark into every image of `in_dir`, saving each under the same name into `out_dir`
+///
+/// Files without the extension of a readable image format are skipped, as are subdirectories. Each image
+/// is saved in the format of its name, so lossy ones such as JPEG fail, see [`save_watermarked`]
+///
+/// Return value: the path of each image, in name order, with the path of its watermarked copy or why it failed
+///
+/// Errors if `in_dir` can't be read or `out_dir` can't be created
+pub fn embed_directory(
+    in_dir: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+    watermark: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<Vec<DirectoryEntry>, WatermarkError> {
+    let out_dir = out_dir.as_ref();
+    std::fs::create_dir_all(out_dir)?;
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(in_dir)? {
+        let path = entry?.path();
+        let readable = ImageFormat::from_path(&path).is_ok_and(|format| format.reading_enabled());
+        if readable && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let config = EmbedConfig::new(key).step_size(step_size);
+    let results = paths
+        .into_iter()
+        .map(|path| {
+            let out_path = out_dir.join(path.file_name().expect("directory entries have a name"));
+            let result = image::open(&path)
+                .map_err(WatermarkError::from)
+                .and_then(|host| embed_with(&host, watermark, &config))
+                .and_then(|watermarked| save_watermarked(&watermarked, &out_path))
+                .map(|()| out_path);
+            (path, result)
+        })
+        .collect();
+    Ok(results)
+}
+
+/// Embeds the watermark image into the host image with the given config
+///
+/// Hosts with 16 bits per channel are processed and returned with 16 bits per channel
+///
+/// Only the coefficients of the config mask are modified, so calling it again on the result with another
+/// key and a non-overlapping mask adds a second watermark, see [`EmbedConfig::mask`]
+///
+/// Errors if the host is smaller than a block or if the watermark doesn't fit into the host
+pub fn embed_with(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<DynamicImage, WatermarkError> {
+    embed_image(host, watermark, config, None).map(|(watermarked, _)| watermarked)
+}
+
+/// Same as [`embed_with`], also reporting the capacity used and the distortion of the embed
+///
+/// Errors for the same reasons as [`embed_with`]
+pub fn embed_with_report(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<(DynamicImage, EmbedReport), WatermarkError> {
+    embed_image(host, watermark, config, None)
+}
+
+/// Same as [`embed_with`], but checks the coefficients it writes against `usage`, then marks them as used
+///
+/// Errors with [`WatermarkError::MaskConflict`] if a previous embed recorded in `usage` wrote one of those
+/// coefficients, nothing is embedded nor marked then, or for the same reasons as [`embed_with`]
+pub fn embed_with_usage(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+    usage: &mut qim::CoefficientUsage,
+) -> Result<DynamicImage, WatermarkError> {
+    embed_image(host, watermark, config, Some(usage)).map(|(watermarked, _)| watermarked)
+}
+
+/// Embeds the watermark into every host with [`embed_with`], calling `progress(done, total)` as each one completes
+///
+/// Return value: the result of each host, in order. A failing host doesn't stop the others
+pub fn embed_batch(
+    hosts: &[DynamicImage],
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+    mut progress: impl FnMut(usize, usize),
+) -> Vec<Result<DynamicImage, WatermarkError>> {
+    hosts
+        .iter()
+        .enumerate()
+        .map(|(n, host)| {
+            let watermarked = embed_with(host, watermark, config);
+            progress(n + 1, hosts.len());
+            watermarked
+        })
+        .collect()
+}
+
+/// Same as [`embed_watermark_image`], then extracts the watermark back in memory
+///
+/// Return value: the watermarked image and the bit error rate of the extraction, 0 for a clean embed.
+/// A step size too small for the rounding to 8-bit samples shows up as errors, so a caller can raise
+/// the step size until the embed verifies
+pub fn embed_and_verify(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<(DynamicImage, f64), WatermarkError> {
+    embed_and_verify_with(host, watermark, &EmbedConfig::new(key).step_size(step_size))
+}
+
+/// Same as [`embed_with`], then extracts the watermark back in memory, see [`embed_and_verify`]
+///
+/// Return value: the watermarked image and the bit error rate of the extraction
+pub fn embed_and_verify_with(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<(DynamicImage, f64), WatermarkError> {
+    let watermarked = embed_with(host, watermark, config)?;
+
+    // Extract with the kind embedding detected, which extraction can't guess
+    let kind = config
+        .watermark_kind
+        .unwrap_or_else(|| WatermarkKind::detect(watermark));
+    let (wm_width, wm_height) = watermark.dimensions();
+    let bits = extract_bits_with(
+        &watermarked,
+        &config.clone().watermark_kind(kind),
+        wm_width,
+        wm_height,
+    )?;
+    let ber = metrics::bit_error_rate(&kind.recode(watermark, config.bits_per_channel), &bits)?;
+    Ok((watermarked, ber))
+}
+
+/// Embeds a synthetic watermark into a synthetic host, saves the result as PNG in memory and extracts it back
+///
+/// Return value: whether every bit came back. A quick check that a build, its image codecs and
+/// its floating point math work; the images are generated from their coordinates, the same every run
+pub fn self_test() -> bool {
+    // Smooth mid-range colors, away from the clipping of saturated ones
+    let host = DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |x, y| {
+        image::Rgb([
+            (48 + (x + y) / 4) as u8,
+            (64 + x / 2) as u8,
+            (192 - y / 2) as u8,
+        ])
+    }));
+    let watermark = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+        let on = |bit: u32| if bit == 1 { 255 } else { 0 };
+        image::Rgb([on((x / 8 + y / 8) % 2), on(x / 16 % 2), on(y / 4 % 2)])
+    }));
+    // Large enough a step that the rounding of the YCbCr round trip flips no bit
+    let (key, step_size) = (0x5e1f_7e57, 100.0);
+
+    let round_trip = || -> Result<f64, WatermarkError> {
+        let watermarked = embed_watermark_image(&host, &watermark, key, step_size)?;
+        let decoded = image::load_from_memory(&encode_to_bytes(&watermarked, ImageFormat::Png)?)?;
+        let (bits, _) = extract_watermark_image(&decoded, key, step_size, 64, 64)?;
+        metrics::bit_error_rate(&color_recode::recode_to_3bits(&watermark), &bits)
+    };
+    matches!(round_trip(), Ok(ber) if ber == 0.0)
+}
+
+/// Finds the largest step size whose watermarked image keeps a PSNR of at least `target_psnr` dB
+///
+/// Return value: the step size and the image watermarked with it
+///
+/// Doubles the step size from 1 until the PSNR drops below the target, then bisects the last interval.
+/// The PSNR doesn't fall strictly with the step size, so the step is the largest one found, not
+/// necessarily the largest one meeting the target
+///
+/// Errors if the watermark doesn't fit or if even a step size of 1 misses the target
+pub fn auto_step_size(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    key: u64,
+    target_psnr: f64,
+) -> Result<(f32, DynamicImage), WatermarkError> {
+    auto_step_size_with(host, watermark, &EmbedConfig::new(key), target_psnr)
+}
+
+/// Same as [`auto_step_size`], ignoring the step size of the config
+pub fn auto_step_size_with(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+    target_psnr: f64,
+) -> Result<(f32, DynamicImage), WatermarkError> {
+    let embed_at = |step_size: f32| -> Result<Option<DynamicImage>, WatermarkError> {
+        let watermarked = embed_with(host, watermark, &config.clone().step_size(step_size))?;
+        Ok((metrics::psnr(host, &watermarked) >= target_psnr).then_some(watermarked))
+    };
+
+    let mut best = match embed_at(MIN_AUTO_STEP)? {
+        Some(watermarked) => (MIN_AUTO_STEP, watermarked),
+        None => return Err(WatermarkError::TargetPsnr(target_psnr)),
+    };
+    // Double until the target is missed, the step then lies between the last two tries
+    let mut high = 2.0 * MIN_AUTO_STEP;
+    while high <= MAX_AUTO_STEP {
+        match embed_at(high)? {
+            Some(watermarked) => best = (high, watermarked),
+            None => break,
+        }
+        high *= 2.0;
+    }
+    if high > MAX_AUTO_STEP {
+        return Ok(best);
+    }
+
+    for _ in 0..AUTO_STEP_BISECTIONS {
+        let middle = (best.0 + high) / 2.0;
+        match embed_at(middle)? {
+            Some(watermarked) => best = (middle, watermarked),
+            None => high = middle,
+        }
+    }
+    Ok(best)
+}
+
+/// Passes of [`embed_fragile`] correcting the blocks the rounding to 8-bit samples flipped
+const FRAGILE_PASSES: usize = 8;
+
+/// Embeds a fragile watermark for [`locate_tamper`], a pattern derived from the key filling every block
+///
+/// Uses [`EmbedConfig::fragile`] with the given step size, [`config::FRAGILE_STEP`] being about the smallest
+/// one that rises above the noise of 8-bit samples. The colorspace round trip and the rounding to samples
+/// still flip a bit of some blocks, so the embed is read back and the blocks that mismatch are moved by the
+/// difference to their lattice points, until an untouched lossless save reads the pattern intact or for
+/// 8 passes at most. Any edit, recompression included, flips the bits of the blocks it touches
+///
+/// Errors if the host is smaller than a block
+pub fn embed_fragile(
+    host: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<DynamicImage, WatermarkError> {
+    let config = EmbedConfig::fragile(key).step_size(step_size);
+    let (width, height) = host.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_step_size(step_size)?;
+    let pattern = qim::fragile_pattern(plane_capacity(width, height, &config), key);
+    let (mut watermarked, _) = embed_stream(host, &pattern, &config, None)?;
+
+    let block_transform = BlockTransform::new(&config);
+    let dithers =
+        qim::generate_dither_lattices(qim::MASK_LEN, config.step_size, config.levels, config.key);
+    let mask = config.mask.scaled(config.block_size);
+    let block_size = config.block_size as u32;
+    let blocks_per_row = width / block_size;
+    for _ in 0..FRAGILE_PASSES {
+        let blocks = transform_channels(&watermarked, &config);
+        let mismatches: Vec<usize> = fragile_mismatches(&blocks, &pattern, &config).collect();
+        if mismatches.iter().all(|&mismatches| mismatches == 0) {
+            break;
+        }
+        for (n, bits) in pattern.chunks(block_bits(&config)).enumerate() {
+            if mismatches[n] == 0 {
+                continue;
+            }
+            // Embedding again into the coefficients read back gives the change that puts them on their lattices
+            let (step_size, dithers) = block_quantizer(&blocks[n], n, &dithers, &mask, &config);
+            let mut change = blocks[n].clone();
+            qim::embed_wm_mary(
+                &mut change,
+                bits,
+                &dithers,
+                config.levels,
+                step_size,
+                1.0,
+                &mask,
+            );
+            for (change, read) in change.iter_mut().zip(&blocks[n]) {
+                *change -= read;
+            }
+            block_transform.inverse(std::slice::from_mut(&mut change));
+            let n = n as u32;
+            let (x, y) = (
+                n % blocks_per_row * block_size,
+                n / blocks_per_row * block_size,
+            );
+            if !add_to_block(&mut watermarked, x, y, &change) {
+                // The change rounded away under half a level, scale it up until its largest part moves a sample
+                let largest = change
+                    .iter()
+                    .fold(0.0f32, |largest, change| largest.max(change.abs()));
+                for change in change.iter_mut() {
+                    *change *= 0.6 / largest;
+                }
+                add_to_block(&mut watermarked, x, y, &change);
+            }
+        }
+    }
+    Ok(watermarked)
+}
+
+/// Adds `change`, in levels, to the color samples of the pixels of the block at `(x, y)`
+///
+/// The same change on R, G and B moves Y alone. Leaves alpha and floating point images as they are.
+/// Return value: whether any sample changed once rounded
+fn add_to_block(image: &mut DynamicImage, x: u32, y: u32, change: &[f32]) -> bool {
+    match image {
+        DynamicImage::ImageLuma8(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageLumaA8(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageRgb8(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageRgba8(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageLuma16(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageLumaA16(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageRgb16(image) => add_to_block_of(image, x, y, change),
+        DynamicImage::ImageRgba16(image) => add_to_block_of(image, x, y, change),
+        _ => false,
+    }
+}
+
+fn add_to_block_of<P>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    x: u32,
+    y: u32,
+    change: &[f32],
+) -> bool
+where
+    P: Pixel,
+    P::Subpixel: dct::Sample,
+{
+    use dct::Sample;
+
+    let block_size = change.len().isqrt() as u32;
+    let mut changed = false;
+    for (i, &change) in change.iter().enumerate() {
+        let pixel = image.get_pixel_mut(x + i as u32 % block_size, y + i as u32 / block_size);
+        let before = *pixel;
+        pixel.apply_without_alpha(|sample| Sample::from_level(sample.to_level() + change as f64));
+        changed |= pixel.channels() != before.channels();
+    }
+    changed
+}
+
+/// Number of bits of each block, in the order of `blocks`, that don't match the fragile `pattern`
+fn fragile_mismatches<'a>(
+    blocks: &[Vec<f32>],
+    pattern: &'a BitSlice,
+    config: &EmbedConfig,
+) -> impl Iterator<Item = usize> + 'a {
+    let bits = extract_bits(blocks, None, 0, config);
+    let block_bits = block_bits(config);
+    (0..bits.len() / block_bits).map(move |n| {
+        let range = n * block_bits..(n + 1) * block_bits;
+        bits[range.clone()]
+            .iter()
+            .zip(&pattern[range])
+            .filter(|(a, b)| a != b)
+            .count()
+    })
+}
+
+/// Maps the blocks of an image watermarked by [`embed_fragile`] that no longer carry the pattern of the key
+///
+/// Return value: a heatmap with one pixel per whole block, from 0 for an intact block to 255 when
+/// every bit mismatches. Edited blocks mismatch about half their bits, while intact blocks clipped
+/// by saturated colors can mismatch one or two
+///
+/// Errors if the image is smaller than a block
+pub fn locate_tamper(
+    watermarked: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<GrayImage, WatermarkError> {
+    let config = EmbedConfig::fragile(key).step_size(step_size);
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_step_size(step_size)?;
+
+    let blocks = transform_channels(watermarked, &config);
+    let pattern = qim::fragile_pattern(plane_capacity(width, height, &config), key);
+    let heat = fragile_mismatches(&blocks, &pattern, &config)
+        .map(|mismatches| (255 * mismatches / block_bits(&config)) as u8)
+        .collect();
+    let block_size = config.block_size as u32;
+    Ok(GrayImage::from_raw(width / block_size, height / block_size, heat).unwrap())
+}
+
+fn embed_image(
+    host: &DynamicImage,
+    watermark: &DynamicImage,
+    config: &EmbedConfig,
+    usage: Option<&mut qim::CoefficientUsage>,
+) -> Result<(DynamicImage, EmbedReport), WatermarkError> {
     let (h_width, h_height) = host.dimensions();
-    assert!(
-        h_width == 512 && h_height == 512,
-        "{}",
-        format!(
-            "Host image:'{}' must be 512 * 512, got {} * {}",
-            host_image, h_width, h_height
+    check_host_dimensions(h_width, h_height, config.block_size)?;
+    check_alpha(config.alpha)?;
+    check_levels(config.levels)?;
+    check_channels(config)?;
+    check_energy_host(host, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+    check_bits_per_channel(config.bits_per_channel)?;
+
+    // Recoding the watermark, the header and the watermark are each padded with zeros to whole blocks
+    let copies = config.redundancy.copies();
+    let kind = config
+        .watermark_kind
+        .unwrap_or_else(|| WatermarkKind::detect(watermark));
+    let mut wm_bits = if config.header {
+        let (wm_width, wm_height) = watermark.dimensions();
+        let header = payload::encode_header(wm_width, wm_height, kind, config.bits_per_channel)?;
+        payload::spread(&header, block_bits(config), copies)
+    } else {
+        BitVec::new()
+    };
+    let recoded = encode_watermark_bits(&kind.recode(watermark, config.bits_per_channel), config);
+    wm_bits.extend_from_bitslice(&payload::spread(&recoded, block_bits(config), copies));
+    embed_stream(host, &wm_bits, config, usage)
+}
+
+/// Embeds `text` into the host image instead of a watermark image
+///
+/// Errors if the host is smaller than a block or if the text doesn't fit into the host
+pub fn embed_text(
+    host: &DynamicImage,
+    text: &str,
+    key: u64,
+    step_size: f32,
+) -> Result<DynamicImage, WatermarkError> {
+    embed_text_with(host, text, &EmbedConfig::new(key).step_size(step_size))
+}
+
+/// Embeds the UTF-8 bytes of `text` with the given config, preceded by their length
+///
+/// The header, watermark kind and bits per channel of the config don't apply to text
+///
+/// Errors if the host is smaller than a block or if the text doesn't fit into the host
+pub fn embed_text_with(
+    host: &DynamicImage,
+    text: &str,
+    config: &EmbedConfig,
+) -> Result<DynamicImage, WatermarkError> {
+    let (h_width, h_height) = host.dimensions();
+    check_host_dimensions(h_width, h_height, config.block_size)?;
+    check_alpha(config.alpha)?;
+    check_levels(config.levels)?;
+    check_channels(config)?;
+    check_energy_host(host, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+
+    let copies = config.redundancy.copies();
+    let len = payload::bytes_to_bits(&(text.len() as u32).to_be_bytes());
+    let mut wm_bits = payload::spread(&len, block_bits(config), copies);
+    let text_bits = encode_watermark_bits(&payload::bytes_to_bits(text.as_bytes()), config);
+    wm_bits.extend_from_bitslice(&payload::spread(&text_bits, block_bits(config), copies));
+    embed_stream(host, &wm_bits, config, None).map(|(watermarked, _)| watermarked)
+}
+
+/// Embeds a bit stream filling whole blocks into the planes of a host with valid dimensions,
+/// claiming the written coefficients in `usage` if given
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(bits = wm_bits.len()))
+)]
+fn embed_stream(
+    host: &DynamicImage,
+    wm_bits: &BitSlice,
+    config: &EmbedConfig,
+    usage: Option<&mut qim::CoefficientUsage>,
+) -> Result<(DynamicImage, EmbedReport), WatermarkError> {
+    let (h_width, h_height) = host.dimensions();
+    check_capacity(h_width, h_height, config, wm_bits.len())?;
+    if config.preserve_dc && config.mask.indices().contains(&0) {
+        return Err(WatermarkError::InvalidMask(
+            "position 0 is the DC coefficient, which preserve_dc keeps".to_string(),
+        ));
+    }
+    let plane_capacity = plane_capacity(h_width, h_height, config);
+    if let Some(usage) = usage {
+        let blocks: Vec<(Channel, usize)> = config
+            .channels
+            .iter()
+            .zip(plane_streams(wm_bits, plane_capacity, config))
+            .map(|(&channel, bits)| (channel, bits.len() / block_bits(config)))
+            .collect();
+        usage.claim(config.block_size, &config.mask, &blocks)?;
+    }
+
+    // Grayscale hosts skip the colorspace round trip, others are converted to HSV or YCbCr,
+    // keeping the alpha channel aside and 16-bit hosts 16-bit in YCbCr
+    let luma = if config.channels == [Channel::Y] {
+        embed_into_luma(host, wm_bits, config)
+    } else {
+        None
+    };
+    let (watermarked, (delta_sum, squared_error)) = if let Some(embedded) = luma {
+        embedded?
+    } else if config.color_domain == ColorDomain::Hsv {
+        let alpha = colorspace::split_alpha(host);
+        let mut planes = colorspace::convert_to_HSV(host);
+        let distortion = embed_into_plane(&mut planes.v, h_width, h_height, wm_bits, 0, config)?;
+        let watermarked = colorspace::convert_HSV_to_RGB(&planes);
+        (
+            colorspace::attach_alpha(watermarked, alpha.as_deref()),
+            distortion,
         )
-    );
+    } else if colorspace::keeps_16_bit(host, &config.color) {
+        let alpha = colorspace::split_alpha16(host);
+        let mut planes = colorspace::convert_to_YCbCr16_with(host, &config.color);
+        let distortion = embed_into_planes(
+            [&mut planes.y, &mut planes.cb, &mut planes.cr],
+            h_width,
+            h_height,
+            wm_bits,
+            config,
+        )?;
+        let watermarked = colorspace::convert_to_RGB16_with(&planes, &config.color);
+        (
+            colorspace::attach_alpha16(watermarked, alpha.as_deref()),
+            distortion,
+        )
+    } else {
+        let alpha = colorspace::split_alpha(host);
+        let mut planes = colorspace::convert_to_YCbCr_with(host, &config.color);
+        let distortion = embed_into_planes(
+            [&mut planes.y, &mut planes.cb, &mut planes.cr],
+            h_width,
+            h_height,
+            wm_bits,
+            config,
+        )?;
+        // Convert back to RGB colorspace, with alpha if the host had one
+        let watermarked = colorspace::convert_to_RGB_with(&planes, &config.color);
+        (
+            colorspace::attach_alpha(watermarked, alpha.as_deref()),
+            distortion,
+        )
+    };
+
+    let watermarked = if config.region.is_some() {
+        restore_outside_blocks(host, watermarked, config)
+    } else {
+        watermarked
+    };
+
+    let bits_embedded: usize = plane_streams(wm_bits, plane_capacity, config)
+        .iter()
+        .map(|bits| bits.len())
+        .sum();
+    let blocks_used = bits_embedded / block_bits(config);
+    let mse = squared_error / (h_width as f64 * h_height as f64);
+    let report = EmbedReport {
+        blocks_total: plane_capacity / block_bits(config) * config.channels.len(),
+        blocks_used,
+        bits_embedded,
+        mean_coefficient_delta: if blocks_used == 0 {
+            0.0
+        } else {
+            (delta_sum / bits_embedded as f64) as f32
+        },
+        estimated_psnr: 10.0 * (255.0 * 255.0 / mse).log10(),
+    };
+    Ok((watermarked, report))
+}
+
+/// `(sum of coefficient change magnitudes, squared error)` of an embed
+type Distortion = (f64, f64);
+
+/// Embeds the bit stream straight into the luma of a grayscale host, returning an image of the same type
+///
+/// Return value: `None` unless the host is grayscale, else the image and
+/// `(sum of coefficient change magnitudes, squared error)`, or why the bits don't fit
+fn embed_into_luma(
+    host: &DynamicImage,
+    wm_bits: &BitSlice,
+    config: &EmbedConfig,
+) -> Option<Result<(DynamicImage, Distortion), WatermarkError>> {
+    let embedded = match host {
+        DynamicImage::ImageLuma8(image) => {
+            let mut image = image.clone();
+            embed_into_first_channel(&mut image, wm_bits, config)
+                .map(|distortion| (DynamicImage::ImageLuma8(image), distortion))
+        }
+        DynamicImage::ImageLumaA8(image) => {
+            let mut image = image.clone();
+            embed_into_first_channel(&mut image, wm_bits, config)
+                .map(|distortion| (DynamicImage::ImageLumaA8(image), distortion))
+        }
+        DynamicImage::ImageLuma16(image) => {
+            let mut image = image.clone();
+            embed_into_first_channel(&mut image, wm_bits, config)
+                .map(|distortion| (DynamicImage::ImageLuma16(image), distortion))
+        }
+        DynamicImage::ImageLumaA16(image) => {
+            let mut image = image.clone();
+            embed_into_first_channel(&mut image, wm_bits, config)
+                .map(|distortion| (DynamicImage::ImageLumaA16(image), distortion))
+        }
+        _ => return None,
+    };
+    Some(embedded)
+}
+
+/// Embeds the bit stream into the first channel of the image, the luma of grayscale images
+///
+/// Return value: `(sum of coefficient change magnitudes, squared error)`
+fn embed_into_first_channel<P>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    wm_bits: &BitSlice,
+    config: &EmbedConfig,
+) -> Result<Distortion, WatermarkError>
+where
+    P: Pixel,
+    P::Subpixel: dct::Sample,
+{
+    let (width, height) = image.dimensions();
+    let mut plane: Vec<P::Subpixel> = image.pixels().map(|p| p.channels()[0]).collect();
+    let distortion = embed_into_plane(&mut plane, width, height, wm_bits, 0, config)?;
+    for (pixel, sample) in image.pixels_mut().zip(plane) {
+        pixel.channels_mut()[0] = sample;
+    }
+    Ok(distortion)
+}
+
+/// Copies back the host pixels outside the blocks carrying bits, undoing the rounding of the colorspace round trip
+///
+/// Grayscale hosts, which skip the round trip, are returned as is
+fn restore_outside_blocks(
+    host: &DynamicImage,
+    watermarked: DynamicImage,
+    config: &EmbedConfig,
+) -> DynamicImage {
+    let (width, height) = host.dimensions();
+    let (cols, rows) = block_span(width, height, config);
+    let block_size = config.block_size as u32;
+    let xs = cols.start as u32 * block_size..cols.end as u32 * block_size;
+    let ys = rows.start as u32 * block_size..rows.end as u32 * block_size;
+    match watermarked {
+        DynamicImage::ImageRgb8(mut image) => {
+            restore_pixels(&mut image, &host.to_rgb8(), &xs, &ys);
+            image.into()
+        }
+        DynamicImage::ImageRgba8(mut image) => {
+            restore_pixels(&mut image, &host.to_rgba8(), &xs, &ys);
+            image.into()
+        }
+        DynamicImage::ImageRgb16(mut image) => {
+            restore_pixels(&mut image, &host.to_rgb16(), &xs, &ys);
+            image.into()
+        }
+        DynamicImage::ImageRgba16(mut image) => {
+            restore_pixels(&mut image, &host.to_rgba16(), &xs, &ys);
+            image.into()
+        }
+        other => other,
+    }
+}
+
+/// Copies the pixels of `host` outside of `xs * ys` into `image`
+fn restore_pixels<P: Pixel>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    host: &ImageBuffer<P, Vec<P::Subpixel>>,
+    xs: &std::ops::Range<u32>,
+    ys: &std::ops::Range<u32>,
+) {
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        if !xs.contains(&x) || !ys.contains(&y) {
+            *pixel = *host.get_pixel(x, y);
+        }
+    }
+}
+
+/// Embeds the bit stream into the `[y, cb, cr]` planes selected by the config, one after another
+///
+/// Return value: `(sum of coefficient change magnitudes, squared error)` over the planes
+fn embed_into_planes<S: dct::Sample>(
+    [y_plane, cb_plane, cr_plane]: [&mut Vec<S>; 3],
+    width: u32,
+    height: u32,
+    wm_bits: &BitSlice,
+    config: &EmbedConfig,
+) -> Result<Distortion, WatermarkError> {
+    let plane_capacity = plane_capacity(width, height, config);
+    let plane_blocks = plane_capacity / block_bits(config);
+    let (mut delta_sum, mut squared_error) = (0.0, 0.0);
+    for (n, (channel, bits)) in config
+        .channels
+        .iter()
+        .zip(plane_streams(wm_bits, plane_capacity, config))
+        .enumerate()
+    {
+        let plane = match channel {
+            Channel::Y => &mut *y_plane,
+            Channel::Cb => &mut *cb_plane,
+            Channel::Cr => &mut *cr_plane,
+        };
+        let (plane_delta_sum, plane_squared_error) =
+            embed_into_plane(plane, width, height, bits, n * plane_blocks, config)?;
+        delta_sum += plane_delta_sum;
+        squared_error += plane_squared_error;
+    }
+    Ok((delta_sum, squared_error))
+}
+
+/// Embeds `wm_bits` into the leading blocks of a plane, `wm_bits` must fill whole blocks
+///
+/// `first_block` is the index in the bit stream of the first block of the plane
+///
+/// Return value: `(sum of coefficient change magnitudes, squared error of the plane)`, both before rounding
+///
+/// Errors if skipping the low energy blocks leaves too few for `wm_bits`
+fn embed_into_plane<S: dct::Sample>(
+    plane: &mut Vec<S>,
+    width: u32,
+    height: u32,
+    wm_bits: &BitSlice,
+    first_block: usize,
+    config: &EmbedConfig,
+) -> Result<Distortion, WatermarkError> {
+    // Split the plane into blocks for the block transform
+    let block_size = config.block_size;
+    let mut grid = dct::split_into_blocks(plane, width as usize, height as usize, block_size);
+    let blocks = &mut grid.blocks;
+    let full_blocks = full_blocks(width, height, config);
+    // Callers check the capacity and pad the stream to whole blocks
+    assert!(
+        wm_bits.len() % block_bits(config) == 0
+            && wm_bits.len() / block_bits(config) <= full_blocks.len(),
+        "{} bits don't fill whole blocks of the {} available",
+        wm_bits.len(),
+        full_blocks.len()
+    );
+    let means: Vec<f64> = if config.preserve_dc {
+        blocks
+            .iter()
+            .map(|block| block.iter().map(|&v| v as f64).sum::<f64>() / block.len() as f64)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // DCT, or WHT, on the blocks
+    let block_transform = BlockTransform::new(config);
+    block_transform.forward(blocks);
+
+    let carriers: Vec<usize> = full_blocks
+        .into_iter()
+        .filter(|&i| carries_bits(&blocks[i], config))
+        .collect();
+    if wm_bits.len() / block_bits(config) > carriers.len() {
+        return Err(WatermarkError::Capacity {
+            required: wm_bits.len(),
+            available: carriers.len() * block_bits(config),
+        });
+    }
+    let used_blocks = &carriers[..wm_bits.len() / block_bits(config)];
+
+    // QIM-DM to embed the watermark with the preset key and step_size
+    let dithers = qim::generate_dither_lattices(
+        config.mask.len(),
+        config.step_size,
+        config.levels,
+        config.key,
+    );
+    let mask = config.mask.scaled(block_size);
+    let (mut delta_sum, mut squared_error) = (0.0, 0.0);
+    for (n, &i) in used_blocks.iter().enumerate() {
+        let (step_size, dithers) =
+            block_quantizer(&blocks[i], first_block + n, &dithers, &mask, config);
+        let original: Vec<f32> = mask.indices().iter().map(|&j| blocks[i][j]).collect();
+        let start = n * block_bits(config);
+        qim::embed_wm_mary_at(
+            &mut blocks[i],
+            wm_bits,
+            start,
+            &dithers,
+            config.levels,
+            step_size,
+            config.alpha,
+            &mask,
+        );
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            block = first_block + n,
+            bits = block_bits(config),
+            ones = wm_bits[start..start + block_bits(config)].count_ones(),
+            "embedded block"
+        );
+        for (value, &j) in original.iter().zip(mask.indices()) {
+            let delta = (blocks[i][j] - value) as f64;
+            delta_sum += delta.abs();
+            squared_error += (delta * block_transform.orthonormal_scale(j)).powi(2);
+        }
+    }
+
+    // Inverse transform on watermarked blocks and convert them back to a plane
+    block_transform.inverse(blocks);
+    if config.preserve_dc {
+        for &i in used_blocks {
+            restore_mean::<S>(&mut blocks[i], means[i]);
+        }
+    }
+    *plane = dct::merge_into_plane(&grid);
+    Ok((delta_sum, squared_error))
+}
+
+/// Whether a transformed block carries bits, unless its energy is under [`EmbedConfig::min_block_energy`]
+fn carries_bits(block: &[f32], config: &EmbedConfig) -> bool {
+    config.min_block_energy.is_none_or(|min_energy| {
+        qim::block_energy(block, &config.mask.scaled(config.block_size)) >= min_energy
+    })
+}
+
+/// Adjusts the block so the mean of its samples, once rounded and clamped as `S`, is `mean` up to a step
+///
+/// Shifts away the drift of the transforms, then moves by one step the samples the rounding moved the most,
+/// until the rounded samples add up to the original sum. Clamped samples are left alone
+fn restore_mean<S: dct::Sample>(block: &mut [f32], mean: f64) {
+    let len = block.len() as f64;
+    let drift = mean - block.iter().map(|&v| v as f64).sum::<f64>() / len;
+    for value in block.iter_mut() {
+        *value += drift as f32;
+    }
+
+    let round = |v: f64| S::from_level(v).to_level();
+    let rounded: Vec<f64> = block.iter().map(|&v| round(v as f64)).collect();
+    let missing = ((mean * len - rounded.iter().sum::<f64>()) / S::STEP).round();
+    let step = S::STEP.copysign(missing);
+    // Samples rounded the furthest against the missing sum first
+    let mut order: Vec<usize> = (0..block.len()).collect();
+    order.sort_by(|&a, &b| {
+        let lost = |k: usize| (block[k] as f64 - rounded[k]) * step.signum();
+        lost(b).total_cmp(&lost(a))
+    });
+    let mut missing = missing.abs() as usize;
+    for k in order {
+        if missing == 0 {
+            break;
+        }
+        let moved = round(rounded[k] + step);
+        if moved != rounded[k] {
+            block[k] = moved as f32;
+            missing -= 1;
+        }
+    }
+}
+
+/// Extract the colored watermark embedded using DCT + QIM-DM watermarking scheme
+///
+/// Returns the original bit stream and the reconstructed RGB DynamicImage
+///
+/// `wm_width` and `wm_height` are the dimensions of the embedded watermark,
+/// with watermark embedded in implementation specific locations
+///
+/// Assumes an RGB watermark, grayscale ones need [`extract_with`] and [`EmbedConfig::watermark_kind`]
+pub fn extract_watermark(
+    watermarked_image: &str,
+    key: u64,
+    step_size: f32,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    let wmkd_image = image::open(watermarked_image)?;
+    extract_watermark_image(&wmkd_image, key, step_size, wm_width, wm_height)
+}
+
+/// Same as [`extract_watermark`], but works on an already decoded image
+///
+/// Returns the original bit stream and the reconstructed RGB DynamicImage
+///
+/// Errors if the image is smaller than a block or if the image can't hold a watermark of the given size
+pub fn extract_watermark_image(
+    watermarked: &DynamicImage,
+    key: u64,
+    step_size: f32,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    extract_with(
+        watermarked,
+        &EmbedConfig::new(key).step_size(step_size),
+        wm_width,
+        wm_height,
+    )
+}
+
+/// Same as [`extract_watermark`], but decodes the image from memory
+///
+/// Return value: the reconstructed watermark encoded to `format`
+pub fn extract_watermark_bytes(
+    watermarked: &[u8],
+    key: u64,
+    step_size: f32,
+    wm_width: u32,
+    wm_height: u32,
+    format: ImageFormat,
+) -> Result<Vec<u8>, WatermarkError> {
+    let wmkd_image = image::load_from_memory(watermarked)?;
+    let (_, wm) = extract_watermark_image(&wmkd_image, key, step_size, wm_width, wm_height)?;
+    encode_to_bytes(&wm, format)
+}
+
+/// Extracts a `wm_width * wm_height` watermark embedded with the given config
+///
+/// Returns the original bit stream and the reconstructed RGB DynamicImage
+///
+/// Errors if the image is smaller than a block or if the image can't hold a watermark of the given size
+pub fn extract_with(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    let bits = extract_bits_with(watermarked, config, wm_width, wm_height)?;
+    let kind = config.watermark_kind.unwrap_or(WatermarkKind::Rgb);
+    let wm = kind.reconstruct(&bits, wm_width, wm_height, config.bits_per_channel);
+    Ok((bits, wm))
+}
+
+/// Same as [`extract_watermark_image`], but only returns the bit stream, without reconstructing the image
+///
+/// Errors if the image is smaller than a block or if the image can't hold a watermark of the given size
+pub fn extract_watermark_bits(
+    watermarked: &DynamicImage,
+    key: u64,
+    step_size: f32,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<BitVec, WatermarkError> {
+    extract_bits_with(
+        watermarked,
+        &EmbedConfig::new(key).step_size(step_size),
+        wm_width,
+        wm_height,
+    )
+}
+
+/// Same as [`extract_with`], but only returns the bit stream, for comparisons or error correction
+///
+/// Errors if the image is smaller than a block or if the image can't hold a watermark of the given size
+pub fn extract_bits_with(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<BitVec, WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_levels(config.levels)?;
+    check_acceptable_range(config)?;
+    check_channels(config)?;
+    check_energy_host(watermarked, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+
+    check_bits_per_channel(config.bits_per_channel)?;
+
+    let blocks = transform_channels(watermarked, config);
+    let offset = if config.header {
+        header_blocks(config)
+    } else {
+        0
+    };
+    let kind = config.watermark_kind.unwrap_or(WatermarkKind::Rgb);
+    read_watermark(
+        &blocks,
+        None,
+        offset,
+        (width, height),
+        config,
+        (wm_width, wm_height, kind, config.bits_per_channel),
+    )
+}
+
+/// Same as [`extract_watermark_bits`], for watermarks embedded with [`EmbedConfig::authenticate`] set
+///
+/// Return value: the bits if their authentication tag matches. Images without the watermark, or with
+/// another key, come back unauthenticated, as well as watermarks too damaged to trust
+pub fn extract_authenticated(
+    watermarked: &DynamicImage,
+    key: u64,
+    step_size: f32,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<Authentication, WatermarkError> {
+    extract_authenticated_with(
+        watermarked,
+        &EmbedConfig::new(key).step_size(step_size),
+        wm_width,
+        wm_height,
+    )
+}
+
+/// Same as [`extract_authenticated`], with the given config, whether or not it sets [`EmbedConfig::authenticate`]
+///
+/// Errors if the image is smaller than a block or if the image can't hold a watermark of the given size
+pub fn extract_authenticated_with(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<Authentication, WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_levels(config.levels)?;
+    check_acceptable_range(config)?;
+    check_channels(config)?;
+    check_energy_host(watermarked, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+    check_bits_per_channel(config.bits_per_channel)?;
+
+    let config = config.clone().authenticate(true);
+    let blocks = transform_channels(watermarked, &config);
+    let offset = if config.header {
+        header_blocks(&config)
+    } else {
+        0
+    };
+    let kind = config.watermark_kind.unwrap_or(WatermarkKind::Rgb);
+    let (bits, authentic) = read_authenticated(
+        &blocks,
+        None,
+        offset,
+        (width, height),
+        &config,
+        (wm_width, wm_height, kind, config.bits_per_channel),
+    )?;
+    Ok(if authentic {
+        Authentication::Authenticated(bits)
+    } else {
+        Authentication::Unauthenticated
+    })
+}
+
+/// Soft values of the bits carried by every block of the image, for custom decoders
+///
+/// Yields `(block_x, block_y, soft_bits)` in the order the blocks carry the bit stream, `block_x` and
+/// `block_y` counting blocks from the top left. See [`qim::extract_wm_mary_soft`] for the soft values
+///
+/// Errors if the image is smaller than a block
+pub fn extract_blocks(
+    watermarked: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<impl Iterator<Item = (usize, usize, Vec<f32>)>, WatermarkError> {
+    extract_blocks_with(watermarked, &EmbedConfig::new(key).step_size(step_size))
+}
+
+/// Same as [`extract_blocks`], with the given config
+///
+/// Blocks of several channels come one plane after another, each plane yielding the same coordinates.
+/// The whole image is transformed and decoded up front
+pub fn extract_blocks_with(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<impl Iterator<Item = (usize, usize, Vec<f32>)>, WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_levels(config.levels)?;
+    check_step_size(config.step_size)?;
+    let positions = carrier_blocks(watermarked, config)?;
+
+    let blocks = transform_channels(watermarked, config);
+    let soft = extract_soft(&blocks, 0, config);
+    let soft_bits: Vec<Vec<f32>> = soft
+        .chunks(block_bits(config))
+        .map(<[f32]>::to_vec)
+        .collect();
+    let blocks_per_row = (width as usize).div_ceil(config.block_size);
+    Ok(positions
+        .into_iter()
+        .cycle()
+        .zip(soft_bits)
+        .map(move |(i, values)| (i % blocks_per_row, i / blocks_per_row, values)))
+}
+
+/// Confidence in `[0, 1]` that the image carries a watermark embedded with the key and step size
+///
+/// Scores how tightly the masked coefficients of every block cluster on the QIM lattices, whatever the
+/// bits: the mean magnitude of the soft values, 0.5 for random coefficients, rescaled so that an image
+/// without the watermark scores around 0 and a freshly watermarked one close to 1, less the rounding to
+/// 8-bit samples at small steps. Attacks move the score towards 0, the threshold is up to the caller
+///
+/// Errors if the image is smaller than a block
+pub fn detect_watermark(
+    image: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<f64, WatermarkError> {
+    detect_with(image, &EmbedConfig::new(key).step_size(step_size))
+}
+
+/// Same as [`detect_watermark`], with the given config
+pub fn detect_with(image: &DynamicImage, config: &EmbedConfig) -> Result<f64, WatermarkError> {
+    let (sum, count) = extract_blocks_with(image, config)?
+        .flat_map(|(_, _, soft)| soft)
+        .fold((0.0, 0), |(sum, count), value| {
+            (sum + value.abs() as f64, count + 1)
+        });
+    if count == 0 {
+        return Ok(0.0);
+    }
+    Ok((2.0 * sum / count as f64 - 1.0).clamp(0.0, 1.0))
+}
+
+/// Same as [`extract_bits_with`], but recovers what it can from an image too small for the watermark
+///
+/// Meant for images truncated at the bottom, whose remaining blocks still sit where they were embedded:
+/// the bits of the whole blocks left are decoded and the missing ones read as zeros, before the
+/// majority vote and error correction. Blocks spread over several planes or in a permuted order
+/// no longer line up once the image is truncated, and decode as noise
+///
+/// Errors if the image is smaller than a block
+pub fn extract_partial_with(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<PartialExtraction, WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_levels(config.levels)?;
+    check_acceptable_range(config)?;
+    check_channels(config)?;
+    check_energy_host(watermarked, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+    check_bits_per_channel(config.bits_per_channel)?;
+
+    let blocks = transform_channels(watermarked, config);
+    let offset = if config.header {
+        header_blocks(config)
+    } else {
+        0
+    };
+    let kind = config.watermark_kind.unwrap_or(WatermarkKind::Rgb);
+    let wm_len =
+        wm_width as usize * wm_height as usize * kind.bits_per_pixel(config.bits_per_channel);
+    let stream_len = encoded_watermark_len(wm_len, config);
+    let copies = config.redundancy.copies();
+    let wm_blocks = payload::segment_blocks(stream_len, block_bits(config), copies);
+    let missing_blocks = (offset + wm_blocks).saturating_sub(blocks.len());
+    if missing_blocks == 0 {
+        let wm = (wm_width, wm_height, kind, config.bits_per_channel);
+        let bits = read_watermark(&blocks, None, offset, (width, height), config, wm)?;
+        return Ok(PartialExtraction {
+            bits,
+            missing_blocks,
+        });
+    }
+
+    let first_block = offset.min(blocks.len());
+    let mut stream = extract_bits(&blocks[first_block..], None, first_block, config);
+    stream.resize(wm_blocks * block_bits(config), false);
+    let voted = payload::majority_vote(&stream, stream_len, block_bits(config), copies);
+    Ok(PartialExtraction {
+        bits: decode_watermark_bits(&voted, wm_len, config),
+        missing_blocks,
+    })
+}
+
+/// Same as [`extract_watermark_image`], but decodes with the help of the original host
+///
+/// Returns the original bit stream and the reconstructed RGB DynamicImage
+///
+/// Errors if the original doesn't have the dimensions of the watermarked image
+pub fn extract_watermark_nonblind(
+    watermarked: &DynamicImage,
+    original: &DynamicImage,
+    key: u64,
+    step_size: f32,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    extract_nonblind_with(
+        watermarked,
+        original,
+        &EmbedConfig::new(key).step_size(step_size),
+        wm_width,
+        wm_height,
+    )
+}
+
+/// Same as [`extract_with`], but decodes each coefficient from its change since the original host
+///
+/// Only the symbols next to the embedded one compete under noise, which lowers the bit error rate,
+/// see [`qim::extract_wm_nonblind`]
+///
+/// Errors if the original doesn't have the dimensions of the watermarked image
+pub fn extract_nonblind_with(
+    watermarked: &DynamicImage,
+    original: &DynamicImage,
+    config: &EmbedConfig,
+    wm_width: u32,
+    wm_height: u32,
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    if original.dimensions() != (width, height) {
+        return Err(WatermarkError::OriginalDimensions {
+            expected: (width, height),
+            actual: original.dimensions(),
+        });
+    }
+    check_host_dimensions(width, height, config.block_size)?;
+    check_alpha(config.alpha)?;
+    check_levels(config.levels)?;
+    check_channels(config)?;
+    check_energy_host(watermarked, config)?;
+    check_energy_host(original, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+    check_bits_per_channel(config.bits_per_channel)?;
+
+    let blocks = transform_channels(watermarked, config);
+    let original_blocks = transform_channels(original, config);
+    let offset = if config.header {
+        header_blocks(config)
+    } else {
+        0
+    };
+    let kind = config.watermark_kind.unwrap_or(WatermarkKind::Rgb);
+    reconstruct_watermark(
+        &blocks,
+        Some(&original_blocks),
+        offset,
+        (width, height),
+        config,
+        (wm_width, wm_height, kind, config.bits_per_channel),
+    )
+}
+
+/// Extracts a text embedded with [`embed_text`]
+///
+/// Errors if the image is smaller than a block, if the decoded length doesn't fit into the image
+/// or if the decoded bytes are not UTF-8, both usually meaning that the image carries no text
+pub fn extract_text(
+    watermarked: &DynamicImage,
+    key: u64,
+    step_size: f32,
+) -> Result<String, WatermarkError> {
+    extract_text_with(watermarked, &EmbedConfig::new(key).step_size(step_size))
+}
+
+/// Extracts a text embedded with [`embed_text_with`] and the given config
+///
+/// Errors like [`extract_text`]
+pub fn extract_text_with(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<String, WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_levels(config.levels)?;
+    check_acceptable_range(config)?;
+    check_channels(config)?;
+    check_energy_host(watermarked, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+    let copies = config.redundancy.copies();
+    let len_blocks = payload::segment_blocks(TEXT_LEN_BITS, block_bits(config), copies);
+    check_capacity(width, height, config, len_blocks * block_bits(config))?;
+
+    let blocks = transform_channels(watermarked, config);
+    let len_bits = read_segment(&blocks, None, 0, TEXT_LEN_BITS, config)?;
+    let len = payload::bits_to_bytes(&len_bits)
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | *byte as usize);
+
+    let text_len = len * 8;
+    let stream_len = encoded_watermark_len(text_len, config);
+    let text_blocks = payload::segment_blocks(stream_len, block_bits(config), copies);
+    check_capacity(
+        width,
+        height,
+        config,
+        (len_blocks + text_blocks) * block_bits(config),
+    )?;
+
+    let stream = read_segment(&blocks, None, len_blocks, stream_len, config)?;
+    let text_bits = decode_watermark_bits(&stream, text_len, config);
+    String::from_utf8(payload::bits_to_bytes(&text_bits)).map_err(WatermarkError::InvalidText)
+}
+
+/// Extracts a watermark embedded with [`EmbedConfig::header`] set, reading its dimensions from the image
+///
+/// Returns the original bit stream and the reconstructed RGB DynamicImage
+///
+/// Errors if the image is smaller than a block or if the decoded dimensions don't fit into the image,
+/// which is usually the case when the image carries no header
+pub fn extract_with_header(
+    watermarked: &DynamicImage,
+    config: &EmbedConfig,
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    let (width, height) = watermarked.dimensions();
+    check_host_dimensions(width, height, config.block_size)?;
+    check_levels(config.levels)?;
+    check_acceptable_range(config)?;
+    check_channels(config)?;
+    check_energy_host(watermarked, config)?;
+    check_quant_table(config)?;
+    check_step_size(config.step_size)?;
+    check_capacity(
+        width,
+        height,
+        config,
+        header_blocks(config) * block_bits(config),
+    )?;
+
+    let blocks = transform_channels(watermarked, config);
+    let header = read_segment(&blocks, None, 0, payload::HEADER_LEN, config)?;
+    reconstruct_watermark(
+        &blocks,
+        None,
+        header_blocks(config),
+        (width, height),
+        config,
+        payload::decode_header(&header),
+    )
+}
+
+/// Converts the image to the color domain and returns the whole transformed blocks of the channels selected by the config,
+/// one plane after another
+fn transform_channels(image: &DynamicImage, config: &EmbedConfig) -> Vec<Vec<f32>> {
+    // The luma of grayscale images is their Y plane
+    if config.channels == [Channel::Y] && colorspace::is_luma(image) {
+        let block_transform = BlockTransform::new(config);
+        return if colorspace::is_16_bit(image) {
+            transform_plane(
+                &mut image.to_luma16().into_raw(),
+                image,
+                &block_transform,
+                config,
+            )
+        } else {
+            transform_plane(
+                &mut image.to_luma8().into_raw(),
+                image,
+                &block_transform,
+                config,
+            )
+        };
+    }
+
+    if config.color_domain == ColorDomain::Hsv {
+        let block_transform = BlockTransform::new(config);
+        let mut v_plane = colorspace::convert_to_HSV(image).v;
+        return transform_plane(&mut v_plane, image, &block_transform, config);
+    }
+
+    if colorspace::keeps_16_bit(image, &config.color) {
+        let planes = colorspace::convert_to_YCbCr16_with(image, &config.color);
+        transform_planes([planes.y.0, planes.cb.0, planes.cr.0], image, config)
+    } else {
+        let planes = colorspace::convert_to_YCbCr_with(image, &config.color);
+        transform_planes([planes.y.0, planes.cb.0, planes.cr.0], image, config)
+    }
+}
+
+/// Returns the whole transformed blocks of the `[y, cb, cr]` planes selected by the config, one plane after another
+fn transform_planes<S: dct::Sample>(
+    mut planes: [Vec<S>; 3],
+    image: &DynamicImage,
+    config: &EmbedConfig,
+) -> Vec<Vec<f32>> {
+    let block_transform = BlockTransform::new(config);
+    let mut blocks = Vec::new();
+    for channel in config.channels.iter() {
+        let plane = &mut planes[*channel as usize];
+        blocks.append(&mut transform_plane(plane, image, &block_transform, config));
+    }
+    blocks
+}
+
+/// Returns the whole transformed blocks of a plane of the image
+fn transform_plane<S: dct::Sample>(
+    plane: &mut [S],
+    image: &DynamicImage,
+    block_transform: &BlockTransform,
+    config: &EmbedConfig,
+) -> Vec<Vec<f32>> {
+    let (width, height) = image.dimensions();
+    let mut plane_blocks =
+        dct::split_into_blocks(plane, width as usize, height as usize, config.block_size).blocks;
+    block_transform.forward(&mut plane_blocks);
+    let carriers: Vec<usize> = full_blocks(width, height, config)
+        .into_iter()
+        .filter(|&i| carries_bits(&plane_blocks[i], config))
+        .collect();
+    carriers
+        .into_iter()
+        .map(|i| std::mem::take(&mut plane_blocks[i]))
+        .collect()
+}
+
+/// Extracts the `wm_width * wm_height` watermark starting at block `offset` and reconstructs the image
+fn reconstruct_watermark(
+    blocks: &[Vec<f32>],
+    original: Option<&[Vec<f32>]>,
+    offset: usize,
+    host_dimensions: (u32, u32),
+    config: &EmbedConfig,
+    wm: (u32, u32, WatermarkKind, u8),
+) -> Result<(BitVec, DynamicImage), WatermarkError> {
+    let extracted_wm = read_watermark(blocks, original, offset, host_dimensions, config, wm)?;
+
+    // Reconstruct the image from bits and save the recovered watermark
+    let (wm_width, wm_height, kind, bits_per_channel) = wm;
+    let reconstructed_wm_image =
+        kind.reconstruct(&extracted_wm, wm_width, wm_height, bits_per_channel);
+    Ok((extracted_wm, reconstructed_wm_image))
+}
+
+/// Extracts the bits of the `wm_width * wm_height` watermark starting at block `offset`
+fn read_watermark(
+    blocks: &[Vec<f32>],
+    original: Option<&[Vec<f32>]>,
+    offset: usize,
+    host_dimensions: (u32, u32),
+    config: &EmbedConfig,
+    wm: (u32, u32, WatermarkKind, u8),
+) -> Result<BitVec, WatermarkError> {
+    read_authenticated(blocks, original, offset, host_dimensions, config, wm).map(|(bits, _)| bits)
+}
+
+/// Same as [`read_watermark`], also returns whether the authentication tag matches, see [`decode_authenticated`]
+fn read_authenticated(
+    blocks: &[Vec<f32>],
+    original: Option<&[Vec<f32>]>,
+    offset: usize,
+    (width, height): (u32, u32),
+    config: &EmbedConfig,
+    (wm_width, wm_height, kind, bits_per_channel): (u32, u32, WatermarkKind, u8),
+) -> Result<(BitVec, bool), WatermarkError> {
+    let wm_len = wm_width as usize * wm_height as usize * kind.bits_per_pixel(bits_per_channel);
+    let stream_len = encoded_watermark_len(wm_len, config);
+    let wm_blocks =
+        payload::segment_blocks(stream_len, block_bits(config), config.redundancy.copies());
+    check_capacity(
+        width,
+        height,
+        config,
+        (offset + wm_blocks) * block_bits(config),
+    )?;
+
+    let stream = read_segment(blocks, original, offset, stream_len, config)?;
+    Ok(decode_authenticated(&stream, wm_len, config))
+}
+
+/// Appends the authentication tag if the config sets one and applies the error correcting code
+fn encode_watermark_bits(bits: &BitSlice, config: &EmbedConfig) -> BitVec {
+    if config.authenticate {
+        let mut tagged = bits.to_bitvec();
+        tagged.extend_from_bitslice(&payload::auth_tag(bits, config.key));
+        ecc_encode(&tagged, config)
+    } else {
+        ecc_encode(bits, config)
+    }
+}
+
+/// Number of embedded bits for a recoded watermark of `wm_len` bits
+fn encoded_watermark_len(wm_len: usize, config: &EmbedConfig) -> usize {
+    let tag_len = if config.authenticate {
+        payload::TAG_LEN
+    } else {
+        0
+    };
+    ecc_encoded_len(wm_len + tag_len, config)
+}
+
+/// Inverse of [`encode_watermark_bits`], returns the `wm_len` recoded watermark bits
+fn decode_watermark_bits(bits: &BitSlice, wm_len: usize, config: &EmbedConfig) -> BitVec {
+    decode_authenticated(bits, wm_len, config).0
+}
+
+/// Same as [`decode_watermark_bits`], also returns whether the tag matches the bits, false without a tag
+fn decode_authenticated(bits: &BitSlice, wm_len: usize, config: &EmbedConfig) -> (BitVec, bool) {
+    if !config.authenticate {
+        return (ecc_decode(bits, wm_len, config), false);
+    }
+    let mut decoded = ecc_decode(bits, wm_len + payload::TAG_LEN, config);
+    let tag = decoded.split_off(wm_len);
+    let authentic = tag == payload::auth_tag(&decoded, config.key);
+    (decoded, authentic)
+}
+
+/// Applies the error correcting code of the config
+#[cfg(feature = "ecc")]
+fn ecc_encode(bits: &BitSlice, config: &EmbedConfig) -> BitVec {
+    config.ecc.encode(bits)
+}
+
+#[cfg(not(feature = "ecc"))]
+fn ecc_encode(bits: &BitSlice, _config: &EmbedConfig) -> BitVec {
+    bits.to_bitvec()
+}
+
+/// Number of bits [`ecc_encode`] turns `len` bits into
+#[cfg(feature = "ecc")]
+fn ecc_encoded_len(len: usize, config: &EmbedConfig) -> usize {
+    config.ecc.encoded_len(len)
+}
+
+#[cfg(not(feature = "ecc"))]
+fn ecc_encoded_len(len: usize, _config: &EmbedConfig) -> usize {
+    len
+}
+
+/// Inverse of [`ecc_encode`], returns the `len` data bits
+#[cfg(feature = "ecc")]
+fn ecc_decode(bits: &BitSlice, len: usize, config: &EmbedConfig) -> BitVec {
+    config.ecc.decode(bits, len)
+}
+
+#[cfg(not(feature = "ecc"))]
+fn ecc_decode(bits: &BitSlice, len: usize, _config: &EmbedConfig) -> BitVec {
+    bits[..len].to_bitvec()
+}
+
+/// Number of leading blocks taken by the header and its copies
+fn header_blocks(config: &EmbedConfig) -> usize {
+    payload::segment_blocks(
+        payload::HEADER_LEN,
+        block_bits(config),
+        config.redundancy.copies(),
+    )
+}
+
+/// Extracts a segment of `len` bits starting at block `offset`, majority voting over its copies
+///
+/// Decodes non-blindly when given the blocks of the original host. With [`ChannelMode::Diversity`],
+/// `offset` is relative to each plane, whose copies of the segment are soft voted first
+///
+/// Errors if the segment runs past the blocks, which [`check_capacity`] can't rule out when
+/// [`EmbedConfig::min_block_energy`] skips some of them
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip(blocks, original, config))
+)]
+fn read_segment(
+    blocks: &[Vec<f32>],
+    original: Option<&[Vec<f32>]>,
+    offset: usize,
+    len: usize,
+    config: &EmbedConfig,
+) -> Result<BitVec, WatermarkError> {
+    let copies = config.redundancy.copies();
+    let num_blocks = payload::segment_blocks(len, block_bits(config), copies);
+    // The stream runs over every plane in capacity mode, each plane carries all of it in diversity mode
+    let planes = config.channels.len() / stream_planes(config);
+    let plane_blocks = blocks.len() / planes;
+    let available = original.map_or(plane_blocks, |original| {
+        plane_blocks.min(original.len() / planes)
+    });
+    if offset + num_blocks > available {
+        return Err(WatermarkError::Capacity {
+            required: (offset + num_blocks) * block_bits(config),
+            available: available * block_bits(config),
+        });
+    }
+    let bits = match config.channel_mode {
+        ChannelMode::Capacity => {
+            let range = offset..offset + num_blocks;
+            let original = original.map(|original| &original[range.clone()]);
+            extract_bits(&blocks[range], original, offset, config)
+        }
+        ChannelMode::Diversity => {
+            let mut votes = vec![0.0; num_blocks * block_bits(config)];
+            for plane in 0..config.channels.len() {
+                let first_block = plane * plane_blocks + offset;
+                let range = first_block..first_block + num_blocks;
+                // Non-blind decisions have no confidence, every plane gets an equal vote
+                let soft: Vec<f32> = match original {
+                    None => extract_soft(&blocks[range], first_block, config),
+                    Some(original) => extract_bits(
+                        &blocks[range.clone()],
+                        Some(&original[range]),
+                        first_block,
+                        config,
+                    )
+                    .iter()
+                    .map(|bit| if *bit { 1.0 } else { -1.0 })
+                    .collect(),
+                };
+                // Random coefficients average a confidence of 0.5, planes without the watermark get next to no weight
+                let confidence = soft.iter().map(|v| v.abs()).sum::<f32>() / soft.len() as f32;
+                let weight = (2.0 * confidence - 1.0).max(f32::EPSILON);
+                for (vote, value) in votes.iter_mut().zip(soft) {
+                    *vote += weight * value;
+                }
+            }
+            votes.into_iter().map(|vote| vote > 0.0).collect()
+        }
+    };
+    Ok(payload::majority_vote(
+        &bits,
+        len,
+        block_bits(config),
+        copies,
+    ))
+}
+
+/// Step size and dithers of the DCT block at `index` in the bit stream
+///
+/// The dithers are derived for the block when the config has per-block dithers, and scaled by the block
+/// energy when it has an adaptive step
+fn block_quantizer(
+    block: &[f32],
+    index: usize,
+    dithers: &[f32],
+    mask: &qim::CoefficientMask,
+    config: &EmbedConfig,
+) -> (f32, Vec<f32>) {
+    let block_dithers;
+    let dithers = if config.per_block_dither {
+        block_dithers = qim::generate_block_dither_lattices(
+            config.mask.len(),
+            config.step_size,
+            config.levels,
+            config.key,
+            index as u64,
+        );
+        &block_dithers
+    } else {
+        dithers
+    };
+    match &config.adaptive_step {
+        None => (config.step_size, dithers.to_vec()),
+        Some(adaptive) => {
+            let step_size = qim::adaptive_step_size(block, config.step_size, mask, adaptive);
+            let scale = step_size / config.step_size;
+            let dithers = dithers.iter().map(|d| d * scale).collect();
+            (step_size, dithers)
+        }
+    }
+}
+
+/// Extracts the bits carried by each of the given DCT blocks, the first one at `first_block` in the bit stream
+///
+/// Decodes non-blindly when given the blocks of the original host, which also set the adaptive steps
+fn extract_bits(
+    blocks: &[Vec<f32>],
+    original: Option<&[Vec<f32>]>,
+    first_block: usize,
+    config: &EmbedConfig,
+) -> BitVec {
+    let dithers = qim::generate_dither_lattices(
+        config.mask.len(),
+        config.step_size,
+        config.levels,
+        config.key,
+    );
+    let mask = config.mask.scaled(config.block_size);
+    let mut extracted_wm: BitVec<usize, Lsb0> =
+        BitVec::repeat(false, blocks.len() * block_bits(config));
+    for (n, block) in blocks.iter().enumerate() {
+        let start = n * block_bits(config);
+        match original {
+            None => {
+                let (step_size, dithers) =
+                    block_quantizer(block, first_block + n, &dithers, &mask, config);
+                match config.acceptable_range {
+                    Some(acceptable_range) => {
+                        // The 2 lattices of each coefficient are the pair of dithers of binary QIM
+                        let dithers: Vec<(f32, f32)> =
+                            dithers.chunks(2).map(|d| (d[0], d[1])).collect();
+                        let bits = qim::extract_wm_with_range(
+                            block,
+                            &dithers,
+                            step_size,
+                            acceptable_range,
+                            &mask,
+                        );
+                        extracted_wm[start..start + bits.len()].copy_from_bitslice(&bits);
+                    }
+                    // Distortion compensation leaves the coefficients off their lattice, each symbol comes from the closest one
+                    None => qim::extract_wm_mary_at(
+                        block,
+                        &dithers,
+                        config.levels,
+                        step_size,
+                        &mask,
+                        &mut extracted_wm,
+                        start,
+                    ),
+                }
+            }
+            Some(original) => {
+                let (step_size, dithers) =
+                    block_quantizer(&original[n], first_block + n, &dithers, &mask, config);
+                let bits = qim::extract_wm_nonblind(
+                    block,
+                    &original[n],
+                    &dithers,
+                    config.levels,
+                    step_size,
+                    config.alpha,
+                    &mask,
+                );
+                extracted_wm[start..start + bits.len()].copy_from_bitslice(&bits);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            block = first_block + n,
+            bits = block_bits(config),
+            ones = extracted_wm[start..start + block_bits(config)].count_ones(),
+            "extracted block"
+        );
+    }
+    extracted_wm
+}
+
+/// Soft values of the bits carried by each of the given DCT blocks, the first one at `first_block` in the bit stream
+///
+/// See [`qim::extract_wm_mary_soft`]
+fn extract_soft(blocks: &[Vec<f32>], first_block: usize, config: &EmbedConfig) -> Vec<f32> {
+    let dithers = qim::generate_dither_lattices(
+        config.mask.len(),
+        config.step_size,
+        config.levels,
+        config.key,
+    );
+    let mask = config.mask.scaled(config.block_size);
+    let mut soft = Vec::with_capacity(blocks.len() * block_bits(config));
+    for (n, block) in blocks.iter().enumerate() {
+        let (step_size, dithers) = block_quantizer(block, first_block + n, &dithers, &mask, config);
+        let values = qim::extract_wm_mary_soft(block, &dithers, config.levels, step_size, &mask);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            block = first_block + n,
+            bits = values.len(),
+            ones = values.iter().filter(|&&v| v > 0.0).count(),
+            "extracted soft block"
+        );
+        soft.extend(values);
+    }
+    soft
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    const OUTPUT_DIR: &str = "/tmp/color_watermark/output";
+
+    /// Stand-in for a 512 * 512 photograph
+    fn synthetic_host(seed: u64) -> DynamicImage {
+        testing::generate_host(512, 512, seed)
+    }
+
+    /// Stand-in for a 128 * 128 logo, filling a 512 * 512 host with the default config
+    fn synthetic_watermark(seed: u64) -> DynamicImage {
+        testing::generate_watermark(128, 128, seed)
+    }
+
+    /// Path of `name` in the output directory, created if needed
+    fn output_path(name: &str) -> String {
+        std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+        format!("{}/{}", OUTPUT_DIR, name)
+    }
+
+    #[test]
+    fn test_3bit_recodification() {
+        let wm = synthetic_watermark(1);
+        let (width, height) = wm.dimensions();
+
+        color_recode::recode_to_rgb(&color_recode::recode_to_3bits(&wm), width, height)
+            .save(output_path("wm_img1_recoded.png"))
+            .unwrap();
+
+        let wm = synthetic_watermark(2);
+        let (w, h) = wm.dimensions();
+        color_recode::recode_to_rgb(&color_recode::recode_to_3bits(&wm), w, h)
+            .save(output_path("wm_img2_recoded.png"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_3bit_recodification_rgba() {
+        let rgba = image::RgbaImage::from_fn(32, 16, |x, y| {
+            image::Rgba([
+                (x * 8) as u8,
+                (y * 16) as u8,
+                ((x + y) * 5) as u8,
+                (x * 3 + y) as u8,
+            ])
+        });
+        let wm = DynamicImage::ImageRgba8(rgba);
+
+        let bits = color_recode::recode_to_3bits(&wm);
+        assert_eq!(bits.len(), 32 * 16 * 3);
+
+        let reconstructed = color_recode::recode_to_rgb(&bits, 32, 16).to_rgb8();
+        let expected = wm.to_rgb8();
+        for (r, e) in reconstructed.pixels().zip(expected.pixels()) {
+            for c in 0..3 {
+                assert_eq!(r[c], if e[c] > 127 { 255 } else { 0 });
+            }
+        }
+    }
+
+    #[test]
+    fn test_2d_dct() {
+        let image = synthetic_host(1);
+        let (width, height) = image.dimensions();
+
+        let (mut blocks_r, mut blocks_g, mut blocks_b) = dct::split_image_into_blocks(&image, 8);
+
+        dct::apply_2d_dct(&mut blocks_r, 8);
+        dct::apply_2d_dct(&mut blocks_g, 8);
+        dct::apply_2d_dct(&mut blocks_b, 8);
+
+        let transformed_image =
+            dct::reconstruct_image_from_rgb(&blocks_r, &blocks_g, &blocks_b, width, height, 8);
+        transformed_image
+            .save(output_path("pepper_2d_dct.png"))
+            .expect("Failed to save image");
+
+        dct::apply_2d_idct(&mut blocks_r, 8);
+        dct::apply_2d_idct(&mut blocks_g, 8);
+        dct::apply_2d_idct(&mut blocks_b, 8);
+
+        let unchanged_image =
+            dct::reconstruct_image_from_rgb(&blocks_r, &blocks_g, &blocks_b, width, height, 8);
+
+        unchanged_image
+            .save(output_path("pepper_unchanged_dct.png"))
+            .expect("Failed to save unchanged_image");
+    }
+
+    #[test]
+    fn test_single_block_dct() {
+        // A DC-only block is a constant plane
+        let mut block = [0_f32; 64];
+        block[0] = 64.0 * 100.0;
+        dct::idct_block(&mut block);
+        assert!(
+            block.iter().all(|v| (v - 100.0).abs() < 1e-3),
+            "{:?}",
+            block
+        );
+
+        dct::dct_block(&mut block);
+        assert!((block[0] - 6400.0).abs() < 1e-2);
+        assert!(block[1..].iter().all(|v| v.abs() < 1e-2), "{:?}", block);
+
+        // Same as the bulk transform
+        let mut plane: Vec<u8> = (0..64).map(|i| (i * 37 % 256) as u8).collect();
+        let mut blocks = dct::split_into_blocks(&mut plane, 8, 8, 8).blocks;
+        let mut block: [f32; 64] = blocks[0].clone().try_into().unwrap();
+        dct::apply_2d_dct(&mut blocks, 8);
+        dct::dct_block(&mut block);
+        assert_eq!(&block[..], &blocks[0][..]);
+    }
+
+    #[test]
+    fn test_f64_precision() {
+        let mut plane: Vec<u8> = (0..64).map(|i| (i * 37 % 256) as u8).collect();
+        let mut blocks_f32: Vec<Vec<f32>> = dct::split_into_blocks(&mut plane, 8, 8, 8).blocks;
+        let mut grid_f64: dct::BlockGrid<f64> = dct::split_into_blocks(&mut plane, 8, 8, 8);
+        let blocks_f64 = &mut grid_f64.blocks;
+
+        dct::BlockDct::new(8).forward(&mut blocks_f32);
+        dct::BlockDct::new(8).inverse(&mut blocks_f32);
+        dct::BlockDct::new(8).forward(blocks_f64);
+        dct::BlockDct::new(8).inverse(blocks_f64);
+
+        let residual_f32 = plane
+            .iter()
+            .zip(&blocks_f32[0])
+            .map(|(&p, &v)| (p as f64 - v as f64).abs())
+            .fold(0.0, f64::max);
+        let residual_f64 = plane
+            .iter()
+            .zip(&blocks_f64[0])
+            .map(|(&p, &v)| (p as f64 - v).abs())
+            .fold(0.0, f64::max);
+        assert!(residual_f64 < 1e-9, "f64 residual {}", residual_f64);
+        assert!(
+            residual_f64 < residual_f32,
+            "{} >= {}",
+            residual_f64,
+            residual_f32
+        );
+        assert_eq!(dct::merge_into_plane::<u8, _>(&grid_f64), plane);
+    }
+
+    #[test]
+    fn test_reused_block_dct() {
+        let image = synthetic_host(1);
+        let (blocks, _, _) = dct::split_image_into_blocks(&image, 8);
+
+        let mut planned_per_call = blocks.clone();
+        dct::apply_2d_dct(&mut planned_per_call, 8);
+
+        let block_dct = dct::BlockDct::new(8);
+        let mut reused = blocks.clone();
+        block_dct.forward(&mut reused);
+        assert_eq!(planned_per_call, reused);
+
+        block_dct.inverse(&mut reused);
+        for (a, b) in reused.iter().flatten().zip(blocks.iter().flatten()) {
+            assert!((a - b).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_block_sizes() {
+        let host = synthetic_host(1);
+        let (width, height) = host.dimensions();
+        let mut y_plane = colorspace::convert_to_YCbCr(&host).y;
+
+        for block_size in [8, 16] {
+            let mut grid =
+                dct::split_into_blocks(&mut y_plane, width as usize, height as usize, block_size);
+            assert!(grid
+                .blocks
+                .iter()
+                .all(|b| b.len() == block_size * block_size));
+
+            let block_dct: dct::BlockDct = dct::BlockDct::new(block_size);
+            block_dct.forward(&mut grid.blocks);
+            block_dct.inverse(&mut grid.blocks);
+            let plane: Vec<u8> = dct::merge_into_plane(&grid);
+            assert_eq!(plane, y_plane.0);
+        }
+
+        // Scaling keeps the spatial frequency: (0, 4) on 8 * 8 is (0, 8) on 16 * 16
+        let scaled = qim::CoefficientMask::default().scaled(16);
+        assert_eq!(&scaled.indices()[..2], &[8, 10]);
+        assert_eq!(scaled.indices()[4], 16 * 2 + 6);
+
+        // A 16 * 16 block carries as many bits as an 8 * 8 one, so a quarter of the capacity,
+        // and its larger coefficients need a larger step size
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let config = EmbedConfig::new(2143658709).block_size(16).step_size(200.0);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let (extracted_bits, _) = extract_with(&watermarked, &config, 64, 64).unwrap();
+        let ber =
+            metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &extracted_bits).unwrap();
+        assert!(ber < 0.02);
+        assert!(matches!(
+            embed_with(
+                &host,
+                &wm.resize_exact(65, 64, image::imageops::FilterType::Nearest),
+                &config
+            ),
+            Err(WatermarkError::Capacity { .. })
+        ));
+
+        for block_size in [4, 12] {
+            let config = EmbedConfig::new(2143658709).block_size(block_size);
+            assert!(matches!(
+                embed_with(&host, &wm, &config),
+                Err(WatermarkError::BlockSize(size)) if size == block_size
+            ));
+        }
+    }
+
+    #[test]
+    fn test_edge_padding() {
+        let host = synthetic_host(1);
+        let mut y_plane = colorspace::convert_to_YCbCr(&host.crop_imm(0, 0, 510, 506)).y;
+
+        let mut grid = dct::split_into_blocks(&mut y_plane, 510, 506, 8);
+        assert_eq!(grid.blocks.len(), 64 * 64);
+        let block_dct: dct::BlockDct = dct::BlockDct::new(8);
+        block_dct.forward(&mut grid.blocks);
+        block_dct.inverse(&mut grid.blocks);
+        assert_eq!(dct::merge_into_plane::<u8, _>(&grid), y_plane.0);
+
+        // Only the whole blocks of a 500 * 375 host carry the watermark
+        let host = host.crop_imm(0, 0, 500, 375);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let wmkd_image = embed_watermark_image(&host, &wm, 42, 50.0).unwrap();
+        assert_eq!(wmkd_image.dimensions(), (500, 375));
+        assert!(metrics::psnr(&host, &wmkd_image) > 35.0);
+
+        let (bits, _) =
+            extract_with(&wmkd_image, &EmbedConfig::new(42).step_size(50.0), 64, 64).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert!(ber < 0.02, "BER {}", ber);
+    }
+
+    #[test]
+    fn test_block_grid() {
+        let mut plane: Vec<u16> = (0..37 * 21_u32)
+            .map(|i| (i.wrapping_mul(2654435761_u32) >> 16) as u16)
+            .collect();
+        let grid: dct::BlockGrid = dct::split_into_blocks(&mut plane, 37, 21, 16);
+        assert_eq!((grid.width, grid.height, grid.block_size), (37, 21, 16));
+        assert_eq!((grid.blocks_per_row(), grid.blocks_per_column()), (3, 2));
+        assert_eq!(grid.blocks.len(), 6);
+        assert_eq!(dct::merge_into_plane::<u16, _>(&grid), plane);
+    }
+
+    #[test]
+    #[should_panic(expected = "needs 3 * 3 blocks")]
+    fn test_block_grid_mismatch() {
+        let mut plane = vec![0_u8; 37 * 21];
+        let mut grid: dct::BlockGrid = dct::split_into_blocks(&mut plane, 37, 21, 16);
+        grid.height = 40;
+        dct::merge_into_plane::<u8, _>(&grid);
+    }
+
+    #[test]
+    fn test_merge_into_plane_clamps() {
+        let mut block = vec![128.0_f32; 64];
+        block[0] = -3.2;
+        block[1] = 259.8;
+        block[2] = 12.6;
+        block[3] = 254.4;
+
+        let grid = dct::BlockGrid {
+            blocks: vec![block],
+            width: 8,
+            height: 8,
+            block_size: 8,
+        };
+        let plane: Vec<u8> = dct::merge_into_plane(&grid);
+        assert_eq!(plane[0], 0);
+        assert_eq!(plane[1], 255);
+        assert_eq!(plane[2], 13);
+        assert_eq!(plane[3], 254);
+        assert!(plane[4..].iter().all(|&p| p == 128));
+    }
+
+    #[test]
+    fn test_blocks_to_plane_clamped() {
+        // 12 * 10 plane of 4 flat blocks at 0, 100, 200 and 300, cropped to the plane
+        let blocks: Vec<Vec<f32>> = (0..4)
+            .map(|i| {
+                let mut block = [i as f32 * 100.0; 64];
+                dct::dct_block(&mut block);
+                block.to_vec()
+            })
+            .collect();
+        let mut out_of_range = blocks.clone();
+        // Far below 0 in the first block
+        out_of_range[0][0] = -blocks[3][0];
+
+        let plane = dct::blocks_to_plane_clamped(&out_of_range, 12, 10);
+        assert_eq!(plane.len(), 12 * 10);
+        assert!(plane[..8].iter().all(|&p| p == 0));
+        assert_eq!(plane[8], 100);
+        assert_eq!(plane[8 * 12], 200);
+        assert_eq!(plane[12 * 10 - 1], 255);
+    }
+
+    #[test]
+    #[should_panic(expected = "needs 4 blocks")]
+    fn test_blocks_to_plane_clamped_count() {
+        dct::blocks_to_plane_clamped(&[vec![0.0; 64]], 12, 10);
+    }
+
+    #[test]
+    fn test_rgb_toforth_ycrcb() {
+        let image = synthetic_host(1);
+
+        let planes = colorspace::convert_to_YCbCr(&image);
+        assert_eq!((planes.width, planes.height), image.dimensions());
+
+        let rgb_img = colorspace::convert_to_RGB(&planes);
+
+        rgb_img
+            .save(output_path("pepper_unchanged_color.png"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_16_bit_host() {
+        let host = synthetic_host(1);
+        // Fill in the low byte, which an 8-bit host doesn't have
+        let mut host16 = host.to_rgb16();
+        for (x, y, pixel) in host16.enumerate_pixels_mut() {
+            for sample in pixel.0.iter_mut() {
+                *sample = sample.saturating_add(((x * 31 + y * 17) % 257) as u16);
+            }
+        }
+        let host16 = DynamicImage::ImageRgb16(host16);
+        assert!(colorspace::is_16_bit(&host16));
+
+        let squared_error = |image: &DynamicImage| -> f64 {
+            host16
+                .to_rgb16()
+                .as_raw()
+                .iter()
+                .zip(image.to_rgb16().as_raw())
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum()
+        };
+        let color = colorspace::ColorConfig::default();
+        let round_trip16 = colorspace::convert_to_RGB16_with(
+            &colorspace::convert_to_YCbCr16_with(&host16, &color),
+            &color,
+        );
+        let round_trip8 = colorspace::convert_to_RGB(&colorspace::convert_to_YCbCr(&host16));
+        assert!(squared_error(&round_trip16) * 100.0 < squared_error(&round_trip8));
+
+        // The watermarked image stays 16-bit, alpha included
+        let wm = synthetic_watermark(1);
+        let config = EmbedConfig::new(16).step_size(50.0);
+        let wmkd_image = embed_with(&host16, &wm, &config).unwrap();
+        assert_eq!(wmkd_image.color(), image::ColorType::Rgb16);
+        assert!(metrics::psnr(&host16, &wmkd_image) > 35.0);
+        let (bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert!(ber < 0.02, "BER {}", ber);
+
+        let rgba16 = DynamicImage::ImageRgba16(host16.to_rgba16());
+        let wmkd_image = embed_with(&rgba16, &wm, &config).unwrap();
+        assert_eq!(wmkd_image.color(), image::ColorType::Rgba16);
+    }
+
+    #[test]
+    fn test_max_watermark_dims() {
+        assert_eq!(max_watermark_dims(512, 512, 8, 12), (128, 128));
+        assert_eq!(max_watermark_dims(512, 512, 16, 12), (64, 64));
+        assert_eq!(max_watermark_dims(7, 512, 8, 12), (0, 0));
+
+        // The reported watermark fits a 500 * 300 host, one more pixel each side doesn't
+        let host = DynamicImage::new_rgb8(500, 300);
+        let (width, height) = max_watermark_dims(500, 300, 8, 12);
+        let wm = DynamicImage::new_rgb8(width, height);
+        let config = EmbedConfig::new(54);
+        assert!(embed_with(&host, &wm, &config).is_ok());
+        let wm = DynamicImage::new_rgb8(width + 1, height + 1);
+        assert!(matches!(
+            embed_with(&host, &wm, &config),
+            Err(WatermarkError::Capacity { .. })
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let config = EmbedConfig::new(55)
+            .step_size(80.0)
+            .channels(&[Channel::Y, Channel::Cr])
+            .mask(qim::CoefficientMask::preset(qim::Preset::LowFrequency))
+            .adaptive_step(qim::AdaptiveStep::default())
+            .redundancy(Redundancy::Repeat(3));
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<EmbedConfig>(&json).unwrap(), config);
+
+        let host = synthetic_host(1);
+        // Y and Cr at 3 repeats carry a 64 * 64 watermark
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let (_, report) = embed_with_report(&host, &wm, &config).unwrap();
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<EmbedReport>(&json).unwrap(), report);
+
+        // Masks are validated like the ones built in code
+        assert!(serde_json::from_str::<qim::CoefficientMask>("[1, 2, 2]").is_err());
+        // And so are the channels
+        for channels in ["[]", r#"["Cr", "Y"]"#, r#"["Y", "Y"]"#] {
+            let json = serde_json::to_string(&config)
+                .unwrap()
+                .replace(r#"["Y","Cr"]"#, channels);
+            assert!(
+                serde_json::from_str::<EmbedConfig>(&json).is_err(),
+                "{}",
+                channels
+            );
+        }
+    }
+
+    #[test]
+    fn test_nonblind_extraction() {
+        use rand::{Rng, SeedableRng};
+
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let expected = color_recode::recode_to_3bits(&wm);
+        let config = EmbedConfig::new(56).step_size(100.0);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (bits, _) = extract_nonblind_with(&wmkd_image, &host, &config, 128, 128).unwrap();
+        assert_eq!(metrics::bit_error_rate(&expected, &bits).unwrap(), 0.0);
+
+        // Uniform noise of up to 3 levels on every sample, non-blind decoding about halves the errors
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(56);
+        let mut noisy = wmkd_image.to_rgb8();
+        for sample in noisy.iter_mut() {
+            *sample = (*sample as i32 + rng.random_range(-3..=3)).clamp(0, 255) as u8;
+        }
+        let noisy = DynamicImage::ImageRgb8(noisy);
+        let (blind, _) = extract_with(&noisy, &config, 128, 128).unwrap();
+        let (nonblind, _) = extract_nonblind_with(&noisy, &host, &config, 128, 128).unwrap();
+        let blind_ber = metrics::bit_error_rate(&expected, &blind).unwrap();
+        let nonblind_ber = metrics::bit_error_rate(&expected, &nonblind).unwrap();
+        println!("BER blind {} non-blind {}", blind_ber, nonblind_ber);
+        assert!(nonblind_ber < blind_ber * 0.7);
+
+        let cropped = host.crop_imm(0, 0, 256, 256);
+        assert!(matches!(
+            extract_watermark_nonblind(&wmkd_image, &cropped, 56, 100.0, 128, 128),
+            Err(WatermarkError::OriginalDimensions { .. })
+        ));
+    }
+
+    #[test]
+    fn test_hadamard_transform() {
+        // Integer samples round trip exactly
+        let original: Vec<f32> = (0..64).map(|i| ((i * 37) % 256) as f32).collect();
+        let mut blocks = vec![original.clone()];
+        wht::apply_2d_wht(&mut blocks, 8);
+        assert_eq!(blocks[0][0], original.iter().sum::<f32>() / 2.0);
+        wht::apply_2d_iwht(&mut blocks, 8);
+        assert_eq!(blocks[0], original);
+
+        // Coefficients are in sequency order: the basis function of row-major (0, k) changes sign k times
+        let block_wht = wht::BlockWht::new(8);
+        for k in 0..8 {
+            let mut basis = vec![0.0f32; 64];
+            basis[k] = 1.0;
+            block_wht.inverse_block(&mut basis);
+            let sign_changes = basis[..8].windows(2).filter(|w| w[0] * w[1] < 0.0).count();
+            assert_eq!(sign_changes, k);
+        }
+
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let expected = color_recode::recode_to_3bits(&wm);
+        // The estimate leaves out the rounding to 8-bit samples, about 1.2 squared levels over the RGB planes,
+        // half the error of the embedding at the default step, so the step is high enough for the embedding to dominate
+        let config = EmbedConfig::new(57)
+            .step_size(100.0)
+            .transform(Transform::Hadamard);
+        let (wmkd_image, report) = embed_with_report(&host, &wm, &config).unwrap();
+        let psnr = metrics::psnr(&host, &wmkd_image);
+        assert!(psnr > 35.0, "PSNR {}", psnr);
+        assert!(
+            (report.estimated_psnr - psnr).abs() < 1.0,
+            "estimated {} PSNR {}",
+            report.estimated_psnr,
+            psnr
+        );
+        let (bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&expected, &bits).unwrap();
+        assert!(ber < 0.01, "BER {}", ber);
+    }
+
+    #[test]
+    fn test_multilevel_transform() {
+        // Both levels round trip
+        let original: Vec<f64> = (0..64 * 64).map(|i| ((i * 37) % 256) as f64).collect();
+        let multilevel_dct = multilevel::MultiLevelDct::<f64>::new(64);
+        let mut block = original.clone();
+        multilevel_dct.forward_block(&mut block);
+        multilevel_dct.inverse_block(&mut block);
+        assert!(block
+            .iter()
+            .zip(&original)
+            .all(|(a, b)| (a - b).abs() < 1e-9));
+
+        // The scaled mask selects the DCT of the orthonormal DC coefficients of the 8 * 8 sub-blocks
+        let mut subs: Vec<Vec<f64>> = (0..64)
+            .map(|k| {
+                let (y0, x0) = (k / 8 * 8, k % 8 * 8);
+                (0..64)
+                    .map(|i| original[(y0 + i / 8) * 64 + x0 + i % 8])
+                    .collect()
+            })
+            .collect();
+        dct::BlockDct::new(8).forward(&mut subs);
+        let mut dc: Vec<f64> = subs.iter().map(|sub| sub[0] / 8.0).collect();
+        dct::BlockDct::new(8).forward_block(&mut dc);
+        multilevel_dct.forward_block(&mut block);
+        let mask = qim::CoefficientMask::default();
+        for (&i, &j) in mask.scaled(64).indices().iter().zip(mask.indices()) {
+            assert!((block[i] - dc[j]).abs() < 1e-6);
+        }
+
+        // The two-level embed extracts losslessly, coarse coefficients take larger steps
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(16, 16, image::imageops::FilterType::Nearest);
+        let expected = color_recode::recode_to_3bits(&wm);
+        let config = EmbedConfig::new(61)
+            .step_size(800.0)
+            .transform(Transform::MultiLevel)
+            .block_size(64);
+        let (wmkd_image, report) = embed_with_report(&host, &wm, &config).unwrap();
+        let psnr = metrics::psnr(&host, &wmkd_image);
+        assert!(psnr > 35.0, "PSNR {}", psnr);
+        assert!((report.estimated_psnr - psnr).abs() < 1.0);
+        let (bits, _) = extract_with(&wmkd_image, &config, 16, 16).unwrap();
+        assert_eq!(metrics::bit_error_rate(&expected, &bits).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_embed_and_verify() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let (wmkd_image, ber) = embed_and_verify(&host, &wm, 59, 100.0).unwrap();
+        assert_eq!(ber, 0.0);
+        assert_eq!(
+            wmkd_image,
+            embed_watermark_image(&host, &wm, 59, 100.0).unwrap()
+        );
+
+        // Rounding to 8-bit samples flips bits of tiny steps, raise the step until the embed verifies
+        let (_, ber) = embed_and_verify(&host, &wm, 59, 1.0).unwrap();
+        assert!(ber > 0.0);
+        let mut step_size = 1.0;
+        while embed_and_verify(&host, &wm, 59, step_size).unwrap().1 > 0.0 {
+            step_size *= 2.0;
+        }
+        assert!(step_size <= 64.0, "step size {}", step_size);
+
+        // Grayscale watermarks are detected at embedding and verified as such
+        let gray_wm = DynamicImage::ImageLuma8(wm.to_luma8());
+        let (_, ber) = embed_and_verify(&host, &gray_wm, 59, 100.0).unwrap();
+        assert_eq!(ber, 0.0);
+    }
+
+    #[test]
+    fn test_auto_step_size() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let (step_size, wmkd_image) = auto_step_size(&host, &wm, 60, 40.0).unwrap();
+        let psnr = metrics::psnr(&host, &wmkd_image);
+        assert!((40.0..40.5).contains(&psnr), "PSNR {}", psnr);
+        assert_eq!(
+            wmkd_image,
+            embed_watermark_image(&host, &wm, 60, step_size).unwrap()
+        );
+
+        // Rounding the YCbCr round trip alone keeps the PSNR below 60 dB
+        assert!(matches!(
+            auto_step_size(&host, &wm, 60, 80.0),
+            Err(WatermarkError::TargetPsnr(_))
+        ));
+    }
+
+    #[test]
+    fn test_locate_tamper() {
+        let host = synthetic_host(1);
+        let step_size = config::FRAGILE_STEP;
+        let wmkd_image = embed_fragile(&host, 61, step_size).unwrap();
+        assert!(metrics::psnr(&host, &wmkd_image) > 45.0);
+        let heatmap = locate_tamper(&wmkd_image, 61, step_size).unwrap();
+        assert_eq!(heatmap.dimensions(), (64, 64));
+        assert!(heatmap.pixels().all(|p| p.0[0] == 0));
+
+        // Paint blocks 10..20 * 30..36
+        let mut tampered = wmkd_image.to_rgb8();
+        for y in 30 * 8..36 * 8 {
+            for x in 10 * 8..20 * 8 {
+                tampered.put_pixel(x, y, image::Rgb([200, 40, 40]));
+            }
+        }
+        let heatmap = locate_tamper(&DynamicImage::ImageRgb8(tampered), 61, step_size).unwrap();
+        for (x, y, pixel) in heatmap.enumerate_pixels() {
+            let painted = (10..20).contains(&x) && (30..36).contains(&y);
+            if painted {
+                assert!(pixel.0[0] > 0, "block ({}, {}) missed", x, y);
+            } else {
+                assert_eq!(pixel.0[0], 0, "block ({}, {}) flagged", x, y);
+            }
+        }
+
+        // Another key sees tampering everywhere
+        let heatmap = locate_tamper(&wmkd_image, 62, step_size).unwrap();
+        let mean = heatmap.pixels().map(|p| p.0[0] as f64).sum::<f64>() / (64.0 * 64.0);
+        assert!((100.0..155.0).contains(&mean), "mean {}", mean);
+    }
+
+    #[test]
+    fn test_yuv420() {
+        let host = synthetic_host(1);
+        let color = colorspace::ColorConfig {
+            subsampling: colorspace::ChromaSubsampling::Yuv420,
+            ..colorspace::ColorConfig::default()
+        };
+
+        // Chroma planes hold one sample per 2 * 2 pixels, odd dimensions rounded up
+        let odd = host.crop_imm(0, 0, 101, 75);
+        let planes = colorspace::convert_to_YCbCr_with(&odd, &color);
+        assert_eq!(planes.y.len(), 101 * 75);
+        assert_eq!((planes.cb.len(), planes.cr.len()), (51 * 38, 51 * 38));
+        let round_trip = colorspace::convert_to_RGB_with(&planes, &color);
+        assert_eq!(round_trip.dimensions(), (101, 75));
+        assert!(metrics::psnr(&odd, &round_trip) > 25.0);
+
+        let wm = synthetic_watermark(1);
+        let config = EmbedConfig::new(62).color(color);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert!(ber < 0.01, "BER {}", ber);
+
+        assert!(matches!(
+            embed_with(&host, &wm, &config.clone().channel(Channel::Cb)),
+            Err(WatermarkError::SubsampledChroma)
+        ));
+        assert!(matches!(
+            extract_with(&wmkd_image, &config.channel(Channel::Cr), 128, 128),
+            Err(WatermarkError::SubsampledChroma)
+        ));
+    }
+
+    #[test]
+    fn test_extract_bits_only() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wmkd_image = embed_watermark_image(&host, &wm, 63, 50.0).unwrap();
+        let bits = extract_watermark_bits(&wmkd_image, 63, 50.0, 128, 128).unwrap();
+        let (image_bits, _) = extract_watermark_image(&wmkd_image, 63, 50.0, 128, 128).unwrap();
+        assert_eq!(bits, image_bits);
+        assert_eq!(bits.len(), 128 * 128 * 3);
+
+        // Grayscale watermarks too
+        let config = EmbedConfig::new(63).watermark_kind(WatermarkKind::Gray);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let bits = extract_bits_with(&wmkd_image, &config, 128, 128).unwrap();
+        assert_eq!(
+            bits,
+            extract_with(&wmkd_image, &config, 128, 128).unwrap().0
+        );
+    }
+
+    #[cfg(feature = "wasm")]
+    #[test]
+    fn test_wasm_bindings() {
+        let host = encode_to_bytes(&synthetic_host(1), ImageFormat::Tiff).unwrap();
+        let wm = encode_to_bytes(&synthetic_watermark(1), ImageFormat::Png).unwrap();
+        let watermarked = wasm::embed(&host, &wm, 64, 50.0).unwrap();
+        assert_eq!(image::guess_format(&watermarked).unwrap(), ImageFormat::Png);
+        let extracted = wasm::extract(&watermarked, 64, 50.0, 128, 128).unwrap();
+        let extracted = image::load_from_memory(&extracted).unwrap();
+        let wm = image::load_from_memory(&wm).unwrap();
+        let ber = metrics::bit_error_rate(
+            &color_recode::recode_to_3bits(&wm),
+            &color_recode::recode_to_3bits(&extracted),
+        )
+        .unwrap();
+        assert!(ber < 0.01, "BER {}", ber);
+    }
+
+    /// Run with `wasm-pack test --headless --firefox --features wasm`
+    #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+    #[wasm_bindgen_test::wasm_bindgen_test]
+    fn test_wasm_loads() {
+        let encode = |image: DynamicImage| encode_to_bytes(&image, ImageFormat::Png).unwrap();
+        let host = encode(DynamicImage::new_rgb8(64, 64));
+        let wm = encode(DynamicImage::new_rgb8(8, 8));
+        let watermarked = wasm::embed(&host, &wm, 64, 50.0).unwrap();
+        let extracted = wasm::extract(&watermarked, 64, 50.0, 8, 8).unwrap();
+        assert_eq!(
+            image::load_from_memory(&extracted).unwrap().dimensions(),
+            (8, 8)
+        );
+    }
+
+    #[test]
+    fn test_invalid_step_size() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        for step_size in [0.0, -50.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                embed_watermark_image(&host, &wm, 65, step_size),
+                Err(WatermarkError::InvalidStepSize(_))
+            ));
+            assert!(matches!(
+                extract_watermark_image(&host, 65, step_size, 128, 128),
+                Err(WatermarkError::InvalidStepSize(_))
+            ));
+            assert!(matches!(
+                embed_text(&host, "text", 65, step_size),
+                Err(WatermarkError::InvalidStepSize(_))
+            ));
+            assert!(matches!(
+                locate_tamper(&host, 65, step_size),
+                Err(WatermarkError::InvalidStepSize(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_preserve_dc() {
+        // A grayscale host measures the block means without the colorspace round trip
+        let host = DynamicImage::ImageLuma8(synthetic_host(1).to_luma8());
+        let wm = synthetic_watermark(1);
+        let mean_shift_variance = |watermarked: &DynamicImage| {
+            let (host, watermarked) = (host.to_luma8(), watermarked.to_luma8());
+            let shifts: Vec<f64> = (0..64 * 64)
+                .map(|block| {
+                    let (bx, by) = (block % 64 * 8, block / 64 * 8);
+                    let mut shift = 0.0;
+                    for y in by..by + 8 {
+                        for x in bx..bx + 8 {
+                            shift += watermarked.get_pixel(x, y).0[0] as f64
+                                - host.get_pixel(x, y).0[0] as f64;
+                        }
+                    }
+                    shift / 64.0
+                })
+                .collect();
+            shifts.iter().map(|s| s * s).sum::<f64>() / shifts.len() as f64
+        };
+
+        let config = EmbedConfig::new(66).step_size(100.0);
+        let plain = embed_with(&host, &wm, &config).unwrap();
+        let config = config.preserve_dc(true);
+        let preserved = embed_with(&host, &wm, &config).unwrap();
+        let (plain_variance, preserved_variance) =
+            (mean_shift_variance(&plain), mean_shift_variance(&preserved));
+        println!(
+            "block mean shift variance: {} plain, {} preserved",
+            plain_variance, preserved_variance
+        );
+        assert!(plain_variance > 0.0);
+        assert_eq!(preserved_variance, 0.0);
+
+        let (bits, _) = extract_with(&preserved, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert!(ber < 0.01, "BER {}", ber);
+
+        let dc_mask = qim::CoefficientMask::zigzag(&(0..12).collect::<Vec<_>>()).unwrap();
+        assert!(matches!(
+            embed_with(&host, &wm, &config.mask(dc_mask)),
+            Err(WatermarkError::InvalidMask(_))
+        ));
+    }
+
+    #[test]
+    fn test_embed_batch() {
+        let pepper = synthetic_host(1);
+        let lena = synthetic_host(2);
+        let wm = synthetic_watermark(1);
+        let too_small = DynamicImage::new_rgb8(4, 4);
+        let hosts = [pepper.clone(), too_small, lena];
+        let config = EmbedConfig::new(67);
+
+        let mut calls = Vec::new();
+        let results = embed_batch(&hosts, &wm, &config, |done, total| {
+            calls.push((done, total))
+        });
+        assert_eq!(calls, [(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().unwrap(),
+            &embed_with(&pepper, &wm, &config).unwrap()
+        );
+        assert!(matches!(
+            results[1],
+            Err(WatermarkError::HostDimensions { .. })
+        ));
+        assert!(results[2].is_ok());
+
+        let results = embed_batch(&[], &wm, &config, |_, _| panic!("no host to report"));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_region() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let config = EmbedConfig::new(69).region(image::math::Rect {
+            x: 128,
+            y: 128,
+            width: 256,
+            height: 256,
+        });
+
+        // The 32 * 32 blocks of the region fit a 64 * 64 watermark but not the full size one
+        let full = synthetic_watermark(1);
+        assert!(matches!(
+            embed_with(&host, &full, &config),
+            Err(WatermarkError::Capacity {
+                available: 12288,
+                ..
+            })
+        ));
+
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (bits, _) = extract_with(&wmkd_image, &config, 64, 64).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert!(ber < 0.01, "BER {}", ber);
+
+        // The borders are left pristine
+        let (host_rgb, wmkd_rgb) = (host.to_rgb8(), wmkd_image.to_rgb8());
+        for (x, y, pixel) in wmkd_rgb.enumerate_pixels() {
+            if !(128..384).contains(&x) || !(128..384).contains(&y) {
+                assert_eq!(pixel, host_rgb.get_pixel(x, y), "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_capacity_report() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let config = EmbedConfig::new(71).header(true);
+
+        // Matches the bits an actual embed writes
+        let report = capacity_report(host.dimensions(), wm.dimensions(), &config);
+        assert_eq!(report.total_bits, 64 * 64 * 12);
+        assert!(!report.fits);
+        assert!(report.utilization > 1.0);
+        assert!(matches!(
+            embed_with(&host, &wm, &config),
+            Err(WatermarkError::Capacity { required, available })
+                if required == report.needed_bits && available == report.total_bits
+        ));
+
+        let report = capacity_report(host.dimensions(), (100, 100), &config);
+        assert!(report.fits);
+        let wm = wm.resize_exact(100, 100, image::imageops::FilterType::Nearest);
+        let (_, embed) = embed_with_report(&host, &wm, &config).unwrap();
+        assert_eq!(report.needed_bits, embed.bits_embedded);
+        assert!((report.utilization - embed.usage()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_difference_map() {
+        let host = synthetic_host(1);
+        let map = metrics::difference_map(&host, &host, 20.0);
+        assert_eq!(map.dimensions(), host.dimensions());
+        assert!(map.to_rgb8().as_raw().iter().all(|&v| v == 0));
+
+        let wm = synthetic_watermark(1);
+        let wmkd_image = embed_watermark_image(&host, &wm, 73, 50.0).unwrap();
+        let faint = metrics::difference_map(&host, &wmkd_image, 1.0).to_rgb8();
+        let amplified = metrics::difference_map(&host, &wmkd_image, 20.0).to_rgb8();
+        for (&f, &a) in faint.as_raw().iter().zip(amplified.as_raw()) {
+            assert_eq!(a as u32, (f as u32 * 20).min(255));
+        }
+        assert!(amplified.as_raw().iter().any(|&v| v > 0));
+    }
+
+    #[test]
+    fn test_channel_diversity() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        let config = EmbedConfig::new(74)
+            .channels(&[Channel::Y, Channel::Cb, Channel::Cr])
+            .channel_mode(ChannelMode::Diversity);
+
+        // The capacity is that of a single plane, filled three times
+        let (wmkd_image, report) = embed_with_report(&host, &wm, &config).unwrap();
+        assert_eq!(report.bits_embedded, 3 * wm_bits.len());
+        assert_eq!(report.usage(), 1.0);
+
+        // Wipe the watermark from two planes by putting back those of the host
+        let color = colorspace::ColorConfig::default();
+        let wmkd_planes = colorspace::convert_to_YCbCr_with(&wmkd_image, &color);
+        for survivor in [Channel::Y, Channel::Cb, Channel::Cr] {
+            let mut planes = colorspace::convert_to_YCbCr_with(&host, &color);
+            match survivor {
+                Channel::Y => planes.y = wmkd_planes.y.clone(),
+                Channel::Cb => planes.cb = wmkd_planes.cb.clone(),
+                Channel::Cr => planes.cr = wmkd_planes.cr.clone(),
+            }
+            let damaged = colorspace::convert_to_RGB_with(&planes, &color);
+            let (bits, _) = extract_with(&damaged, &config, 128, 128).unwrap();
+            let ber = metrics::bit_error_rate(&wm_bits, &bits).unwrap();
+            assert!(ber < 0.01, "{:?} alone: BER {}", survivor, ber);
+        }
+    }
+
+    #[test]
+    fn test_oversized_watermark() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+
+        // A single row more than the 128 * 128 that exactly fills the 64 * 64 blocks
+        let oversized = wm.resize_exact(128, 129, image::imageops::FilterType::Nearest);
+        let err = embed_watermark_image(&host, &oversized, 75, 50.0)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            WatermarkError::Capacity {
+                required: 49536,
+                available: 49152
+            }
+        ));
+        let msg = err.to_string();
+        assert!(msg.contains("49536") && msg.contains("49152"), "{}", msg);
+
+        // Short watermarks are padded to a whole block, one pixel takes a block
+        let (_, report) =
+            embed_with_report(&host, &wm.crop_imm(0, 0, 1, 1), &EmbedConfig::new(75)).unwrap();
+        assert_eq!((report.bits_embedded, report.blocks_used), (12, 1));
+    }
+
+    #[test]
+    fn test_permute_blocks() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        // A step the YCbCr rounding can't flip, so both orders extract every bit
+        let plain_config = EmbedConfig::new(76).step_size(100.0);
+        let config = plain_config.clone().permute_blocks(true);
+
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (bits, _) = extract_with(&wmkd_image, &config, 64, 64).unwrap();
+        let (plain_bits, _) = extract_with(
+            &embed_with(&host, &wm, &plain_config).unwrap(),
+            &plain_config,
+            64,
+            64,
+        )
+        .unwrap();
+        assert_eq!(bits, plain_bits);
+        assert!(metrics::bit_error_rate(&wm_bits, &bits).unwrap() < 0.01);
+
+        // A quarter of the capacity reaches the bottom half of the host instead of stopping at the top quarter,
+        // grayscale hosts keep the untouched blocks exactly
+        let gray = DynamicImage::ImageLuma8(host.to_luma8());
+        let bottom = |image: &DynamicImage| image.crop_imm(0, 256, 512, 256);
+        let plain = embed_with(&gray, &wm, &plain_config).unwrap();
+        assert_eq!(
+            metrics::psnr(&bottom(&gray), &bottom(&plain)),
+            f64::INFINITY
+        );
+        let permuted = embed_with(&gray, &wm, &config).unwrap();
+        assert!(metrics::psnr(&bottom(&gray), &bottom(&permuted)) < 60.0);
+
+        // The order depends on the key
+        let permutation = qim::block_permutation(4096, 76);
+        let mut sorted = permutation.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..4096).collect::<Vec<_>>());
+        assert_ne!(permutation, qim::block_permutation(4096, 77));
+        assert_eq!(permutation, qim::block_permutation(4096, 76));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_events() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        /// Collects the message of every event
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        struct Message(String);
+
+        impl Visit for Message {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &tracing::Event<'_>) {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().unwrap().push(message.0);
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let host = synthetic_host(1).crop_imm(0, 0, 128, 128);
+        let wm = synthetic_watermark(1).resize_exact(32, 32, image::imageops::FilterType::Nearest);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(events.clone()), || {
+            let wmkd_image = embed_watermark_image(&host, &wm, 78, 50.0).unwrap();
+            extract_watermark_image(&wmkd_image, 78, 50.0, 32, 32).unwrap();
+        });
+
+        let events = events.lock().unwrap();
+        let count = |message: &str| events.iter().filter(|e| *e == message).count();
+        assert_eq!(count("converting to YCbCr"), 2);
+        assert_eq!(count("forward transform"), 2);
+        assert_eq!(count("inverse transform"), 1);
+        assert_eq!(count("embedded block"), 16 * 16);
+        assert_eq!(count("extracted block"), 16 * 16);
+        assert_eq!(count("reconstructing watermark"), 1);
+    }
+
+    #[test]
+    fn test_self_test() {
+        assert!(self_test());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = WatermarkError::OriginalDimensions {
+            expected: (512, 512),
+            actual: (256, 300),
+        };
+        let msg = err.to_string();
+        assert!(
+            msg.contains("256 * 300") && msg.contains("512 * 512"),
+            "{}",
+            msg
+        );
+
+        let msg = WatermarkError::Capacity {
+            required: 49200,
+            available: 12288,
+        }
+        .to_string();
+        assert!(msg.contains("49200") && msg.contains("12288"), "{}", msg);
+
+        let msg = WatermarkError::InvalidStepSize(-2.5).to_string();
+        assert!(msg.contains("-2.5"), "{}", msg);
+
+        // Composes with boxed errors and keeps the underlying cause
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing.png");
+        let boxed: Box<dyn std::error::Error> = Box::new(WatermarkError::from(io));
+        assert!(boxed.to_string().contains("missing.png"));
+        assert!(boxed.source().is_some());
+    }
+
+    #[test]
+    fn test_grayscale_host() {
+        let host = DynamicImage::ImageLuma8(synthetic_host(1).to_luma8());
+        let wm = synthetic_watermark(1);
+        let config = EmbedConfig::new(53).step_size(50.0);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        assert_eq!(wmkd_image.color(), image::ColorType::L8);
+        assert!(metrics::psnr(&host, &wmkd_image) > 35.0);
+        let (bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert!(ber < 0.01, "BER {}", ber);
+
+        let gray_alpha = DynamicImage::ImageLumaA8(host.to_luma_alpha8());
+        let wmkd_image = embed_with(&gray_alpha, &wm, &config).unwrap();
+        assert_eq!(wmkd_image.color(), image::ColorType::La8);
+
+        // Chroma planes of a grayscale host go through the colorspace conversion
+        let config = config.channels(&[Channel::Y, Channel::Cb]);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        assert_eq!(wmkd_image.color(), image::ColorType::Rgb8);
+    }
+
+    #[test]
+    fn test_saturated_colors_round_trip() {
+        let red =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([255, 0, 0])));
+        let blue =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([0, 0, 255])));
+
+        // Red pushes Cr up and Cb down, blue the opposite
+        let planes = colorspace::convert_to_YCbCr(&red);
+        assert!(planes.cr[0] > 200 && planes.cb[0] < 128);
+        let rgb = colorspace::convert_to_RGB(&planes).to_rgb8();
+        let [r, g, b] = rgb.get_pixel(0, 0).0;
+        assert!(r > 250 && g < 5 && b < 5, "red became {:?}", (r, g, b));
+
+        let planes = colorspace::convert_to_YCbCr(&blue);
+        assert!(planes.cb[0] > 200 && planes.cr[0] < 128);
+        let rgb = colorspace::convert_to_RGB(&planes).to_rgb8();
+        let [r, g, b] = rgb.get_pixel(0, 0).0;
+        assert!(r < 5 && g < 5 && b > 250, "blue became {:?}", (r, g, b));
+    }
+
+    #[test]
+    fn test_non_square_ycbcr_round_trip() {
+        // Distinct colors along each axis so transposed width / height would show up
+        let (width, height) = (16, 8);
+        let rgb = image::RgbImage::from_fn(width, height, |col, row| {
+            image::Rgb([
+                (col * 16) as u8,
+                (row * 32) as u8,
+                200 - (col * 4 + row) as u8,
+            ])
+        });
+        let image = DynamicImage::ImageRgb8(rgb.clone());
+
+        let planes = colorspace::convert_to_YCbCr(&image);
+        assert_eq!((planes.width, planes.height), (width, height));
+        let round_trip = colorspace::convert_to_RGB(&planes).to_rgb8();
+
+        assert_eq!(round_trip.dimensions(), (width, height));
+        // Y, Cb and Cr are rounded to 8 bits, and the inverse matrix scales the half level of chroma error
+        // by up to 1.77 into blue, 1.4 levels with the luma error. The fixed point coefficients of the
+        // conversion add up to a level each way, so a sample comes back at most 3 levels off
+        for (col, row, pixel) in round_trip.enumerate_pixels() {
+            let expected = rgb.get_pixel(col, row);
+            for c in 0..3 {
+                assert!(
+                    (pixel[c] as i32 - expected[c] as i32).abs() <= 3,
+                    "pixel ({}, {}) is {:?}, expected {:?}",
+                    col,
+                    row,
+                    pixel,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_typed_buffers() {
+        let image = synthetic_host(1);
+        let rgb: image::RgbImage = image.to_rgb8();
+
+        // Typed buffers and views of them go straight in, without a DynamicImage
+        assert_eq!(
+            color_recode::recode_to_3bits(&rgb),
+            color_recode::recode_to_3bits(&image)
+        );
+        assert_eq!(
+            colorspace::convert_to_YCbCr(&rgb),
+            colorspace::convert_to_YCbCr(&image)
+        );
+        let view = image::imageops::crop_imm(&rgb, 64, 32, 100, 50);
+        assert_eq!(
+            color_recode::recode(&*view, 2),
+            color_recode::recode(&image.crop_imm(64, 32, 100, 50), 2)
+        );
+
+        let rgba: image::RgbaImage = image.to_rgba8();
+        assert_eq!(
+            colorspace::convert_to_YCbCr_with(&rgba, &colorspace::ColorConfig::default()),
+            colorspace::convert_to_YCbCr(&rgb)
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_ycbcr_planes() {
+        // A 2 * 1 image, white then black, built by hand
+        let planes = colorspace::YCbCrPlanes {
+            y: colorspace::YPlane(vec![255, 0]),
+            cb: colorspace::CbPlane(vec![128, 128]),
+            cr: colorspace::CrPlane(vec![128, 128]),
+            width: 2,
+            height: 1,
+        };
+        let rgb = colorspace::convert_to_RGB(&planes);
+        assert_eq!(rgb.dimensions(), (2, 1));
+        assert_eq!(rgb.to_rgb8().as_raw(), &vec![255, 255, 255, 0, 0, 0]);
+        assert_eq!(colorspace::convert_to_YCbCr(&rgb), planes);
+
+        // The tuple shims agree with the struct
+        let image = synthetic_host(1).crop_imm(0, 0, 40, 24);
+        let planes = colorspace::convert_to_YCbCr(&image);
+        let (y_plane, cb_plane, cr_plane) = colorspace::convert_to_YCbCr_tuple(&image);
+        assert_eq!(
+            (&planes.y, &planes.cb, &planes.cr),
+            (&y_plane, &cb_plane, &cr_plane)
+        );
+        assert_eq!(
+            colorspace::convert_to_RGB_tuple(40, 24, &y_plane, &cb_plane, &cr_plane),
+            colorspace::convert_to_RGB(&planes)
+        );
+    }
+
+    #[test]
+    fn test_color_config_round_trip() {
+        use colorspace::{ColorConfig, YuvRange, YuvStandardMatrix};
+
+        let image = synthetic_host(1).crop_imm(0, 0, 64, 64);
+        let rgb = image.to_rgb8();
+
+        for matrix in [
+            YuvStandardMatrix::Bt601,
+            YuvStandardMatrix::Bt709,
+            YuvStandardMatrix::Bt2020,
+        ] {
+            let config = ColorConfig {
+                matrix,
+                range: YuvRange::Full,
+                ..ColorConfig::default()
+            };
+            let planes = colorspace::convert_to_YCbCr_with(&image, &config);
+            let round_trip = colorspace::convert_to_RGB_with(&planes, &config).to_rgb8();
+
+            // Up to 3 levels off, like the default matrix: the inverse of each matrix scales the chroma
+            // rounding into blue by less than 1.9
+            for (a, b) in round_trip.as_raw().iter().zip(rgb.as_raw()) {
+                assert!(
+                    (*a as i32 - *b as i32).abs() <= 3,
+                    "{:?} doesn't invert cleanly",
+                    matrix
+                );
+            }
+        }
+
+        // The default matches the plain conversion
+        assert_eq!(
+            colorspace::convert_to_YCbCr_with(&image, &ColorConfig::default()),
+            colorspace::convert_to_YCbCr(&image)
+        );
+    }
+
+    #[test]
+    fn test_convert_to_rgb_bulk() {
+        use image::GenericImage;
+
+        let image = synthetic_host(1).crop_imm(100, 200, 64, 48);
+        let (width, height) = image.dimensions();
+        let planes = colorspace::convert_to_YCbCr(&image);
+
+        let fast = colorspace::convert_to_RGB(&planes);
+
+        // Per-pixel reference, rebuilt from the same YCbCr planes
+        let ycbcr = image::RgbImage::from_fn(width, height, |col, row| {
+            let i = (row * width + col) as usize;
+            image::Rgb([planes.y[i], planes.cb[i], planes.cr[i]])
+        });
+        let mut slow = DynamicImage::new_rgb8(width, height);
+        for row in 0..height {
+            for col in 0..width {
+                let p = colorspace::convert_to_RGB(&colorspace::YCbCrPlanes {
+                    y: colorspace::YPlane(vec![ycbcr.get_pixel(col, row)[0]]),
+                    cb: colorspace::CbPlane(vec![ycbcr.get_pixel(col, row)[1]]),
+                    cr: colorspace::CrPlane(vec![ycbcr.get_pixel(col, row)[2]]),
+                    width: 1,
+                    height: 1,
+                })
+                .to_rgb8();
+                let [r, g, b] = p.get_pixel(0, 0).0;
+                slow.put_pixel(col, row, image::Rgba([r, g, b, 255]));
+            }
+        }
+
+        assert_eq!(fast.color(), slow.color());
+        assert_eq!(fast.as_bytes(), slow.as_bytes());
+    }
+
+    #[test]
+    fn test_complete_workflow() {
+        let key = 123456_u64;
+        let step_size = 100.0;
+
+        // *********** Embedding the watermark **********
+        let image = synthetic_host(1);
+        let (width, height) = image.dimensions();
+
+        // Convert the image to YCbCr colorspace
+        let mut planes = colorspace::convert_to_YCbCr(&image);
+
+        // Split Y plane into 8 * 8 blocks for DCT operation
+        let mut y_grid = dct::split_into_blocks(&mut planes.y, width as usize, height as usize, 8);
+        let y_blocks = &mut y_grid.blocks;
+
+        // DCT on Y blocks
+        dct::apply_2d_dct(y_blocks, 8);
+
+        // Load the watermark image
+        let wm_image = synthetic_watermark(1);
+        // Recoding the watermark
+        let wm_bits = color_recode::recode_to_3bits(&wm_image);
+
+        // QIM-DM to embed the watermark with the preset key and step_size
+        let mask = qim::CoefficientMask::default();
+        let dithers = qim::generate_dither_signal(12, step_size, key);
+        for (i, block) in y_blocks.iter_mut().take(wm_bits.len() / 12).enumerate() {
+            qim::embed_wm_at(block, &wm_bits, i * 12, &dithers, step_size, 1.0, &mask);
+        }
+
+        // In between embedding result test
+        let lattices = qim::generate_dither_lattices(12, step_size, 2, key);
+        let in_between = qim::extract_from_blocks(y_blocks, &lattices, 2, step_size, &mask);
+        assert_eq!(in_between, wm_bits);
+        color_recode::recode_to_rgb(&in_between, 128, 128)
+            .save(output_path("in_between.png"))
+            .unwrap();
+
+        // IDCT on watermarked Y blocks
+        dct::apply_2d_idct(y_blocks, 8);
+
+        // Convert Y blocks back to Y plane
+        planes.y.0 = dct::merge_into_plane(&y_grid);
+
+        // Convert back to RGB colorspace
+        let wmd_image = colorspace::convert_to_RGB(&planes);
+
+        // Save the watermarked image
+        wmd_image.save(output_path("watermarked_img.png")).unwrap();
+
+        // ************ Extracting the watermark ***************
+        let wmkd_image = image::open(output_path("watermarked_img.png")).unwrap();
+        let (width, height) = wmkd_image.dimensions();
+
+        // Convert the watermarked image to YCbCr colorspace and DCT on Y blocks
+        let mut wmkd_y_plane = colorspace::convert_to_YCbCr(&wmkd_image).y;
+
+        let mut wmkd_y_blocks =
+            dct::split_into_blocks(&mut wmkd_y_plane, width as usize, height as usize, 8).blocks;
+
+        dct::apply_2d_dct(&mut wmkd_y_blocks, 8);
+
+        // Extract the watermark from each block
+        let mut extracted_wm: BitVec<usize, Lsb0> = BitVec::new();
+        for block in wmkd_y_blocks.iter() {
+            let tmp = qim::extract_wm(block, &dithers, step_size, &mask);
+            for bit in tmp {
+                extracted_wm.push(bit);
+            }
+        }
+
+        // A lossless save keeps the bits read before the IDCT
+        assert_eq!(
+            qim::extract_from_blocks(&wmkd_y_blocks, &lattices, 2, step_size, &mask),
+            in_between
+        );
+
+        // Reconstruct the image from bits and save the recovered watermark
+        let reconstructed_wm = color_recode::recode_to_rgb(&extracted_wm, 128, 128);
+        reconstructed_wm
+            .save(output_path("reconstructed_wm.png"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_key_from_passphrase() {
+        let key = key_from_passphrase("correct horse battery staple");
+        assert_eq!(key, key_from_passphrase("correct horse battery staple"));
+        assert_ne!(key, key_from_passphrase("correct horse battery stapler"));
+        assert_ne!(key_from_passphrase(""), key_from_passphrase(" "));
+
+        // Keys from different passphrases give different dithers
+        let dithers = qim::generate_dither_signal(12, 50.0, key);
+        assert_eq!(dithers, qim::generate_dither_signal(12, 50.0, key));
+        assert_ne!(
+            dithers,
+            qim::generate_dither_signal(12, 50.0, key_from_passphrase("hunter2"))
+        );
+    }
+
+    #[test]
+    fn test_round_to_step_size() {
+        // Positive values
+        assert_eq!(qim::round_to_step_size(0.0, 50.0), 0.0);
+        assert_eq!(qim::round_to_step_size(24.0, 50.0), 0.0);
+        assert_eq!(qim::round_to_step_size(26.0, 50.0), 50.0);
+        assert_eq!(qim::round_to_step_size(130.0, 50.0), 150.0);
+
+        // Negative values round symmetrically
+        assert_eq!(qim::round_to_step_size(-24.0, 50.0), 0.0);
+        assert_eq!(qim::round_to_step_size(-26.0, 50.0), -50.0);
+        assert_eq!(qim::round_to_step_size(-130.0, 50.0), -150.0);
+
+        // Exactly at the half step
+        assert_eq!(qim::round_to_step_size(25.0, 50.0), 50.0);
+        assert_eq!(qim::round_to_step_size(-25.0, 50.0), -50.0);
+
+        // Non-integer step sizes
+        assert_eq!(qim::round_to_step_size(30.0, 12.5), 25.0);
+        assert_eq!(qim::round_to_step_size(-30.0, 12.5), -25.0);
+        assert_eq!(qim::round_to_step_size(-32.0, 12.5), -37.5);
+        assert_eq!(qim::round_to_step_size(6.3, 12.5), 12.5);
+    }
+
+    #[test]
+    fn test_qim_negative_coefficients() {
+        let step_size = 12.5;
+        let dithers = qim::generate_dither_signal(12, step_size, 42);
+        let watermark: BitVec = [
+            true, false, true, true, false, false, true, false, true, false, false, true,
+        ]
+        .into_iter()
+        .collect();
+
+        let mask = qim::CoefficientMask::default();
+        let mut block: Vec<f32> = (0..64).map(|i| -(i as f32) * 7.3 - 0.4).collect();
+        qim::embed_wm(&mut block, &watermark, &dithers, step_size, 1.0, &mask);
+        assert_eq!(
+            qim::extract_wm(&block, &dithers, step_size, &mask),
+            watermark
+        );
+    }
+
+    #[test]
+    fn test_qim_f64() {
+        assert_eq!(qim::round_to_step_size(130.0_f64, 50.0), 150.0);
+        assert_eq!(qim::round_to_step_size(-32.0_f64, 12.5), -37.5);
+
+        // Coefficients around 1e9 are spaced 64 apart in f32, f64 still resolves a step of 0.5
+        let step_size = 0.5;
+        let dithers: Vec<(f64, f64)> = qim::generate_dither_signal(12, step_size as f32, 7)
+            .into_iter()
+            .map(|(d0, d1)| (d0 as f64, d1 as f64))
+            .collect();
+        let watermark: BitVec = (0..12).map(|i| i % 3 == 0).collect();
+        let mask = qim::CoefficientMask::default();
+        let mut block: Vec<f64> = (0..64).map(|i| 1e9 + i as f64 * 3.7).collect();
+        qim::embed_wm(&mut block, &watermark, &dithers, step_size, 1.0, &mask);
+        assert_eq!(
+            qim::extract_wm(&block, &dithers, step_size, &mask),
+            watermark
+        );
+        for value in qim::extract_wm_soft(&block, &dithers, step_size, &mask) {
+            assert!(value.abs() > 0.99);
+        }
+    }
+
+    #[test]
+    fn test_qim_borderline_bits() {
+        // A bit decodes to 1 from a tenth of a step away from the lattice of bit 0
+        let step_size = 50.0_f32;
+        let dithers = vec![(0.0_f32, -25.0_f32); 12];
+        let mask = qim::CoefficientMask::default();
+        let borderline = [
+            5.0,
+            f32::from_bits(5.0_f32.to_bits() + 1),
+            f32::from_bits(5.0_f32.to_bits() - 1),
+            45.0,
+            f32::from_bits(45.0_f32.to_bits() + 1),
+            -5.0,
+            f32::from_bits((-5.0_f32).to_bits() - 1),
+            25.0,
+            -25.0,
+            0.1 + 0.2,
+            1e9 + 5.0,
+            55.0,
+        ];
+        let expected = [
+            true, true, false, true, false, true, false, true, true, false, false, true,
+        ];
+
+        let mut block = vec![0.0_f32; 64];
+        let mut block64 = vec![0.0_f64; 64];
+        for (&i, &value) in mask.indices().iter().zip(&borderline) {
+            block[i] = value;
+            block64[i] = value as f64;
+        }
+        let expected: BitVec = expected.into_iter().collect();
+        assert_eq!(
+            qim::extract_wm(&block, &dithers, step_size, &mask),
+            expected
+        );
+        let dithers64 = vec![(0.0_f64, -25.0_f64); 12];
+        assert_eq!(qim::extract_wm(&block64, &dithers64, 50.0, &mask), expected);
+
+        // Halfway cases are rounded away from zero
+        let mut block = vec![0.0_f32; 64];
+        block[mask.indices()[0]] = 25.0;
+        block[mask.indices()[1]] = 0.0;
+        let bits: BitVec = [false, true].into_iter().chain([false; 10]).collect();
+        qim::embed_wm(&mut block, &bits, &dithers, step_size, 1.0, &mask);
+        assert_eq!(block[mask.indices()[0]], 50.0);
+        assert_eq!(block[mask.indices()[1]], -25.0);
+    }
+
+    #[test]
+    fn test_extract_wm_into() {
+        let step_size = 50.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 77);
+        let mask = qim::CoefficientMask::default();
+        let blocks: Vec<Vec<f32>> = (0..32)
+            .map(|n| {
+                (0..64)
+                    .map(|i| ((n * 37 + i * 13) % 150) as f32 - 75.0)
+                    .collect()
+            })
+            .collect();
+
+        let mut filled: BitVec = BitVec::repeat(false, blocks.len() * 12);
+        for (block, out) in blocks.iter().zip(filled.chunks_mut(12)) {
+            qim::extract_wm_into(block, &dithers, step_size, &mask, out);
+        }
+        let allocated: BitVec = blocks
+            .iter()
+            .flat_map(|block| qim::extract_wm(block, &dithers, step_size, &mask))
+            .collect();
+        assert_eq!(filled, allocated);
+
+        // Bits already in the buffer are overwritten
+        let mut ones: BitVec = BitVec::repeat(true, 12);
+        qim::extract_wm_into(&blocks[0], &dithers, step_size, &mask, &mut ones);
+        assert_eq!(ones, allocated[..12]);
+    }
+
+    #[test]
+    fn test_coefficient_delta_histogram() {
+        let blocks: Vec<Vec<f32>> = (0..16)
+            .map(|n| {
+                (0..64)
+                    .map(|i| ((n * 31 + i * 7) % 120) as f32 - 60.0)
+                    .collect()
+            })
+            .collect();
+        let histogram = metrics::coefficient_delta_histogram(&blocks, &blocks);
+        assert_eq!(histogram.len(), metrics::DELTA_BINS);
+        assert_eq!(histogram[0], 16 * 64);
+        assert!(histogram[1..].iter().all(|&count| count == 0));
+
+        // Only the 12 masked coefficients move, by at most half a step
+        let step_size = 50.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 3);
+        let mask = qim::CoefficientMask::default();
+        let mut watermarked = blocks.clone();
+        for (n, block) in watermarked.iter_mut().enumerate() {
+            let bits: BitVec = (0..12).map(|i| (n + i) % 3 == 0).collect();
+            qim::embed_wm(block, &bits, &dithers, step_size, 1.0, &mask);
+        }
+        let histogram = metrics::coefficient_delta_histogram(&blocks, &watermarked);
+        assert_eq!(histogram.iter().sum::<u32>(), 16 * 64);
+        assert!(histogram[0] >= 16 * 52);
+        assert!(histogram[26..].iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn test_wm_at_offsets() {
+        let step_size = 50.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 78);
+        let lattices = qim::generate_dither_lattices(12, step_size, 4, 78);
+        let mask = qim::CoefficientMask::default();
+        let hosts: Vec<Vec<f32>> = (0..8)
+            .map(|n| {
+                (0..64)
+                    .map(|i| ((n * 29 + i * 17) % 150) as f32 - 75.0)
+                    .collect()
+            })
+            .collect();
+        let payload: BitVec = (0..8 * 24).map(|i| (i * 7) % 5 < 2).collect();
+
+        // Binary QIM, 12 bits per block
+        let mut at_offsets = hosts.clone();
+        let mut chunked = hosts.clone();
+        for (n, (block, bits)) in chunked.iter_mut().zip(payload.chunks(12)).enumerate() {
+            qim::embed_wm_at(
+                &mut at_offsets[n],
+                &payload,
+                n * 12,
+                &dithers,
+                step_size,
+                1.0,
+                &mask,
+            );
+            qim::embed_wm(block, bits, &dithers, step_size, 1.0, &mask);
+        }
+        assert_eq!(at_offsets, chunked);
+        let mut extracted: BitVec = BitVec::repeat(false, 8 * 12);
+        for (n, block) in at_offsets.iter().enumerate() {
+            qim::extract_wm_at(block, &dithers, step_size, &mask, &mut extracted, n * 12);
+        }
+        assert_eq!(extracted, payload[..8 * 12]);
+
+        // 4-ary QIM, 24 bits per block
+        let mut at_offsets = hosts.clone();
+        let mut chunked = hosts;
+        for (n, (block, bits)) in chunked.iter_mut().zip(payload.chunks(24)).enumerate() {
+            qim::embed_wm_mary_at(
+                &mut at_offsets[n],
+                &payload,
+                n * 24,
+                &lattices,
+                4,
+                step_size,
+                1.0,
+                &mask,
+            );
+            qim::embed_wm_mary(block, bits, &lattices, 4, step_size, 1.0, &mask);
+        }
+        assert_eq!(at_offsets, chunked);
+        let mut extracted: BitVec = BitVec::repeat(true, 8 * 24);
+        for (n, block) in at_offsets.iter().enumerate() {
+            qim::extract_wm_mary_at(
+                block,
+                &lattices,
+                4,
+                step_size,
+                &mask,
+                &mut extracted,
+                n * 24,
+            );
+        }
+        assert_eq!(extracted, payload);
+    }
+
+    #[test]
+    fn test_embed_wm_slice() {
+        let step_size = 50.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 3);
+        let mask = qim::CoefficientMask::default();
+        let stream: BitVec = (0..36).map(|i| (i * 5) % 7 < 3).collect();
+        let original: Vec<f32> = (0..64).map(|i| (i as f32 * 11.3) % 80.0 - 40.0).collect();
+
+        for chunk in stream.chunks(12) {
+            let mut from_slice = original.clone();
+            qim::embed_wm(&mut from_slice, chunk, &dithers, step_size, 1.0, &mask);
+            let mut from_vec = original.clone();
+            qim::embed_wm(
+                &mut from_vec,
+                &chunk.to_bitvec(),
+                &dithers,
+                step_size,
+                1.0,
+                &mask,
+            );
+            assert_eq!(from_slice, from_vec);
+        }
+    }
+
+    #[test]
+    fn test_soft_extraction() {
+        let step_size = 40.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 42);
+        let watermark: BitVec = (0..12).map(|i| i % 2 == 0).collect();
+        let mask = qim::CoefficientMask::default();
+
+        let mut block: Vec<f32> = (0..64).map(|i| (i as f32 * 9.1) % 70.0 - 35.0).collect();
+        qim::embed_wm(&mut block, &watermark, &dithers, step_size, 1.0, &mask);
+
+        // Freshly embedded bits sit on their lattice
+        let soft = qim::extract_wm_soft(&block, &dithers, step_size, &mask);
+        for (value, bit) in soft.iter().zip(watermark.iter()) {
+            assert_eq!(*value > 0.0, *bit);
+            assert!(value.abs() > 0.99);
+        }
+
+        // Moving each coefficient 0.2 step towards the other lattice leaves a confidence of 0.2
+        for (j, &i) in mask.indices().iter().enumerate() {
+            block[i] += if watermark[j] { -0.2 } else { 0.2 } * step_size;
+        }
+        let soft = qim::extract_wm_soft(&block, &dithers, step_size, &mask);
+        for (value, bit) in soft.iter().zip(watermark.iter()) {
+            assert_eq!(*value > 0.0, *bit);
+            assert!((value.abs() - 0.2).abs() < 1e-3);
+        }
+        // The hard decision reads the 0s moved past a tenth of a step as 1s
+        assert_eq!(
+            qim::extract_wm(&block, &dithers, step_size, &mask),
+            BitVec::<usize, Lsb0>::repeat(true, 12)
+        );
+    }
+
+    #[test]
+    fn test_distortion_compensation() {
+        use rand::{Rng, SeedableRng};
+
+        let mask = qim::CoefficientMask::default();
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(5);
+        let mut gaussian = |sigma: f32| {
+            // Box-Muller
+            let u1: f32 = rng.random_range(f32::EPSILON..1.0);
+            let u2: f32 = rng.random();
+            sigma * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+        };
+
+        // Same embedding distortion for every alpha: step_size = base / alpha
+        let base_step = 20.0;
+        let mut bers = Vec::new();
+        for alpha in [1.0, 0.8, 0.6, 0.4] {
+            let step_size = base_step / alpha;
+            let dithers = qim::generate_dither_signal(12, step_size, 11);
+            let mut errors = 0;
+            let mut total = 0;
+            for n in 0..2000 {
+                let watermark: BitVec = (0..12).map(|i| (n * 7 + i * 3) % 5 < 2).collect();
+                let mut block: Vec<f32> = (0..64).map(|_| gaussian(100.0)).collect();
+                qim::embed_wm(&mut block, &watermark, &dithers, step_size, alpha, &mask);
+                for &i in mask.indices() {
+                    block[i] += gaussian(base_step / 4.0);
+                }
+
+                let extracted: BitVec = qim::extract_wm_soft(&block, &dithers, step_size, &mask)
+                    .into_iter()
+                    .map(|value| value > 0.0)
+                    .collect();
+                errors += (extracted ^ watermark).count_ones();
+                total += 12;
+            }
+            bers.push(errors as f64 / total as f64);
+        }
+
+        println!("BER for alpha 1.0, 0.8, 0.6, 0.4: {:?}", bers);
+        assert!(bers[1] < bers[0]);
+        assert!(bers[2] < bers[0]);
+        assert!(bers[2] < bers[3]);
+
+        let host = DynamicImage::new_rgb8(8, 8);
+        let wm = DynamicImage::new_rgb8(2, 2);
+        for alpha in [0.0, 1.5] {
+            assert!(matches!(
+                embed_with(&host, &wm, &EmbedConfig::new(1).alpha(alpha)),
+                Err(WatermarkError::InvalidAlpha(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_adaptive_step() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let config = EmbedConfig::new(2143658709).adaptive_step(qim::AdaptiveStep::default());
+
+        // The derived steps actually vary over the host
+        let mask = config.mask.scaled(config.block_size);
+        let steps: Vec<f32> = transform_channels(&host, &config)
+            .iter()
+            .map(|block| {
+                qim::adaptive_step_size(
+                    block,
+                    config.step_size,
+                    &mask,
+                    &qim::AdaptiveStep::default(),
+                )
+            })
+            .collect();
+        let min = steps.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = steps.iter().cloned().fold(0.0, f32::max);
+        println!("Adaptive steps from {} to {}", min, max);
+        assert!(max > 2.0 * min);
+
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let (extracted_bits, _) = extract_with(&watermarked, &config, 128, 128).unwrap();
+        let ber =
+            metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &extracted_bits).unwrap();
+        println!("BER with adaptive step: {}", ber);
+        assert!(ber < 0.02);
+    }
+
+    #[test]
+    fn test_mary_qim() {
+        let mask = qim::CoefficientMask::default();
+        let original: Vec<f32> = (0..64).map(|i| (i as f32 * 13.7) % 90.0 - 45.0).collect();
+
+        // 2 levels are binary QIM
+        let pairs = qim::generate_dither_signal(12, 50.0, 7);
+        let lattices = qim::generate_dither_lattices(12, 50.0, 2, 7);
+        let flattened: Vec<f32> = pairs.iter().flat_map(|&(d0, d1)| [d0, d1]).collect();
+        assert_eq!(lattices, flattened);
+        let watermark: BitVec = (0..12).map(|i| i % 3 == 0).collect();
+        let (mut binary, mut mary) = (original.clone(), original.clone());
+        qim::embed_wm(&mut binary, &watermark, &pairs, 50.0, 1.0, &mask);
+        qim::embed_wm_mary(&mut mary, &watermark, &lattices, 2, 50.0, 1.0, &mask);
+        assert_eq!(binary, mary);
+
+        // 2 bits per coefficient at twice the step survive the same noise
+        let step_size = 100.0;
+        let lattices = qim::generate_dither_lattices(12, step_size, 4, 7);
+        let watermark: BitVec = (0..24).map(|i| i % 3 == 0 || i % 5 == 0).collect();
+        let mut block = original.clone();
+        qim::embed_wm_mary(&mut block, &watermark, &lattices, 4, step_size, 1.0, &mask);
+        for (i, value) in block.iter_mut().enumerate() {
+            *value += if i % 2 == 0 { 8.0 } else { -8.0 };
+        }
+        assert_eq!(
+            qim::extract_wm_mary(&block, &lattices, 4, step_size, &mask),
+            watermark
+        );
+
+        // Twice the payload of a 512 * 512 host
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(2);
+        let config = EmbedConfig::new(3)
+            .step_size(step_size)
+            .levels(4)
+            .bits_per_channel(2);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode(&wm, 2), &bits).unwrap();
+        assert!(ber < 0.02, "BER {}", ber);
+
+        assert!(matches!(
+            embed_with(&host, &wm, &EmbedConfig::new(3).levels(3)),
+            Err(WatermarkError::Levels(3))
+        ));
+    }
+
+    #[test]
+    fn test_acceptable_range() {
+        let step_size = 50.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 21);
+        let mask = qim::CoefficientMask::default();
+        // Bits flipped over 100 blocks carrying only `bit`, with noise of up to `noise` steps
+        let flips = |bit: bool, alpha: f32, noise: f32, acceptable_range: f32| {
+            let bits: BitVec = BitVec::repeat(bit, 12);
+            (0..100)
+                .map(|n| {
+                    let mut block: Vec<f32> = (0..64)
+                        .map(|i| ((n * 37 + i * 13) % 150) as f32 - 75.0)
+                        .collect();
+                    qim::embed_wm(&mut block, &bits, &dithers, step_size, alpha, &mask);
+                    for (k, &i) in mask.indices().iter().enumerate() {
+                        let uniform = ((n * 12 + k) * 7919 % 201) as f32 / 100.0 - 1.0;
+                        block[i] += noise * step_size * uniform;
+                    }
+                    let extracted = qim::extract_wm_with_range(
+                        &block,
+                        &dithers,
+                        step_size,
+                        acceptable_range,
+                        &mask,
+                    );
+                    extracted
+                        .iter()
+                        .filter(|extracted| **extracted != bit)
+                        .count()
+                })
+                .sum::<usize>()
+        };
+
+        // The default is extract_wm
+        let block: Vec<f32> = (0..64).map(|i| (i * 29 % 97) as f32 - 48.0).collect();
+        assert_eq!(
+            qim::extract_wm_with_range(
+                &block,
+                &dithers,
+                step_size,
+                qim::DEFAULT_ACCEPTABLE_RANGE,
+                &mask
+            ),
+            qim::extract_wm(&block, &dithers, step_size, &mask)
+        );
+
+        // A wider range keeps the 0s moved by noise
+        assert!(flips(false, 1.0, 0.35, qim::DEFAULT_ACCEPTABLE_RANGE) > 0);
+        assert_eq!(flips(false, 1.0, 0.35, 0.4), 0);
+
+        // But reads the clean 1s distortion compensation left off their lattice as 0s
+        assert_eq!(flips(true, 0.6, 0.0, qim::DEFAULT_ACCEPTABLE_RANGE), 0);
+        assert!(flips(true, 0.6, 0.0, 0.4) > 0);
+
+        // Extraction applies the range of the config, a quarter step is the closest lattice
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        let config = EmbedConfig::new(5);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let (closest, _) = extract_with(&watermarked, &config, 64, 64).unwrap();
+        let (quarter, _) =
+            extract_with(&watermarked, &config.clone().acceptable_range(0.25), 64, 64).unwrap();
+        assert_eq!(quarter, closest);
+        let (tenth, _) =
+            extract_with(&watermarked, &config.clone().acceptable_range(0.1), 64, 64).unwrap();
+        assert!(
+            metrics::bit_error_rate(&wm_bits, &tenth).unwrap()
+                > metrics::bit_error_rate(&wm_bits, &closest).unwrap()
+        );
+
+        for config in [
+            config.clone().acceptable_range(0.5),
+            config.clone().levels(4).acceptable_range(0.25),
+        ] {
+            assert!(matches!(
+                extract_with(&watermarked, &config, 64, 64),
+                Err(WatermarkError::InvalidAcceptableRange(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_dither_signal_with() {
+        use rand::SeedableRng;
+        let mut first = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+        let mut second = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+        let dithers = qim::generate_dither_signal_with(12, 50.0, &mut first);
+        assert_eq!(
+            dithers,
+            qim::generate_dither_signal_with(12, 50.0, &mut second)
+        );
+        // The seed based version is the same generator
+        assert_eq!(dithers, qim::generate_dither_signal(12, 50.0, 42));
+        assert_ne!(
+            dithers,
+            qim::generate_dither_signal_with(12, 50.0, &mut first)
+        );
+    }
+
+    #[test]
+    fn test_per_block_dither() {
+        let first = qim::generate_block_dither_lattices(12, 50.0, 2, 7, 0);
+        let second = qim::generate_block_dither_lattices(12, 50.0, 2, 7, 1);
+        assert_ne!(first, second);
+        assert_eq!(
+            first,
+            qim::generate_block_dither_lattices(12, 50.0, 2, 7, 0)
+        );
+
+        let host = synthetic_host(1);
+        // 2 bits per channel span both planes, the dithers of Cb continue from the last block of Y
+        let wm = synthetic_watermark(2);
+        let reference = color_recode::recode(&wm, 2);
+        let config = EmbedConfig::new(7)
+            .per_block_dither(true)
+            .channels(&[Channel::Y, Channel::Cb])
+            .bits_per_channel(2);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+
+        let (bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&reference, &bits).unwrap();
+        assert!(ber < 0.02, "BER {}", ber);
+
+        // The shared dithers don't decode it
+        let shared = config.clone().per_block_dither(false);
+        let (bits, _) = extract_with(&wmkd_image, &shared, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&reference, &bits).unwrap();
+        assert!(ber > 0.2, "BER {}", ber);
+    }
+
+    #[test]
+    fn test_coefficient_mask() {
+        assert!(matches!(
+            qim::CoefficientMask::new(&[]),
+            Err(WatermarkError::InvalidMask(_))
+        ));
+        assert!(matches!(
+            qim::CoefficientMask::new(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 64]),
+            Err(WatermarkError::InvalidMask(_))
+        ));
+        assert!(matches!(
+            qim::CoefficientMask::new(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 1]),
+            Err(WatermarkError::InvalidMask(_))
+        ));
+
+        let custom =
+            qim::CoefficientMask::new(&[40, 41, 42, 43, 48, 49, 50, 51, 56, 57, 58, 59]).unwrap();
+        assert_eq!(custom.indices()[0], 40);
+
+        // Only the selected coefficients are modified, and the bits come back out
+        let step_size = 30.0;
+        let dithers = qim::generate_dither_signal(12, step_size, 7);
+        let watermark: BitVec = (0..12).map(|i| i % 3 == 0).collect();
+        for mask in [
+            custom,
+            qim::CoefficientMask::preset(qim::Preset::MidFrequency),
+            qim::CoefficientMask::preset(qim::Preset::LowFrequency),
+        ] {
+            let original: Vec<f32> = (0..64).map(|i| (i as f32 * 13.7) % 90.0 - 45.0).collect();
+            let mut block = original.clone();
+            qim::embed_wm(&mut block, &watermark, &dithers, step_size, 1.0, &mask);
+
+            for i in 0..64 {
+                if !mask.indices().contains(&i) {
+                    assert_eq!(block[i], original[i]);
+                }
+            }
+            assert_eq!(
+                qim::extract_wm(&block, &dithers, step_size, &mask),
+                watermark
+            );
+        }
+    }
+
+    #[test]
+    fn test_zigzag_masks() {
+        let scan: Vec<usize> = (0..14).map(qim::zigzag_index).collect();
+        assert_eq!(scan, [0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11]);
+        assert_eq!(qim::zigzag_index(63), 63);
+        let mut all: Vec<usize> = (0..64).map(qim::zigzag_index).collect();
+        all.sort_unstable();
+        assert!(all.iter().enumerate().all(|(i, &index)| i == index));
+
+        // The presets select the same positions, in the same bit order, as their row-major definitions
+        assert_eq!(
+            qim::CoefficientMask::preset(qim::Preset::MidFrequency).indices(),
+            &[4, 5, 6, 7, 11, 12, 13, 14, 15, 18, 19, 20]
+        );
+        assert_eq!(
+            qim::CoefficientMask::preset(qim::Preset::LowFrequency).indices(),
+            &[1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18]
+        );
+        assert!(matches!(
+            qim::CoefficientMask::zigzag(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 64]),
+            Err(WatermarkError::InvalidMask(_))
+        ));
+    }
+
+    #[test]
+    fn test_mask_length() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let reference = color_recode::recode_to_3bits(&wm);
+
+        // Each block carries one bit per position, the capacity follows the mask. The rounding of the
+        // YCbCr round trip moves the unscaled DCT coefficients past the quarter of a 50 step in a few of
+        // the 12288 bits, a 100 step leaves 25 units of margin
+        for len in [6, 16] {
+            let mask = qim::CoefficientMask::zigzag(&(3..3 + len).collect::<Vec<_>>()).unwrap();
+            assert_eq!(mask.len(), len);
+            let config = EmbedConfig::new(1).step_size(100.0).mask(mask);
+            let report = capacity_report((512, 512), (64, 64), &config);
+            assert_eq!(report.total_bits, 64 * 64 * len);
+
+            let (wmkd_image, report) = embed_with_report(&host, &wm, &config).unwrap();
+            assert_eq!(report.blocks_used, (64 * 64 * 3usize).div_ceil(len));
+            let (bits, _) = extract_with(&wmkd_image, &config, 64, 64).unwrap();
+            assert_eq!(metrics::bit_error_rate(&reference, &bits).unwrap(), 0.0);
+        }
+
+        // Per block dithers follow the mask length too
+        let config = EmbedConfig::new(2)
+            .step_size(100.0)
+            .per_block_dither(true)
+            .mask(qim::CoefficientMask::new(&[9, 10, 17]).unwrap());
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (bits, _) = extract_with(&wmkd_image, &config, 64, 64).unwrap();
+        assert_eq!(metrics::bit_error_rate(&reference, &bits).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_authentication() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        // A single bit flipped by the rounding of the YCbCr round trip fails the tag, and a few of the bits
+        // move past the quarter of a 50 step, a 100 step leaves 25 units of margin
+        let config = EmbedConfig::new(9).step_size(100.0).authenticate(true);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+
+        match extract_authenticated(&wmkd_image, 9, 100.0, 64, 64).unwrap() {
+            Authentication::Authenticated(bits) => {
+                assert_eq!(bits, color_recode::recode_to_3bits(&wm));
+            }
+            Authentication::Unauthenticated => panic!("expected an authenticated watermark"),
+        }
+        // The tag sits after the watermark, plain extraction still reads it
+        let (bits, _) = extract_with(&wmkd_image, &config, 64, 64).unwrap();
+        assert_eq!(bits, color_recode::recode_to_3bits(&wm));
 
-    // Convert the image to YCbCr colorspace
-    let (mut y_plane, cb_plane, cr_plane) = colorspace::convert_to_YCbCr(&host);
+        // Noise read out of a clean host, or with another key, doesn't match its tag
+        for (image, key) in [(&host, 9), (&wmkd_image, 10)] {
+            assert_eq!(
+                extract_authenticated(image, key, 100.0, 64, 64).unwrap(),
+                Authentication::Unauthenticated
+            );
+        }
 
-    // Split Y plane into 8 * 8 blocks for DCT operation
-    let mut y_blocks = dct::split_into_blocks(&mut y_plane, h_width as usize, h_height as usize);
+        // A watermark without a tag neither
+        let untagged = embed_with(&host, &wm, &EmbedConfig::new(9).step_size(100.0)).unwrap();
+        assert_eq!(
+            extract_authenticated(&untagged, 9, 100.0, 64, 64).unwrap(),
+            Authentication::Unauthenticated
+        );
+        // The config is validated like for any other extraction
+        assert!(matches!(
+            extract_authenticated_with(&wmkd_image, &config.clone().acceptable_range(0.6), 64, 64),
+            Err(WatermarkError::InvalidAcceptableRange(_))
+        ));
 
-    // DCT on Y blocks
-    dct::apply_2d_dct(&mut y_blocks);
+        let report = capacity_report((512, 512), (64, 64), &config);
+        assert_eq!(
+            report.needed_bits,
+            (64 * 64 * 3 + payload::TAG_LEN).div_ceil(12) * 12
+        );
+    }
 
-    let wm = image::open(watermark_image).expect("Failed to open watermark image");
-    let (wm_width, wm_height) = wm.dimensions();
-    assert!(wm_width == 128 && wm_height == 128);
+    #[test]
+    fn test_detect_watermark() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        // The rounding to 8-bit samples moves the coefficients by some 2 units on average, taking 8 * 2 / step
+        // off the score, 0.3 at a 50 step and 0.15 at a 100 step
+        let wmkd_image = embed_watermark_image(&host, &wm, 5, 100.0).unwrap();
 
-    // Recoding the watermark
-    let wm_bits = color_recode::recode_to_3bits(&wm);
+        let watermarked = detect_watermark(&wmkd_image, 5, 100.0).unwrap();
+        let clean = detect_watermark(&host, 5, 100.0).unwrap();
+        let other_key = detect_watermark(&wmkd_image, 6, 100.0).unwrap();
+        println!("Scores: {} {} {}", watermarked, clean, other_key);
+        assert!(watermarked > 0.8, "{}", watermarked);
+        assert!(clean < 0.2, "{}", clean);
+        assert!(other_key < 0.2, "{}", other_key);
 
-    // QIM-DM to embed the watermark with the preset key and step_size
-    let dithers = qim::generate_dither_signal(12, step_size, key);
-    for (i, bits) in wm_bits.chunks(12).enumerate() {
-        qim::embed_wm(&mut y_blocks[i], &bits.to_bitvec(), &dithers, step_size);
+        // Still stands out once compressed, with a step surviving the compression
+        let robust = embed_watermark_image(&host, &wm, 5, 150.0).unwrap();
+        let jpeg = attack::simulate_jpeg(&robust, 90);
+        let compressed = detect_watermark(&jpeg, 5, 150.0).unwrap();
+        assert!(compressed > 0.3, "{}", compressed);
     }
 
-    // IDCT on watermarked Y blocks
-    dct::apply_2d_idct(&mut y_blocks);
+    #[test]
+    fn test_extract_partial() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        // Large enough a step that the rounding of the YCbCr round trip, a few units of the unscaled DCT
+        // coefficients, stays well within a quarter step and flips no bit
+        let config = EmbedConfig::new(5).step_size(100.0);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
 
-    // Convert Y blocks back to Y plane
-    let watermarked_y_plane = dct::merge_into_plane(&y_blocks, h_width as usize, h_height as usize);
+        let complete = extract_partial_with(&wmkd_image, &config, 128, 128).unwrap();
+        assert!(complete.is_complete());
+        assert_eq!(complete.bits, wm_bits);
 
-    // Convert back to RGB colorspace and return the RGB DynamicImage
-    colorspace::convert_to_RGB(
-        h_width,
-        h_height,
-        &watermarked_y_plane,
-        &cb_plane,
-        &cr_plane,
-    )
-}
+        // The bottom 112 rows are lost, taking the last 14 rows of 64 blocks with them
+        let truncated = wmkd_image.crop_imm(0, 0, 512, 400);
+        assert!(matches!(
+            extract_with(&truncated, &config, 128, 128),
+            Err(WatermarkError::Capacity { .. })
+        ));
+        let partial = extract_partial_with(&truncated, &config, 128, 128).unwrap();
+        assert_eq!(partial.missing_blocks, 14 * 64);
+        assert_eq!(partial.bits.len(), wm_bits.len());
+        let read = 50 * 64 * 12;
+        assert_eq!(partial.bits[..read], wm_bits[..read]);
+        assert!(partial.bits[read..].not_any());
+    }
 
-/// Extract the colored watermark embedded using DCT + QIM-DM watermarking scheme
-///
-/// Returns the original bit stream and the reconstructed RGB DynamicImage
-///
-/// Works with images of size 512 * 512 and watermark of size 128 * 128,
-/// with watermark embedded in implementation specific locations
-pub fn extract_watermark(
-    watermarked_image: &str,
-    key: u64,
-    step_size: f32,
-) -> (BitVec, DynamicImage) {
-    let wmkd_image = image::open(watermarked_image).unwrap();
-    let (width, height) = wmkd_image.dimensions();
+    #[test]
+    fn test_extract_blocks() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wmkd_image = embed_watermark_image(&host, &wm, 5, 50.0).unwrap();
+
+        let blocks: Vec<_> = extract_blocks(&wmkd_image, 5, 50.0).unwrap().collect();
+        assert_eq!(blocks.len(), 64 * 64);
+        assert_eq!(blocks[1].0, 1);
+        assert_eq!(blocks[64].1, 1);
+        assert!(blocks.iter().all(|(_, _, soft)| soft.len() == 12));
+
+        // The signs of the soft values are the extracted bits
+        let bits = extract_watermark_bits(&wmkd_image, 5, 50.0, 128, 128).unwrap();
+        let signs: BitVec = blocks
+            .iter()
+            .flat_map(|(_, _, soft)| soft.iter().map(|&v| v > 0.0))
+            .collect();
+        assert_eq!(signs, bits);
+
+        // Each channel yields the blocks of its plane
+        let config = EmbedConfig::new(5).channels(&[Channel::Y, Channel::Cr]);
+        assert_eq!(
+            extract_blocks_with(&wmkd_image, &config).unwrap().count(),
+            2 * 64 * 64
+        );
+    }
 
-    // Convert the watermarked image to YCbCr colorspace and DCT on Y blocks
-    let (mut wmkd_y_plane, _, _) = colorspace::convert_to_YCbCr(&wmkd_image);
+    #[test]
+    fn test_multiple_watermarks() {
+        let host = synthetic_host(1);
+        let logo = synthetic_watermark(1);
+        let gradient = synthetic_watermark(2);
 
-    let mut wmkd_y_blocks =
-        dct::split_into_blocks(&mut wmkd_y_plane, width as usize, height as usize);
+        let robust = EmbedConfig::new(1)
+            .step_size(50.0)
+            .mask(qim::CoefficientMask::preset(qim::Preset::LowFrequency));
+        let high_frequency: Vec<usize> = (30..42).collect();
+        let fragile = EmbedConfig::new(2)
+            .step_size(50.0)
+            .mask(qim::CoefficientMask::zigzag(&high_frequency).unwrap());
+        assert!(!robust.mask.overlaps(&fragile.mask));
+        assert!(robust.mask.overlaps(&qim::CoefficientMask::default()));
 
-    dct::apply_2d_dct(&mut wmkd_y_blocks);
+        let wmkd_image = embed_with(&host, &logo, &robust).unwrap();
+        let wmkd_image = embed_with(&wmkd_image, &gradient, &fragile).unwrap();
+        println!("PSNR: {}", metrics::psnr(&host, &wmkd_image));
 
-    // Extract the watermark from each block
-    let dithers = qim::generate_dither_signal(12, step_size, key);
-    let mut extracted_wm: BitVec<usize, Lsb0> = BitVec::new();
-    for block in wmkd_y_blocks.iter() {
-        let tmp = qim::extract_wm(block, &dithers, step_size);
-        for bit in tmp {
-            extracted_wm.push(bit);
+        for (config, wm) in [(&robust, &logo), (&fragile, &gradient)] {
+            let (bits, _) = extract_with(&wmkd_image, config, 128, 128).unwrap();
+            let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(wm), &bits).unwrap();
+            println!("BER: {}", ber);
+            assert!(ber < 0.02, "BER {}", ber);
         }
     }
 
-    // Reconstruct the image from bits and save the recovered watermark
-    let reconstructed_wm_image = color_recode::recode_to_rgb(&extracted_wm, 128, 128);
-    (extracted_wm, reconstructed_wm_image)
-}
+    #[test]
+    fn test_mask_conflict() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let mut usage = qim::CoefficientUsage::new();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use image::GenericImageView;
+        let low = EmbedConfig::new(1).mask(qim::CoefficientMask::preset(qim::Preset::LowFrequency));
+        let wmkd_image = embed_with_usage(&host, &wm, &low, &mut usage).unwrap();
 
-    const INPUT_DIR: &str = "/tmp/color_watermark/assets";
-    const OUTPUT_DIR: &str = "/tmp/color_watermark/output";
+        // The default mask shares position 18 with the low frequency one
+        let mid = EmbedConfig::new(2);
+        match embed_with_usage(&wmkd_image, &wm, &mid, &mut usage) {
+            Err(WatermarkError::MaskConflict { channel, block }) => {
+                assert_eq!(channel, Channel::Y);
+                assert_eq!(block, 0);
+            }
+            other => panic!("expected MaskConflict error, got {:?}", other.map(|_| ())),
+        }
+
+        // The failed embed marked nothing, disjoint masks and other planes are still free
+        let high_frequency: Vec<usize> = (30..42).collect();
+        let high = EmbedConfig::new(3).mask(qim::CoefficientMask::zigzag(&high_frequency).unwrap());
+        let wmkd_image = embed_with_usage(&wmkd_image, &wm, &high, &mut usage).unwrap();
+        let chroma = EmbedConfig::new(4).channels(&[Channel::Cb]);
+        embed_with_usage(&wmkd_image, &wm, &chroma, &mut usage).unwrap();
+
+        // Another block grid conflicts with any written block
+        let large = EmbedConfig::new(5)
+            .block_size(16)
+            .mask(qim::CoefficientMask::zigzag(&(50..62).collect::<Vec<_>>()).unwrap());
+        assert!(matches!(
+            embed_with_usage(&wmkd_image, &wm, &large, &mut usage),
+            Err(WatermarkError::MaskConflict { .. })
+        ));
+    }
 
     #[test]
-    fn test_3bit_recodification() {
-        let wm = image::open(format!("{}/wm_img1.png", INPUT_DIR)).unwrap();
-        let (width, height) = wm.dimensions();
+    fn test_embed_report() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
 
-        color_recode::recode_to_rgb(&color_recode::recode_to_3bits(&wm), width, height)
-            .save(format!("{}/wm_img1_recoded.png", OUTPUT_DIR))
-            .unwrap();
+        let (wmkd_image, report) =
+            embed_with_report(&host, &wm, &EmbedConfig::new(1).step_size(50.0)).unwrap();
+        assert_eq!(report.blocks_total, 64 * 64);
+        assert_eq!(report.blocks_used, 64 * 64 * 3 / 12);
+        assert_eq!(report.bits_embedded, report.blocks_used * 12);
+        assert_eq!(report.usage(), 0.25);
 
-        let wm = image::open(format!("{}/wm_img2.png", INPUT_DIR)).unwrap();
-        let (w, h) = wm.dimensions();
-        color_recode::recode_to_rgb(&color_recode::recode_to_3bits(&wm), w, h)
-            .save(format!("{}/wm_img2_recoded.png", OUTPUT_DIR))
-            .unwrap();
+        // Dithered QIM moves coefficients by a quarter of the step on average
+        assert!((report.mean_coefficient_delta - 12.5).abs() < 2.0);
+        assert!(report.estimated_psnr > metrics::psnr(&host, &wmkd_image));
+
+        // Once the embed dominates the rounding of the YCbCr round trip, the estimate is close
+        let (wmkd_image, report) =
+            embed_with_report(&host, &wm, &EmbedConfig::new(1).step_size(300.0)).unwrap();
+        let psnr = metrics::psnr(&host, &wmkd_image);
+        assert!(
+            (report.estimated_psnr - psnr).abs() < 0.5,
+            "{} vs {}",
+            report.estimated_psnr,
+            psnr
+        );
     }
 
     #[test]
-    fn test_2d_dct() {
-        let image_path = format!("{}/pepper.tiff", INPUT_DIR);
-        let image = image::open(image_path).unwrap();
-        let (width, height) = image.dimensions();
+    fn test_in_memory_interface() {
+        let key = 2143658709;
+        let step_size = 50.0;
 
-        let (mut blocks_r, mut blocks_g, mut blocks_b) = dct::split_image_into_blocks(&image);
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
 
-        dct::apply_2d_dct(&mut blocks_r);
-        dct::apply_2d_dct(&mut blocks_g);
-        dct::apply_2d_dct(&mut blocks_b);
+        let wmkd_image = embed_watermark_image(&host, &wm, key, step_size).unwrap();
+        assert_eq!(wmkd_image.dimensions(), host.dimensions());
 
-        let transformed_image =
-            dct::reconstruct_image_from_rgb(&blocks_r, &blocks_g, &blocks_b, width, height);
-        transformed_image
-            .save(format!("{}/pepper_2d_dct.png", OUTPUT_DIR))
-            .expect("Failed to save image");
+        let (extracted_bits, extracted_wm) =
+            extract_watermark_image(&wmkd_image, key, step_size, 128, 128).unwrap();
+        assert_eq!(
+            extracted_bits.len(),
+            color_recode::recode_to_3bits(&wm).len()
+        );
+        assert_eq!(extracted_wm.dimensions(), (128, 128));
+    }
 
-        dct::apply_2d_idct(&mut blocks_r);
-        dct::apply_2d_idct(&mut blocks_g);
-        dct::apply_2d_idct(&mut blocks_b);
+    #[test]
+    fn test_psnr() {
+        let image = synthetic_host(1);
+        assert_eq!(metrics::psnr(&image, &image), f64::INFINITY);
 
-        let unchanged_image =
-            dct::reconstruct_image_from_rgb(&blocks_r, &blocks_g, &blocks_b, width, height);
+        let mut flipped = image.to_rgb8();
+        let pixel = flipped.get_pixel_mut(10, 20);
+        pixel[0] = 255 - pixel[0];
+        let flipped = DynamicImage::ImageRgb8(flipped);
+        let psnr = metrics::psnr(&image, &flipped);
+        assert!(psnr.is_finite());
+        assert!(psnr > 50.0);
 
-        unchanged_image
-            .save(format!("{}/pepper_unchanged_dct.png", OUTPUT_DIR))
-            .expect("Failed to save unchanged_image");
+        let wm = synthetic_watermark(1);
+        let wmkd_image = embed_watermark_image(&image, &wm, 2143658709, 50.0).unwrap();
+        println!(
+            "PSNR at step_size 50: {:.2} dB",
+            metrics::psnr(&image, &wmkd_image)
+        );
     }
 
     #[test]
-    fn test_rgb_toforth_ycrcb() {
-        let image_path = format!("{}/pepper.tiff", INPUT_DIR);
-        let image = image::open(image_path).unwrap();
-        let (width, height) = image.dimensions();
+    fn test_bit_error_rate() {
+        let bits: BitVec = (0..1000).map(|i| i % 7 < 3).collect();
+        assert_eq!(metrics::bit_error_rate(&bits, &bits).unwrap(), 0.0);
+        assert_eq!(metrics::bit_error_rate(&bits, &!bits.clone()).unwrap(), 1.0);
+
+        let mut some_flipped = bits.clone();
+        for i in (0..1000).step_by(10) {
+            let bit = some_flipped[i];
+            some_flipped.set(i, !bit);
+        }
+        assert_eq!(metrics::bit_error_rate(&bits, &some_flipped).unwrap(), 0.1);
 
-        let (y_plane, cb_plane, cr_plane) = colorspace::convert_to_YCbCr(&image);
+        let shorter: BitVec = bits[..999].to_bitvec();
+        assert!(matches!(
+            metrics::bit_error_rate(&bits, &shorter),
+            Err(WatermarkError::LengthMismatch {
+                expected: 1000,
+                actual: 999
+            })
+        ));
+    }
+
+    #[test]
+    fn test_normalized_correlation() {
+        let wm = synthetic_watermark(1);
+        assert_eq!(metrics::normalized_correlation(&wm, &wm), 1.0);
+
+        let mut inverted = wm.clone();
+        inverted.invert();
+        assert_eq!(metrics::normalized_correlation(&wm, &inverted), -1.0);
+
+        let black = DynamicImage::new_rgb8(128, 128);
+        let nc = metrics::normalized_correlation(&wm, &black);
+        assert!((-1.0..1.0).contains(&nc));
+    }
+
+    #[test]
+    fn test_embed_config() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
 
-        let rgb_img = colorspace::convert_to_RGB(
-            width,
-            height,
-            y_plane.as_slice(),
-            cb_plane.as_slice(),
-            cr_plane.as_slice(),
+        // The plain functions are the default config
+        let config = EmbedConfig::new(2143658709).step_size(80.0);
+        assert_eq!(
+            embed_with(&host, &wm, &config).unwrap(),
+            embed_watermark_image(&host, &wm, 2143658709, 80.0).unwrap()
         );
 
-        rgb_img
-            .save(format!("{}/pepper_unchanged_color.png", OUTPUT_DIR))
-            .unwrap();
+        for config in [
+            config.clone(),
+            config.clone().step_size(100.0).channel(Channel::Cb),
+            config
+                .clone()
+                .channel(Channel::Cr)
+                .mask(qim::CoefficientMask::preset(qim::Preset::LowFrequency)),
+        ] {
+            let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+            let (extracted_bits, _) = extract_with(&wmkd_image, &config, 128, 128).unwrap();
+            let ber = metrics::bit_error_rate(&wm_bits, &extracted_bits).unwrap();
+            println!("{:?}: bit error rate {}", config.channels, ber);
+            assert!(ber < 0.02);
+        }
     }
 
     #[test]
-    fn test_complete_workflow() {
-        let key = 0123456_u64;
-        let step_size = 100.0;
+    fn test_multiple_channels() {
+        let host = synthetic_host(1);
+        let wm =
+            synthetic_watermark(1).resize_exact(160, 128, image::imageops::FilterType::Nearest);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
 
-        // *********** Embedding the watermark **********
-        let image_path = format!("{}/pepper.tiff", INPUT_DIR);
-        let image = image::open(image_path).unwrap();
-        let (width, height) = image.dimensions();
+        // 160 * 128 * 3 bits are more than a single 512 * 512 plane holds
+        let config = EmbedConfig::new(2143658709).step_size(80.0);
+        assert!(matches!(
+            embed_with(&host, &wm, &config),
+            Err(WatermarkError::Capacity { .. })
+        ));
 
-        // Convert the image to YCbCr colorspace
-        let (mut y_plane, cb_plane, cr_plane) = colorspace::convert_to_YCbCr(&image);
+        // A config without any plane is rejected up front
+        assert!(matches!(
+            embed_with(&host, &wm, &config.clone().channels(&[])),
+            Err(WatermarkError::NoChannels)
+        ));
 
-        // Split Y plane into 8 * 8 blocks for DCT operation
-        let mut y_blocks = dct::split_into_blocks(&mut y_plane, width as usize, height as usize);
+        let config = config.channels(&[Channel::Cb, Channel::Y]);
+        assert_eq!(config.channels, vec![Channel::Y, Channel::Cb]);
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (extracted_bits, _) = extract_with(&wmkd_image, &config, 160, 128).unwrap();
+        assert!(metrics::bit_error_rate(&wm_bits, &extracted_bits).unwrap() < 0.02);
+    }
 
-        // DCT on Y blocks
-        dct::apply_2d_dct(&mut y_blocks);
+    #[test]
+    fn test_repeat_redundancy() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
 
-        // Load the watermark image
-        let wm_path = format!("{}/wm_img1.png", INPUT_DIR);
-        let wm_image = image::open(wm_path).unwrap();
-        // Recoding the watermark
-        let wm_bits = color_recode::recode_to_3bits(&wm_image);
+        let plain = EmbedConfig::new(2143658709).step_size(100.0);
+        let repeated = plain.clone().redundancy(Redundancy::Repeat(3));
 
-        // QIM-DM to embed the watermark with the preset key and step_size
-        let dithers = qim::generate_dither_signal(12, step_size, key);
-        for (i, bits) in wm_bits.chunks(12).enumerate() {
-            qim::embed_wm(&mut y_blocks[i], &bits.to_bitvec(), &dithers, step_size);
+        // Wipe out the top quarter of the image, i.e. a quarter of the blocks
+        let damage = |image: &DynamicImage| {
+            let mut rgb = image.to_rgb8();
+            for y in 0..128 {
+                for x in 0..512 {
+                    rgb.put_pixel(x, y, image::Rgb([0, 0, 0]));
+                }
+            }
+            DynamicImage::ImageRgb8(rgb)
+        };
+
+        let damaged = damage(&embed_with(&host, &wm, &plain).unwrap());
+        let (extracted_bits, _) = extract_with(&damaged, &plain, 64, 64).unwrap();
+        let plain_ber = metrics::bit_error_rate(&wm_bits, &extracted_bits).unwrap();
+
+        let damaged = damage(&embed_with(&host, &wm, &repeated).unwrap());
+        let (extracted_bits, _) = extract_with(&damaged, &repeated, 64, 64).unwrap();
+        let repeated_ber = metrics::bit_error_rate(&wm_bits, &extracted_bits).unwrap();
+
+        println!("BER plain: {}, repeated: {}", plain_ber, repeated_ber);
+        assert!(plain_ber > 0.2);
+        assert!(repeated_ber < 0.02);
+    }
+
+    #[cfg(feature = "ecc")]
+    #[test]
+    fn test_hamming_ecc() {
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+
+        for ecc in [Ecc::Hamming7_4, Ecc::Hamming15_11, Ecc::Hamming31_26] {
+            let mut encoded = ecc.encode(&wm_bits);
+            assert_eq!(encoded.len(), ecc.encoded_len(wm_bits.len()));
+            assert_eq!(ecc.decode(&encoded, wm_bits.len()), wm_bits);
+
+            // Flip a few percent of the embedded bits, codewords are interleaved so neighbouring
+            // positions belong to different codewords
+            let mut plain = wm_bits.clone();
+            for i in (0..encoded.len()).step_by(40).take(plain.len() / 40) {
+                let flipped = !encoded[i];
+                encoded.set(i, flipped);
+                let flipped = !plain[i];
+                plain.set(i, flipped);
+            }
+            assert_eq!(ecc.decode(&encoded, wm_bits.len()), wm_bits);
+            assert!(metrics::bit_error_rate(&wm_bits, &plain).unwrap() > 0.02);
         }
 
-        // In between embedding result test
-        let mut extracted_wm: BitVec<usize, Lsb0> = BitVec::new();
-        for block in y_blocks.iter() {
-            let tmp = qim::extract_wm(&block, &dithers, step_size);
-            for bit in tmp {
-                extracted_wm.push(bit);
+        // The in-memory round trip flips a handful of bits, which the code corrects
+        let host = synthetic_host(1);
+        let wm = wm.resize_exact(96, 96, image::imageops::FilterType::Nearest);
+        let config = EmbedConfig::new(2143658709)
+            .step_size(150.0)
+            .ecc(Ecc::Hamming7_4);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let (extracted_bits, _) = extract_with(&watermarked, &config, 96, 96).unwrap();
+        assert_eq!(extracted_bits, color_recode::recode_to_3bits(&wm));
+    }
+
+    #[test]
+    fn test_self_describing_header() {
+        let config = EmbedConfig::new(2143658709).step_size(60.0).header(true);
+
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1).crop_imm(10, 20, 96, 72);
+
+        let wmkd_image = embed_with(&host, &wm, &config).unwrap();
+        let (extracted_bits, extracted_wm) = extract_with_header(&wmkd_image, &config).unwrap();
+        assert_eq!(extracted_wm.dimensions(), (96, 72));
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        assert!(metrics::bit_error_rate(&wm_bits, &extracted_bits).unwrap() < 0.02);
+
+        // Passing the size explicitly skips over the header
+        let (bits_with_size, _) = extract_with(&wmkd_image, &config, 96, 72).unwrap();
+        assert_eq!(bits_with_size, extracted_bits);
+
+        // The header takes a few blocks, so a watermark filling the whole host no longer fits
+        let full_wm = synthetic_watermark(1);
+        assert!(matches!(
+            embed_with(&host, &full_wm, &config),
+            Err(WatermarkError::Capacity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bytes_interface() {
+        let host = encode_to_bytes(&synthetic_host(1), ImageFormat::Tiff).unwrap();
+        let wm = encode_to_bytes(&synthetic_watermark(1), ImageFormat::Png).unwrap();
+        let key = 2143658709;
+        let step_size = 50.0;
+
+        let watermarked =
+            embed_watermark_bytes(&host, &wm, key, step_size, ImageFormat::Png).unwrap();
+        assert_eq!(image::guess_format(&watermarked).unwrap(), ImageFormat::Png);
+
+        let extracted =
+            extract_watermark_bytes(&watermarked, key, step_size, 128, 128, ImageFormat::Png)
+                .unwrap();
+        let extracted = image::load_from_memory(&extracted).unwrap();
+        let original = image::load_from_memory(&wm).unwrap();
+        assert!(metrics::normalized_correlation(&original, &extracted) > 0.95);
+
+        assert!(matches!(
+            embed_watermark_bytes(&host, &wm, key, step_size, ImageFormat::Jpeg),
+            Err(WatermarkError::LossyFormat(ImageFormat::Jpeg))
+        ));
+        assert!(matches!(
+            embed_watermark_bytes(&[1, 2, 3], &wm, key, step_size, ImageFormat::Png),
+            Err(WatermarkError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_format_watermark_safe() {
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::Tiff,
+            ImageFormat::Bmp,
+            ImageFormat::Tga,
+            ImageFormat::Pnm,
+            ImageFormat::Qoi,
+            ImageFormat::Farbfeld,
+            ImageFormat::WebP,
+        ] {
+            assert!(is_format_watermark_safe(format), "{:?}", format);
+        }
+        for format in [
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Avif,
+            ImageFormat::Hdr,
+            ImageFormat::OpenExr,
+            ImageFormat::Ico,
+            ImageFormat::Dds,
+        ] {
+            assert!(!is_format_watermark_safe(format), "{:?}", format);
+        }
+
+        let image = DynamicImage::new_rgb8(8, 8);
+        assert!(matches!(
+            save_watermarked(&image, output_path("lossy.jpg")),
+            Err(WatermarkError::LossyFormat(ImageFormat::Jpeg))
+        ));
+        save_watermarked(&image, output_path("lossless.png")).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_visible() {
+        let host = synthetic_host(1);
+        let logo = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            64,
+            32,
+            image::Rgb([255, 255, 255]),
+        ));
+
+        let stamped = overlay::overlay_visible(&host, &logo, overlay::Position::BottomRight, 0.5);
+        assert_eq!(stamped.color(), host.color());
+        let (original, stamped) = (host.to_rgb8(), stamped.to_rgb8());
+        let mut changed = 0;
+        for (x, y, pixel) in stamped.enumerate_pixels() {
+            let before = original.get_pixel(x, y);
+            if x >= 512 - 64 && y >= 512 - 32 {
+                // Halfway to white
+                for (&b, &a) in before.0.iter().zip(&pixel.0) {
+                    assert_eq!(a, ((b as f32 + 255.0) / 2.0).round() as u8);
+                }
+                changed += (pixel != before) as usize;
+            } else {
+                assert_eq!(pixel, before, "({}, {})", x, y);
             }
         }
-        color_recode::recode_to_rgb(&extracted_wm, 128, 128)
-            .save(format!("{}/in_between.png", OUTPUT_DIR))
-            .unwrap();
+        assert!(changed > 64 * 32 * 9 / 10);
 
-        // IDCT on watermarked Y blocks
-        dct::apply_2d_idct(&mut y_blocks);
+        // The invisible watermark goes on top of the visible one
+        let wm = synthetic_watermark(1);
+        let stamped = DynamicImage::ImageRgb8(stamped);
+        let watermarked = embed_watermark_image(&stamped, &wm, 5, 100.0).unwrap();
+        let (bits, _) = extract_watermark_image(&watermarked, 5, 100.0, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert_eq!(ber, 0.0);
+    }
 
-        // Convert Y blocks back to Y plane
-        let watermarked_y_plane = dct::merge_into_plane(&y_blocks, width as usize, height as usize);
+    #[test]
+    fn test_hsv_domain() {
+        let host = synthetic_host(1);
+        let planes = colorspace::convert_to_HSV(&host);
+        assert_eq!(
+            colorspace::convert_HSV_to_RGB(&planes).to_rgb8(),
+            host.to_rgb8()
+        );
+        let gray = image::Rgb([90, 90, 90]);
+        let orange = image::Rgb([200, 100, 0]);
+        let pixels = image::RgbImage::from_fn(2, 1, |x, _| if x == 0 { gray } else { orange });
+        let planes = colorspace::convert_to_HSV(&pixels);
+        assert_eq!((planes.h[1], planes.s[1], planes.v[1]), (30.0, 1.0, 200));
+        assert_eq!((planes.s[0], planes.v[0]), (0.0, 90));
 
-        // Convert back to RGB colorspace
-        let wmd_image =
-            colorspace::convert_to_RGB(width, height, &watermarked_y_plane, &cb_plane, &cr_plane);
+        let wm = synthetic_watermark(1);
+        let config = EmbedConfig::new(5)
+            .step_size(50.0)
+            .color_domain(ColorDomain::Hsv);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        assert!(metrics::psnr(&host, &watermarked) > 35.0);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        let bits = extract_bits_with(&watermarked, &config, 128, 128).unwrap();
+        assert_eq!(metrics::bit_error_rate(&wm_bits, &bits).unwrap(), 0.0);
 
-        // Save the watermarked image
-        wmd_image
-            .save(format!("{}/watermarked_img.png", OUTPUT_DIR))
-            .unwrap();
+        // Doubling the saturation keeps V, and the watermark
+        let saturated = image::RgbImage::from_fn(512, 512, |x, y| {
+            let pixel = watermarked.get_pixel(x, y);
+            let max = *pixel.0[..3].iter().max().unwrap() as f32;
+            image::Rgb([0, 1, 2].map(|c| (max - 2.0 * (max - pixel[c] as f32)).max(0.0) as u8))
+        });
+        let bits = extract_bits_with(&saturated.into(), &config, 128, 128).unwrap();
+        assert_eq!(metrics::bit_error_rate(&wm_bits, &bits).unwrap(), 0.0);
 
-        // ************ Extracting the watermark ***************
-        let wmkd_image = image::open(format!("{}/watermarked_img.png", OUTPUT_DIR)).unwrap();
-        let (width, height) = wmkd_image.dimensions();
+        assert!(matches!(
+            embed_with(&host, &wm, &config.channels(&[Channel::Y, Channel::Cb])),
+            Err(WatermarkError::HsvChannels)
+        ));
+    }
 
-        // Convert the watermarked image to YCbCr colorspace and DCT on Y blocks
-        let (mut wmkd_y_plane, _, _) = colorspace::convert_to_YCbCr(&wmkd_image);
+    #[test]
+    fn test_embed_directory() {
+        let root = std::env::temp_dir().join(format!("embed_directory_{}", std::process::id()));
+        let (in_dir, out_dir) = (root.join("in"), root.join("out"));
+        std::fs::create_dir_all(in_dir.join("nested.png")).unwrap();
+        let host = synthetic_host(1);
+        host.save(in_dir.join("b.png")).unwrap();
+        host.save(in_dir.join("a.bmp")).unwrap();
+        host.save(in_dir.join("c.jpg")).unwrap();
+        std::fs::write(in_dir.join("notes.txt"), "not an image").unwrap();
+        std::fs::write(in_dir.join("broken.png"), "not a png").unwrap();
 
-        let mut wmkd_y_blocks =
-            dct::split_into_blocks(&mut wmkd_y_plane, width as usize, height as usize);
+        let wm = synthetic_watermark(1);
+        // Rounding the planes to 8-bit samples moves the unscaled DCT coefficients by a few units, past the
+        // quarter of a 50 step in one of some 8000 bits here, while a 100 step leaves 25 units of margin
+        let results = embed_directory(&in_dir, &out_dir, &wm, 9, 100.0).unwrap();
+        let names: Vec<_> = results
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["a.bmp", "b.png", "broken.png", "c.jpg"]);
+
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        for (path, result) in &results[..2] {
+            let out_path = result.as_ref().unwrap();
+            assert_eq!(out_path, &out_dir.join(path.file_name().unwrap()));
+            let (bits, _) =
+                extract_watermark(out_path.to_str().unwrap(), 9, 100.0, 128, 128).unwrap();
+            assert_eq!(metrics::bit_error_rate(&wm_bits, &bits).unwrap(), 0.0);
+        }
+        assert!(matches!(results[2].1, Err(WatermarkError::Decode(_))));
+        assert!(matches!(
+            results[3].1,
+            Err(WatermarkError::LossyFormat(ImageFormat::Jpeg))
+        ));
+        assert!(!out_dir.join("c.jpg").exists());
 
-        dct::apply_2d_dct(&mut wmkd_y_blocks);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-        // Extract the watermark from each block
-        let mut extracted_wm: BitVec<usize, Lsb0> = BitVec::new();
-        for block in wmkd_y_blocks.iter() {
-            let tmp = qim::extract_wm(&block, &dithers, step_size);
-            for bit in tmp {
-                extracted_wm.push(bit);
+    #[test]
+    fn test_min_block_energy() {
+        let color = synthetic_host(2);
+        // Grayscale, the rounding of the YCbCr round trip would move the energy of the skipped blocks. The
+        // blocks under 7 are flattened, as rounding the embedded samples moves the energy of the carriers by
+        // up to some 0.6, and blocks right at the threshold would be judged differently once watermarked
+        let mut gray = color.to_luma8();
+        let textured = carrier_blocks(
+            &DynamicImage::ImageLuma8(gray.clone()),
+            &EmbedConfig::new(3).min_block_energy(7.0),
+        )
+        .unwrap();
+        for i in (0..64 * 64).filter(|i| !textured.contains(i)) {
+            let (x0, y0) = (i as u32 % 64 * 8, i as u32 / 64 * 8);
+            let pixels: Vec<(u32, u32)> = (y0..y0 + 8)
+                .flat_map(|y| (x0..x0 + 8).map(move |x| (x, y)))
+                .collect();
+            let sum: u32 = pixels
+                .iter()
+                .map(|&(x, y)| gray.get_pixel(x, y).0[0] as u32)
+                .sum();
+            for &(x, y) in &pixels {
+                gray.put_pixel(x, y, image::Luma([(sum / 64) as u8]));
             }
         }
+        let host = DynamicImage::ImageLuma8(gray);
+        let wm = synthetic_watermark(1).resize_exact(64, 64, image::imageops::FilterType::Nearest);
+        let config = EmbedConfig::new(3).step_size(50.0).min_block_energy(3.5);
 
-        // Reconstruct the image from bits and save the recovered watermark
-        let reconstructed_wm = color_recode::recode_to_rgb(&extracted_wm, 128, 128);
-        reconstructed_wm
-            .save(format!("{}/reconstructed_wm.png", OUTPUT_DIR))
-            .unwrap();
+        let carriers = carrier_blocks(&host, &config).unwrap();
+        let all = carrier_blocks(&host, &EmbedConfig::new(3)).unwrap();
+        assert!(carriers.len() > 64 * 64 * 3 / 12 && carriers.len() < all.len());
+
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        assert_eq!(carrier_blocks(&watermarked, &config).unwrap(), carriers);
+        let bits = extract_bits_with(&watermarked, &config, 64, 64).unwrap();
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        assert_eq!(metrics::bit_error_rate(&wm_bits, &bits).unwrap(), 0.0);
+
+        // Skipped blocks among the used ones are left untouched
+        let used = &carriers[..64 * 64 * 3 / 12];
+        let last = *used.iter().max().unwrap();
+        let (before, after) = (host.to_luma8(), watermarked.to_luma8());
+        let block_diff = |i: usize| {
+            let (x0, y0) = (i as u32 % 64 * 8, i as u32 / 64 * 8);
+            let pixels = (y0..y0 + 8).flat_map(|y| (x0..x0 + 8).map(move |x| (x, y)));
+            pixels
+                .map(|(x, y)| before.get_pixel(x, y).0[0].abs_diff(after.get_pixel(x, y).0[0]))
+                .max()
+                .unwrap()
+        };
+        for i in (0..last).filter(|i| !carriers.contains(i)) {
+            assert_eq!(block_diff(i), 0, "block {}", i);
+        }
+        assert!(used.iter().any(|&i| block_diff(i) > 2));
+
+        // A watermark fitting the full blocks but not the carriers
+        let side = ((all.len() * 4) as f64).sqrt() as u32;
+        assert!((side * side) as usize > carriers.len() * 4);
+        assert!(matches!(
+            extract_bits_with(&watermarked, &config, side, side),
+            Err(WatermarkError::Capacity { .. })
+        ));
+        let big = synthetic_watermark(1);
+        assert!(matches!(
+            embed_with(&host, &big, &config),
+            Err(WatermarkError::Capacity { .. })
+        ));
+        assert!(matches!(
+            carrier_blocks(&host, &config.clone().channels(&[Channel::Y, Channel::Cr])),
+            Err(WatermarkError::EnergyChannels)
+        ));
+        assert!(matches!(
+            carrier_blocks(&color, &config),
+            Err(WatermarkError::EnergyColorHost)
+        ));
+        assert!(matches!(
+            embed_with(&color, &wm, &config),
+            Err(WatermarkError::EnergyColorHost)
+        ));
+        assert!(matches!(
+            extract_bits_with(&color, &config, 64, 64),
+            Err(WatermarkError::EnergyColorHost)
+        ));
+    }
+
+    #[test]
+    fn test_huge_host_dimensions() {
+        // 70000 * 70000 * 3 wraps around in u32 arithmetic, and u32::MAX squared in 64-bit usize
+        let (width, height) = (70000_u32, 70000_u32);
+        assert!(width.checked_mul(height).is_none());
+        assert!(matches!(
+            check_host_dimensions(u32::MAX, u32::MAX, 8),
+            Err(WatermarkError::HostTooLarge {
+                actual: (u32::MAX, u32::MAX),
+                max_pixels: MAX_HOST_PIXELS
+            })
+        ));
+        // 70000 * 70000 pixels are over the limit of 32-bit targets only
+        let fits = width as u64 * height as u64 <= MAX_HOST_PIXELS;
+        assert_eq!(check_host_dimensions(width, height, 8).is_ok(), fits);
+        assert_eq!(fits, usize::BITS == 64);
+
+        let report = capacity_report((width, height), (128, 128), &EmbedConfig::new(1));
+        assert_eq!(report.total_bits, if fits { 8750 * 8750 * 12 } else { 0 });
+        assert_eq!(report.fits, fits);
+        let report = capacity_report((u32::MAX, u32::MAX), (128, 128), &EmbedConfig::new(1));
+        assert_eq!(report.total_bits, 0);
+        assert!(!report.fits);
+    }
+
+    #[test]
+    fn test_limited_range() {
+        // TV range content, with blacks at 16 and whites at 235
+        let host = synthetic_host(1);
+        let mut tv = host.to_rgb8();
+        for sample in tv.iter_mut() {
+            *sample = (16.0 + *sample as f32 * 219.0 / 255.0).round() as u8;
+        }
+        let host = DynamicImage::ImageRgb8(tv);
+        // The YCbCr round trip rounds every sample of the planes, which moves the unscaled DCT coefficients
+        // past the quarter of a 50 step in one of some 50000 bits, while a 100 step leaves 25 units of margin
+        let config = EmbedConfig::new(12)
+            .step_size(100.0)
+            .yuv_range(colorspace::YuvRange::Limited);
+        assert_eq!(config.color.range, colorspace::YuvRange::Limited);
+
+        let wm = synthetic_watermark(1);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let planes = colorspace::convert_to_YCbCr_with(&watermarked, &config.color);
+        assert!(planes.y.iter().all(|y| (16..=235).contains(y)));
+        // Nothing was pushed into the clipping of the RGB conversion
+        assert!(watermarked.to_rgb8().iter().all(|&c| c != 0 && c != 255));
+        let bits = extract_bits_with(&watermarked, &config, 128, 128).unwrap();
+        let ber = metrics::bit_error_rate(&color_recode::recode_to_3bits(&wm), &bits).unwrap();
+        assert_eq!(ber, 0.0);
+
+        // Scaling the step to the 219 luma levels gives the distortion of full range
+        let full = embed_with(&host, &wm, &EmbedConfig::new(12).step_size(50.0)).unwrap();
+        let scaled = config.step_size(50.0 * 219.0 / 255.0);
+        let scaled = embed_with(&host, &wm, &scaled).unwrap();
+        let (full, scaled) = (metrics::psnr(&host, &full), metrics::psnr(&host, &scaled));
+        assert!((full - scaled).abs() < 0.5, "{} {}", full, scaled);
+        assert!(metrics::psnr(&host, &watermarked) < scaled);
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        let config = EmbedConfig::new(5).step_size(100.0);
+        let hosts = [
+            ("rgb8", host.clone()),
+            ("rgba8", DynamicImage::ImageRgba8(host.to_rgba8())),
+            ("rgb16", DynamicImage::ImageRgb16(host.to_rgb16())),
+        ];
+
+        for (name, host) in hosts {
+            let watermarked = embed_with(&host, &wm, &config).unwrap();
+            for extension in ["tiff", "png", "bmp"] {
+                let path = output_path(&format!("container_{}.{}", name, extension));
+                let saved = save_watermarked(&watermarked, &path);
+                if name == "rgb16" && extension == "bmp" {
+                    // The image crate would quietly save 8 bit samples
+                    assert!(
+                        matches!(saved, Err(WatermarkError::SampleDepth { .. })),
+                        "{:?}",
+                        saved
+                    );
+                    continue;
+                }
+                saved.unwrap();
+
+                let loaded = image::open(&path).unwrap();
+                assert_eq!(loaded.color(), watermarked.color(), "{}", path);
+                let bits = extract_bits_with(&loaded, &config, 128, 128).unwrap();
+                assert_eq!(
+                    metrics::bit_error_rate(&wm_bits, &bits).unwrap(),
+                    0.0,
+                    "{}",
+                    path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_quant_table() {
+        assert_eq!(dct::QuantTable::default().values()[..4], [16, 11, 10, 16]);
+        assert!(dct::QuantTable::luma(100).values().iter().all(|&v| v == 1));
+        assert!(matches!(
+            dct::QuantTable::new(&[1; 63]),
+            Err(WatermarkError::InvalidQuantTable(_))
+        ));
+        let mut zero = [1; 64];
+        zero[10] = 0;
+        assert!(matches!(
+            dct::QuantTable::new(&zero),
+            Err(WatermarkError::InvalidQuantTable(_))
+        ));
+
+        let host = synthetic_host(2);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        let hadamard = EmbedConfig::new(3)
+            .transform(Transform::Hadamard)
+            .quant_table(dct::QuantTable::default());
+        assert!(matches!(
+            embed_with(&host, &wm, &hadamard),
+            Err(WatermarkError::InvalidQuantTable(_))
+        ));
+
+        // Quality 50 compresses with the standard table itself. At about the same PSNR,
+        // steps of 2 quantization steps survive it far better than a uniform step
+        let aligned = EmbedConfig::new(3)
+            .step_size(2.0)
+            .quant_table(dct::QuantTable::default());
+        let uniform = EmbedConfig::new(3).step_size(420.0);
+        let mut results = Vec::new();
+        for config in [&aligned, &uniform] {
+            let watermarked = embed_with(&host, &wm, config).unwrap();
+            let compressed = attack::simulate_jpeg(&watermarked, 50);
+            let (bits, _) = extract_with(&compressed, config, 128, 128).unwrap();
+            let ber = metrics::bit_error_rate(&wm_bits, &bits).unwrap();
+            let psnr = metrics::psnr(&host, &watermarked);
+            println!("BER: {}, PSNR: {}", ber, psnr);
+            results.push((ber, psnr));
+        }
+        let ((aligned_ber, aligned_psnr), (uniform_ber, uniform_psnr)) = (results[0], results[1]);
+        assert!(aligned_psnr > uniform_psnr - 0.5);
+        assert!(aligned_ber < 0.05, "BER {}", aligned_ber);
+        assert!(uniform_ber > 2.0 * aligned_ber, "BER {}", uniform_ber);
+    }
+
+    #[test]
+    fn test_jpeg_step_table() {
+        let host = synthetic_host(2);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+
+        // Keep in sync with the table documented on attack::simulate_jpeg
+        println!("| JPEG quality | minimum step size | BER | PSNR |");
+        for (quality, step_size) in [
+            (95, 100.0),
+            (90, 150.0),
+            (80, 300.0),
+            (70, 400.0),
+            (50, 700.0),
+        ] {
+            let config = EmbedConfig::new(2143658709).step_size(step_size);
+            let watermarked = embed_with(&host, &wm, &config).unwrap();
+            let compressed = attack::simulate_jpeg(&watermarked, quality);
+            let (bits, _) = extract_with(&compressed, &config, 128, 128).unwrap();
+            let ber = metrics::bit_error_rate(&wm_bits, &bits).unwrap();
+            let psnr = metrics::psnr(&host, &watermarked);
+            println!(
+                "| {} | {} | {:.2}% | {:.1} dB |",
+                quality,
+                step_size,
+                ber * 100.0,
+                psnr
+            );
+            assert!(ber < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_geometric_attacks() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+        let wm_bits = color_recode::recode_to_3bits(&wm);
+        let config = EmbedConfig::new(2143658709).step_size(100.0);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let ber = |image: &DynamicImage| {
+            let (bits, _) = extract_with(image, &config, 128, 128).unwrap();
+            metrics::bit_error_rate(&wm_bits, &bits).unwrap()
+        };
+
+        let full_frame = image::math::Rect {
+            x: 0,
+            y: 0,
+            width: 512,
+            height: 512,
+        };
+        let identity = attack::crop_and_pad(&watermarked, full_frame);
+        assert_eq!(identity, watermarked);
+        assert_eq!(ber(&identity), 0.0);
+
+        // Cropping off a few columns shifts the block grid
+        let shifted = attack::crop_and_pad(
+            &watermarked,
+            image::math::Rect {
+                x: 3,
+                y: 0,
+                width: 509,
+                height: 512,
+            },
+        );
+        assert_eq!(shifted.dimensions(), (512, 512));
+        let shifted_ber = ber(&shifted);
+
+        let rescaled = attack::rescale(&watermarked, 0.5);
+        assert_eq!(rescaled.dimensions(), (512, 512));
+        let rescaled_ber = ber(&rescaled);
+
+        println!(
+            "BER after shifting crop: {}, after rescaling: {}",
+            shifted_ber, rescaled_ber
+        );
+        assert!(shifted_ber > 0.2);
+        assert!(rescaled_ber > 0.0);
+    }
+
+    #[test]
+    fn test_grayscale_watermark() {
+        let host = synthetic_host(1);
+        let logo = DynamicImage::ImageLuma8(synthetic_watermark(2).to_luma8());
+        assert_eq!(WatermarkKind::detect(&logo), WatermarkKind::Gray);
+        let logo_bits = color_recode::recode_gray_to_bits(&logo);
+        assert_eq!(logo_bits.len(), 128 * 128);
+
+        let reconstructed = color_recode::recode_bits_to_gray(&logo_bits, 128, 128);
+        assert_eq!(reconstructed.color(), image::ColorType::L8);
+        assert_eq!(color_recode::recode_gray_to_bits(&reconstructed), logo_bits);
+
+        // The kind is detected when embedding and read back from the header
+        let config = EmbedConfig::new(2143658709).header(true);
+        let watermarked = embed_with(&host, &logo, &config).unwrap();
+        let (bits, extracted) = extract_with_header(&watermarked, &config).unwrap();
+        assert_eq!(extracted.color(), image::ColorType::L8);
+        assert_eq!(extracted.dimensions(), (128, 128));
+        assert!(metrics::bit_error_rate(&logo_bits, &bits).unwrap() < 0.02);
+
+        // Without a header the kind must be given
+        let config = EmbedConfig::new(2143658709).watermark_kind(WatermarkKind::Gray);
+        let watermarked = embed_with(&host, &logo, &config).unwrap();
+        let (bits, _) = extract_with(&watermarked, &config, 128, 128).unwrap();
+        assert!(metrics::bit_error_rate(&logo_bits, &bits).unwrap() < 0.02);
+    }
+
+    #[test]
+    fn test_bits_per_channel() {
+        let gradient = synthetic_watermark(2);
+        assert_eq!(
+            color_recode::recode(&gradient, 1),
+            color_recode::recode_to_3bits(&gradient)
+        );
+
+        let distance = |bits_per_channel: u8| {
+            let bits = color_recode::recode(&gradient, bits_per_channel);
+            assert_eq!(bits.len(), 128 * 128 * 3 * bits_per_channel as usize);
+            let reconstructed = color_recode::recode_to_rgb_with(&bits, 128, 128, bits_per_channel);
+            metrics::psnr(&gradient, &reconstructed)
+        };
+        println!("PSNR 1 bit: {}, 2 bits: {}", distance(1), distance(2));
+        assert!(distance(2) > distance(1) + 3.0);
+        assert!(distance(8).is_infinite());
+
+        // 2 bits per channel of a 64 * 64 watermark fit into a 512 * 512 host, described by the header
+        let host = synthetic_host(1);
+        let wm = gradient.resize_exact(64, 64, image::imageops::FilterType::Triangle);
+        let config = EmbedConfig::new(2143658709)
+            .bits_per_channel(2)
+            .header(true);
+        let watermarked = embed_with(&host, &wm, &config).unwrap();
+        let (bits, extracted) =
+            extract_with_header(&watermarked, &EmbedConfig::new(2143658709).header(true)).unwrap();
+        assert_eq!(extracted.dimensions(), (64, 64));
+        assert!(metrics::bit_error_rate(&color_recode::recode(&wm, 2), &bits).unwrap() < 0.02);
+
+        assert!(matches!(
+            embed_with(&host, &wm, &config.bits_per_channel(9)),
+            Err(WatermarkError::BitsPerChannel(9))
+        ));
+    }
+
+    #[test]
+    fn test_text_payload() {
+        let host = synthetic_host(1);
+        let text =
+            "© 2024 color_watermark\nAll rights reserved.\n\t550e8400-e29b-41d4-a716-446655440000";
+
+        let watermarked = embed_text(&host, text, 2143658709, 100.0).unwrap();
+        assert_eq!(extract_text(&watermarked, 2143658709, 100.0).unwrap(), text);
+
+        let config = EmbedConfig::new(2143658709).redundancy(Redundancy::Repeat(3));
+        let watermarked = embed_text_with(&host, text, &config).unwrap();
+        assert_eq!(extract_text_with(&watermarked, &config).unwrap(), text);
+
+        let small_host = DynamicImage::new_rgb8(16, 16);
+        assert!(matches!(
+            embed_text(&small_host, text, 2143658709, 100.0),
+            Err(WatermarkError::Capacity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_capacity_bytes() {
+        let host = synthetic_host(1);
+        // A single flipped bit garbles the text. The rounding of the YCbCr round trip moves the unscaled DCT
+        // coefficients past the quarter of a 50 step in a few bits, a 100 step leaves 25 units of margin, and
+        // the 4 levels put their lattices a quarter step apart, so their step doubles for the same margin
+        #[allow(unused_mut)]
+        let mut configs = vec![
+            EmbedConfig::new(7).step_size(100.0),
+            EmbedConfig::new(7)
+                .step_size(100.0)
+                .redundancy(Redundancy::Repeat(3))
+                .authenticate(true),
+            EmbedConfig::new(7)
+                .channels(&[Channel::Y, Channel::Cb, Channel::Cr])
+                .levels(4)
+                .step_size(200.0),
+        ];
+        #[cfg(feature = "ecc")]
+        configs.push(
+            EmbedConfig::new(7)
+                .step_size(100.0)
+                .ecc(Ecc::Hamming15_11)
+                .authenticate(true),
+        );
+
+        for config in configs {
+            let bytes = capacity_bytes(host.dimensions(), &config);
+            let text = "x".repeat(bytes);
+            let watermarked = embed_text_with(&host, &text, &config).unwrap();
+            assert_eq!(extract_text_with(&watermarked, &config).unwrap(), text);
+            assert!(matches!(
+                embed_text_with(&host, &"x".repeat(bytes + 1), &config),
+                Err(WatermarkError::Capacity { .. })
+            ));
+        }
+        // The length takes 3 of the 4 blocks, then nothing fits
+        assert_eq!(capacity_bytes((16, 16), &EmbedConfig::new(7)), 1);
+        assert_eq!(capacity_bytes((8, 16), &EmbedConfig::new(7)), 0);
+    }
+
+    #[test]
+    fn test_alpha_preserved() {
+        let key = 2143658709;
+        let step_size = 50.0;
+
+        let host = synthetic_host(1);
+        let mut rgba = host.to_rgba8();
+        for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+            pixel[3] = ((x * 3 + y * 5) % 256) as u8;
+        }
+        let host = DynamicImage::ImageRgba8(rgba);
+        let wm = synthetic_watermark(1);
+
+        let wmkd_image = embed_watermark_image(&host, &wm, key, step_size).unwrap();
+        assert!(wmkd_image.color().has_alpha());
+        assert_eq!(
+            colorspace::split_alpha(&wmkd_image),
+            colorspace::split_alpha(&host)
+        );
+
+        // Opaque hosts stay RGB
+        let rgb_host = DynamicImage::ImageRgb8(host.to_rgb8());
+        let wmkd_image = embed_watermark_image(&rgb_host, &wm, key, step_size).unwrap();
+        assert!(!wmkd_image.color().has_alpha());
+    }
+
+    #[test]
+    fn test_wrong_dimensions() {
+        let host = synthetic_host(1);
+        let wm = synthetic_watermark(1);
+
+        let tiny_host = host.crop_imm(0, 0, 500, 4);
+        match embed_watermark_image(&tiny_host, &wm, 1, 50.0) {
+            Err(WatermarkError::HostDimensions { actual, block_size }) => {
+                assert_eq!(actual, (500, 4));
+                assert_eq!(block_size, 8);
+            }
+            other => panic!("expected HostDimensions error, got {:?}", other.map(|_| ())),
+        }
+
+        // Partial edge blocks don't add capacity
+        let unaligned_host = host.crop_imm(0, 0, 500, 512);
+        match embed_watermark_image(&unaligned_host, &wm, 1, 50.0) {
+            Err(WatermarkError::Capacity { available, .. }) => {
+                assert_eq!(available, 62 * 64 * 12);
+            }
+            other => panic!("expected Capacity error, got {:?}", other.map(|_| ())),
+        }
+
+        let small_host = host.crop_imm(0, 0, 256, 512);
+        match embed_watermark_image(&small_host, &wm, 1, 50.0) {
+            Err(WatermarkError::Capacity {
+                required,
+                available,
+            }) => {
+                assert_eq!(required, 128 * 128 * 3);
+                assert_eq!(available, 32 * 64 * 12);
+            }
+            other => panic!("expected Capacity error, got {:?}", other.map(|_| ())),
+        }
+
+        assert!(matches!(
+            extract_watermark_image(&small_host, 1, 50.0, 128, 128),
+            Err(WatermarkError::Capacity { .. })
+        ));
+
+        assert!(matches!(
+            embed_watermark("/nonexistent/host.png", "/nonexistent/wm.png", 1, 50.0),
+            Err(WatermarkError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_arbitrary_sizes() {
+        let key = 2143658709;
+        let step_size = 50.0;
+
+        let host =
+            synthetic_host(1).resize_exact(1024, 1024, image::imageops::FilterType::Triangle);
+        let wm =
+            synthetic_watermark(1).resize_exact(256, 256, image::imageops::FilterType::Nearest);
+
+        let wmkd_image = embed_watermark_image(&host, &wm, key, step_size).unwrap();
+        assert_eq!(wmkd_image.dimensions(), (1024, 1024));
+
+        let (extracted_bits, extracted_wm) =
+            extract_watermark_image(&wmkd_image, key, step_size, 256, 256).unwrap();
+        assert_eq!(extracted_bits.len(), 256 * 256 * 3);
+        assert_eq!(extracted_wm.dimensions(), (256, 256));
+
+        // A watermark that doesn't fill the last block exactly
+        let wm = wm.crop_imm(0, 0, 100, 30);
+        let wmkd_image = embed_watermark_image(&host, &wm, key, step_size).unwrap();
+        let (extracted_bits, _) =
+            extract_watermark_image(&wmkd_image, key, step_size, 100, 30).unwrap();
+        assert_eq!(extracted_bits.len(), 100 * 30 * 3);
     }
 
     #[test]
     fn test_interface() {
         let k = 2143658709;
-        for i in vec![1, 2] {
-            let wm_path = format!("{}/wm_img{}.png", INPUT_DIR, i);
-            for ss in vec![10.0, 20.0, 50.0, 100.0] {
-                for image in std::fs::read_dir(INPUT_DIR).unwrap() {
-                    let image = image.unwrap();
-                    let image_path = image.path();
-
-                    if let Some(ext) = image_path.extension() {
-                        if ext == "tiff" {
-                            println!(
-                                "Embedding {} into {} with step_size {}",
-                                wm_path,
-                                image_path.to_string_lossy(),
-                                ss as u32
-                            );
-                            let wmkd_image_path = format!(
-                                "{}/embed_extract{}/{}_{}_wmkd_image.png",
-                                OUTPUT_DIR,
-                                i,
-                                ss as u32,
-                                image_path.file_stem().unwrap().to_string_lossy()
-                            );
-                            let output_file = std::path::Path::new(&wmkd_image_path);
-                            if !output_file.exists() {
-                                std::fs::create_dir_all(output_file.parent().unwrap()).unwrap();
-                            }
-                            let wmkd_img =
-                                embed_watermark(&image_path.to_str().unwrap(), &wm_path, k, ss);
-                            wmkd_img.save(&wmkd_image_path).unwrap();
-
-                            println!("Extracting watermark from {}", wmkd_image_path);
-                            let (_, extracted_wm) = extract_watermark(&wmkd_image_path, k, ss);
-                            let extracted_wm_path = format!(
-                                "{}/embed_extract{}/{}_{}_extracted_wm.png",
-                                OUTPUT_DIR,
-                                i,
-                                ss as u32,
-                                image_path.file_stem().unwrap().to_string_lossy()
-                            );
-                            extracted_wm.save(&extracted_wm_path).unwrap();
-                        }
+        let in_dir = output_path("hosts");
+        std::fs::create_dir_all(&in_dir).unwrap();
+        for seed in [1, 2] {
+            synthetic_host(seed)
+                .save(format!("{}/host{}.tiff", in_dir, seed))
+                .unwrap();
+        }
+        for i in [1, 2] {
+            let wm = synthetic_watermark(i);
+            for ss in [10.0, 20.0, 50.0, 100.0] {
+                let out_dir = output_path(&format!("embed_extract{}/{}", i, ss as u32));
+                println!("Embedding watermark {} with step_size {}", i, ss as u32);
+                let results = embed_directory(&in_dir, &out_dir, &wm, k, ss).unwrap();
+                for (image_path, result) in results {
+                    if image_path.extension().is_none_or(|ext| ext != "tiff") {
+                        continue;
                     }
+                    let wmkd_image_path = result.unwrap();
+
+                    println!("Extracting watermark from {}", wmkd_image_path.display());
+                    let (_, extracted_wm) =
+                        extract_watermark(wmkd_image_path.to_str().unwrap(), k, ss, 128, 128)
+                            .unwrap();
+                    let extracted_wm_path = format!(
+                        "{}/{}_extracted_wm.png",
+                        out_dir,
+                        image_path.file_stem().unwrap().to_string_lossy()
+                    );
+                    extracted_wm.save(&extracted_wm_path).unwrap();
                 }
             }
         }
@@ -0,0 +1,132 @@
+//! Command line tool embedding and extracting watermarks, built with the `cli` feature
+
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use color_watermark::{color_recode, metrics, WatermarkError};
+
+#[derive(Parser)]
+#[command(version, about = "Embed color watermarks using DCT and QIM-DM")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Embeds a watermark into a host image and prints the PSNR of the result
+    Embed {
+        /// Host image, at least 8 * 8
+        #[arg(long)]
+        host: String,
+        /// Watermark image, 3 bits per pixel must fit into the host
+        #[arg(long)]
+        watermark: String,
+        #[arg(long)]
+        key: u64,
+        #[arg(long, default_value_t = 50.0)]
+        step: f32,
+        /// Watermarked image, use a lossless format
+        #[arg(long)]
+        out: String,
+    },
+    /// Extracts a watermark and prints the bit error rate against a reference if given
+    Extract {
+        /// Watermarked image
+        #[arg(long)]
+        input: String,
+        #[arg(long)]
+        key: u64,
+        #[arg(long, default_value_t = 50.0)]
+        step: f32,
+        /// Watermark width
+        #[arg(long)]
+        width: u32,
+        /// Watermark height
+        #[arg(long)]
+        height: u32,
+        /// Reconstructed watermark image
+        #[arg(long)]
+        out: String,
+        /// Original watermark to compute the bit error rate against
+        #[arg(long)]
+        reference: Option<String>,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse().command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", describe(&err));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<(), WatermarkError> {
+    match command {
+        Command::Embed {
+            host,
+            watermark,
+            key,
+            step,
+            out,
+        } => {
+            let host = image::open(host)?;
+            let wm = image::open(watermark)?;
+            let watermarked = color_watermark::embed_watermark_image(&host, &wm, key, step)?;
+            color_watermark::save_watermarked(&watermarked, &out)?;
+            println!("PSNR: {:.2} dB", metrics::psnr(&host, &watermarked));
+        }
+        Command::Extract {
+            input,
+            key,
+            step,
+            width,
+            height,
+            out,
+            reference,
+        } => {
+            let (bits, wm) = color_watermark::extract_watermark(&input, key, step, width, height)?;
+            wm.save(&out)?;
+            if let Some(reference) = reference {
+                let reference = color_recode::recode_to_3bits(&image::open(reference)?);
+                let ber = metrics::bit_error_rate(&reference, &bits)?;
+                println!("BER: {:.4}%", ber * 100.0);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Human readable message for the errors a user can cause from the command line
+fn describe(err: &WatermarkError) -> String {
+    match err {
+        WatermarkError::HostDimensions { actual, block_size } => format!(
+            "image is {} * {}, it must be at least {} pixels wide and high",
+            actual.0, actual.1, block_size
+        ),
+        WatermarkError::Capacity {
+            required,
+            available,
+        } => format!(
+            "watermark needs {} bits but the image only carries {}",
+            required, available
+        ),
+        WatermarkError::LengthMismatch { expected, actual } => format!(
+            "reference watermark has {} bits but {} were extracted, check --width and --height",
+            expected, actual
+        ),
+        WatermarkError::InvalidStepSize(step_size) => {
+            format!("step size {} must be a positive number", step_size)
+        }
+        WatermarkError::LossyFormat(format) => format!(
+            "{:?} is lossy and would destroy the watermark, save as PNG, TIFF or BMP instead",
+            format
+        ),
+        WatermarkError::Io(e) => format!("can't access file: {}", e),
+        WatermarkError::Decode(e) => format!("can't decode or encode image: {}", e),
+        e => e.to_string(),
+    }
+}
@@ -0,0 +1,145 @@
+use bitvec::vec::BitVec;
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+use crate::color_recode;
+use crate::WatermarkError;
+
+/// Number of bins of [`coefficient_delta_histogram`], the last one holding every larger delta
+pub const DELTA_BINS: usize = 64;
+
+/// Width of the bins of [`coefficient_delta_histogram`], in units of the block transform
+pub const DELTA_BIN_WIDTH: f32 = 1.0;
+
+/// Peak signal-to-noise ratio between two images in dB, computed over the RGB channels
+///
+/// Returns `f64::INFINITY` for identical images
+///
+/// Panics if the images have different dimensions
+pub fn psnr(original: &DynamicImage, watermarked: &DynamicImage) -> f64 {
+    assert_eq!(
+        original.dimensions(),
+        watermarked.dimensions(),
+        "Images must have the same dimensions"
+    );
+
+    let original = original.to_rgb8();
+    let watermarked = watermarked.to_rgb8();
+
+    let squared_error: f64 = original
+        .as_raw()
+        .iter()
+        .zip(watermarked.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    if squared_error == 0.0 {
+        return f64::INFINITY;
+    }
+
+    let mse = squared_error / original.as_raw().len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Fraction of bits that differ between the embedded and the extracted watermark
+///
+/// Returns 0.0 for two empty bit vecs, errors if the lengths differ
+pub fn bit_error_rate(original: &BitVec, extracted: &BitVec) -> Result<f64, WatermarkError> {
+    if original.len() != extracted.len() {
+        return Err(WatermarkError::LengthMismatch {
+            expected: original.len(),
+            actual: extracted.len(),
+        });
+    }
+    if original.is_empty() {
+        return Ok(0.0);
+    }
+
+    let errors = (original.clone() ^ extracted).count_ones();
+    Ok(errors as f64 / original.len() as f64)
+}
+
+/// Normalized correlation between two watermark images, in `[-1, 1]`
+///
+/// Computed on the 3-bit representation the scheme actually recovers, each bit mapped to +1 / -1,
+/// so identical watermarks give 1.0 and inverted ones -1.0
+///
+/// Panics if the images have different dimensions
+pub fn normalized_correlation(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    assert_eq!(
+        a.dimensions(),
+        b.dimensions(),
+        "Images must have the same dimensions"
+    );
+
+    let a = color_recode::recode_to_3bits(a);
+    let b = color_recode::recode_to_3bits(b);
+    let len = a.len();
+    if len == 0 {
+        return 0.0;
+    }
+
+    // With +1 / -1 samples both norms are sqrt(len) and the dot product is agreeing - disagreeing bits
+    let disagreeing = (a ^ b).count_ones();
+    (len as f64 - 2.0 * disagreeing as f64) / len as f64
+}
+
+/// Absolute difference between two images, per RGB channel and multiplied by `amplify`, as an RGB image
+///
+/// Makes the changes of a watermark, a few levels at most, visible: identical pixels stay black,
+/// and differences saturate at 255
+///
+/// Panics if the images have different dimensions
+pub fn difference_map(
+    original: &DynamicImage,
+    watermarked: &DynamicImage,
+    amplify: f32,
+) -> DynamicImage {
+    assert_eq!(
+        original.dimensions(),
+        watermarked.dimensions(),
+        "Images must have the same dimensions"
+    );
+
+    let (width, height) = original.dimensions();
+    let original = original.to_rgb8();
+    let watermarked = watermarked.to_rgb8();
+    let difference = original
+        .as_raw()
+        .iter()
+        .zip(watermarked.as_raw())
+        .map(|(&a, &b)| (a.abs_diff(b) as f32 * amplify).round().clamp(0.0, 255.0) as u8)
+        .collect();
+    DynamicImage::ImageRgb8(
+        RgbImage::from_raw(width, height, difference).expect("buffer matches the image dimensions"),
+    )
+}
+
+/// Histogram of the absolute change of every coefficient between two sets of transformed blocks
+///
+/// Bin `i` counts the deltas in `[i, i + 1) * DELTA_BIN_WIDTH`, the last of the [`DELTA_BINS`] bins
+/// every delta beyond. Coefficients outside the mask stay put, so the zero bin holds most of them;
+/// QIM moves the masked ones by up to half a step, a quarter on average
+///
+/// Panics unless both sets have the same number of blocks, of the same lengths
+pub fn coefficient_delta_histogram(
+    original_blocks: &[Vec<f32>],
+    watermarked_blocks: &[Vec<f32>],
+) -> Vec<u32> {
+    assert_eq!(
+        original_blocks.len(),
+        watermarked_blocks.len(),
+        "Block sets must have the same number of blocks"
+    );
+    let mut histogram = vec![0; DELTA_BINS];
+    for (original, watermarked) in original_blocks.iter().zip(watermarked_blocks) {
+        assert_eq!(
+            original.len(),
+            watermarked.len(),
+            "Blocks must have the same length"
+        );
+        for (a, b) in original.iter().zip(watermarked) {
+            let bin = ((a - b).abs() / DELTA_BIN_WIDTH) as usize;
+            histogram[bin.min(DELTA_BINS - 1)] += 1;
+        }
+    }
+    histogram
+}
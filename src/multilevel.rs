@@ -0,0 +1,176 @@
+//! Two-level DCT over square blocks, spreading the watermark across two scales
+//!
+//! Each `block_size * block_size` block is split into an 8 * 8 grid of sub-blocks of width `block_size / 8`,
+//! which go through the block DCT. The DC coefficients of those neighboring sub-blocks form an 8 * 8 block of
+//! their own, which goes through a second DCT whose coefficients replace them. A mask scaled to the block size,
+//! see [`crate::qim::CoefficientMask::scaled`], selects exactly those second-level coefficients, so the
+//! watermark lives in the coarse scale and survives downscaling better.
+//!
+//! The DC coefficients are made orthonormal before the second DCT, so its coefficients have the scale of the
+//! unnormalized 8 * 8 DCT ones, and step sizes keep their meaning
+
+use rustdct::DctNum;
+
+use crate::dct::{self, BlockDct, BLK_WIDTH};
+
+/// Two-level 2D DCT and its inverse over square blocks
+///
+/// Plans both levels once, mirroring [`crate::dct::BlockDct`] so both can be swapped. With 8 * 8 blocks the
+/// sub-blocks are single samples and this is the plain DCT, use 64 * 64 blocks or larger
+pub struct MultiLevelDct<T: DctNum = f32> {
+    block_size: usize,
+    /// First level, over the sub-blocks
+    sub_dct: BlockDct<T>,
+    /// Second level, over the 8 * 8 DC coefficients of the sub-blocks
+    dc_dct: BlockDct<T>,
+}
+
+impl<T: DctNum> MultiLevelDct<T> {
+    /// Plans the transforms for `block_size * block_size` blocks
+    ///
+    /// Panics unless `block_size` is a power of two of at least 8
+    pub fn new(block_size: usize) -> Self {
+        assert!(
+            block_size.is_power_of_two() && block_size >= BLK_WIDTH,
+            "block size {} is not a power of two of at least {}",
+            block_size,
+            BLK_WIDTH
+        );
+        Self {
+            block_size,
+            sub_dct: BlockDct::new(block_size / BLK_WIDTH),
+            dc_dct: BlockDct::new(BLK_WIDTH),
+        }
+    }
+
+    /// Width of the blocks the transforms were planned for
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Width of the first-level sub-blocks
+    pub fn sub_block_size(&self) -> usize {
+        self.block_size / BLK_WIDTH
+    }
+
+    /// Applies the two-level DCT on a Vec of blocks
+    ///
+    /// Changes are made in-place, reusing the sub-block buffers across blocks
+    pub fn forward(&self, blocks: &mut [Vec<T>]) {
+        let mut subs = self.sub_blocks();
+        for block in blocks.iter_mut() {
+            self.forward_with_buffers(block, &mut subs);
+        }
+    }
+
+    /// Applies the inverse two-level DCT on a Vec of blocks
+    ///
+    /// Changes are made in-place, reusing the sub-block buffers across blocks
+    pub fn inverse(&self, blocks: &mut [Vec<T>]) {
+        let mut subs = self.sub_blocks();
+        for block in blocks.iter_mut() {
+            self.inverse_with_buffers(block, &mut subs);
+        }
+    }
+
+    /// Applies the two-level DCT on a single block in place
+    ///
+    /// Panics unless the block holds `block_size * block_size` values
+    pub fn forward_block(&self, block: &mut [T]) {
+        self.forward_with_buffers(block, &mut self.sub_blocks());
+    }
+
+    /// Applies the inverse two-level DCT on a single block in place
+    ///
+    /// Panics unless the block holds `block_size * block_size` values
+    pub fn inverse_block(&self, block: &mut [T]) {
+        self.inverse_with_buffers(block, &mut self.sub_blocks());
+    }
+
+    /// Buffers for the sub-blocks of a block, then the DC coefficients of the sub-blocks
+    fn sub_blocks(&self) -> Vec<Vec<T>> {
+        let sub = self.sub_block_size();
+        let mut buffers = vec![vec![T::zero(); sub * sub]; BLK_WIDTH * BLK_WIDTH];
+        buffers.push(vec![T::zero(); BLK_WIDTH * BLK_WIDTH]);
+        buffers
+    }
+
+    fn forward_with_buffers(&self, block: &mut [T], buffers: &mut [Vec<T>]) {
+        let (subs, dc) = buffers.split_at_mut(BLK_WIDTH * BLK_WIDTH);
+        let dc = &mut dc[0];
+        let scale = T::from_usize(self.sub_block_size()).unwrap();
+
+        self.split(block, subs);
+        self.sub_dct.forward(subs);
+        for (value, sub) in dc.iter_mut().zip(subs.iter()) {
+            *value = sub[0] / scale;
+        }
+        self.dc_dct.forward_block(dc);
+        for (sub, &value) in subs.iter_mut().zip(dc.iter()) {
+            sub[0] = value;
+        }
+        self.merge(subs, block);
+    }
+
+    fn inverse_with_buffers(&self, block: &mut [T], buffers: &mut [Vec<T>]) {
+        let (subs, dc) = buffers.split_at_mut(BLK_WIDTH * BLK_WIDTH);
+        let dc = &mut dc[0];
+        let scale = T::from_usize(self.sub_block_size()).unwrap();
+
+        self.split(block, subs);
+        for (value, sub) in dc.iter_mut().zip(subs.iter()) {
+            *value = sub[0];
+        }
+        self.dc_dct.inverse_block(dc);
+        for (sub, &value) in subs.iter_mut().zip(dc.iter()) {
+            sub[0] = value * scale;
+        }
+        self.sub_dct.inverse(subs);
+        self.merge(subs, block);
+    }
+
+    /// Copies the sub-blocks out of a block, left to right then top to bottom
+    fn split(&self, block: &[T], subs: &mut [Vec<T>]) {
+        let (n, sub) = (self.block_size, self.sub_block_size());
+        assert_eq!(block.len(), n * n, "block is not {} * {}", n, n);
+        for (k, values) in subs.iter_mut().enumerate() {
+            let (y0, x0) = (k / BLK_WIDTH * sub, k % BLK_WIDTH * sub);
+            for (row, chunk) in values.chunks_mut(sub).enumerate() {
+                let start = (y0 + row) * n + x0;
+                chunk.copy_from_slice(&block[start..start + sub]);
+            }
+        }
+    }
+
+    /// Copies the sub-blocks back into their place in the block
+    fn merge(&self, subs: &[Vec<T>], block: &mut [T]) {
+        let (n, sub) = (self.block_size, self.sub_block_size());
+        for (k, values) in subs.iter().enumerate() {
+            let (y0, x0) = (k / BLK_WIDTH * sub, k % BLK_WIDTH * sub);
+            for (row, chunk) in values.chunks(sub).enumerate() {
+                let start = (y0 + row) * n + x0;
+                block[start..start + sub].copy_from_slice(chunk);
+            }
+        }
+    }
+}
+
+impl<T: DctNum> Default for MultiLevelDct<T> {
+    fn default() -> Self {
+        Self::new(BLK_WIDTH * BLK_WIDTH)
+    }
+}
+
+/// Factor turning the coefficient at row-major `index` into the orthonormal one, see
+/// [`crate::dct::orthonormal_scale`]
+///
+/// Second-level coefficients sit at the DC position of each sub-block, the others are first-level ones
+pub(crate) fn orthonormal_scale(index: usize, block_size: usize) -> f64 {
+    let sub = block_size / BLK_WIDTH;
+    let (row, col) = (index / block_size, index % block_size);
+    if row % sub == 0 && col % sub == 0 {
+        dct::orthonormal_scale(row / sub * BLK_WIDTH + col / sub, BLK_WIDTH)
+    } else {
+        dct::orthonormal_scale(row % sub * sub + col % sub, sub)
+    }
+}
@@ -0,0 +1,66 @@
+//! Visible watermarks, stamped over the host before the invisible one is embedded
+
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Where the logo lands on the host, flush against the edges for the corners
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Position {
+    /// Top-left corner of a `logo` sized area in a `host` sized image
+    fn origin(self, host: (u32, u32), logo: (u32, u32)) -> (u32, u32) {
+        let right = host.0.saturating_sub(logo.0);
+        let bottom = host.1.saturating_sub(logo.1);
+        match self {
+            Position::TopLeft => (0, 0),
+            Position::TopRight => (right, 0),
+            Position::BottomLeft => (0, bottom),
+            Position::BottomRight => (right, bottom),
+            Position::Center => (right / 2, bottom / 2),
+        }
+    }
+}
+
+/// Alpha blends `logo` over `host` at `position`, its own alpha scaled by `opacity`
+///
+/// Blending is done on 8 bit RGB, the result is RGBA if the host has alpha and RGB otherwise, ready to
+/// be passed to [`crate::embed_watermark_image`]. The parts of a logo larger than the host are cut off
+///
+/// Panics unless `opacity` is in `[0, 1]`
+pub fn overlay_visible(
+    host: &DynamicImage,
+    logo: &DynamicImage,
+    position: Position,
+    opacity: f32,
+) -> DynamicImage {
+    assert!(
+        (0.0..=1.0).contains(&opacity),
+        "opacity {} is not in [0, 1]",
+        opacity
+    );
+    let mut stamped: RgbaImage = host.to_rgba8();
+    let logo = logo.to_rgba8();
+    let (x0, y0) = position.origin(stamped.dimensions(), logo.dimensions());
+
+    for (x, y, &Rgba(top)) in logo.enumerate_pixels() {
+        let Some(pixel) = stamped.get_pixel_mut_checked(x0 + x, y0 + y) else {
+            continue;
+        };
+        let alpha = top[3] as f32 / 255.0 * opacity;
+        for (bottom, &top) in pixel.0[..3].iter_mut().zip(&top[..3]) {
+            *bottom = (*bottom as f32 * (1.0 - alpha) + top as f32 * alpha).round() as u8;
+        }
+    }
+
+    if host.color().has_alpha() {
+        DynamicImage::ImageRgba8(stamped)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(stamped).to_rgb8())
+    }
+}
@@ -0,0 +1,147 @@
+//! Layout of the embedded bit stream: optional header, padding to whole blocks and repetitions
+
+use bitvec::prelude::{BitSlice, BitVec};
+use sha2::{Digest, Sha256};
+
+use crate::color_recode::WatermarkKind;
+use crate::WatermarkError;
+
+/// Bits used by each of the watermark width and height in the header
+const HEADER_DIM_BITS: usize = 16;
+
+/// Bits describing how the watermark was recoded
+const HEADER_FORMAT_BITS: usize = 8;
+
+/// Length of the header in bits
+pub(crate) const HEADER_LEN: usize = 2 * HEADER_DIM_BITS + HEADER_FORMAT_BITS;
+
+/// Length of the authentication tag in bits, the leading bits of the HMAC
+pub(crate) const TAG_LEN: usize = 64;
+
+/// Block length of SHA-256 in bytes, that of the HMAC key pads
+const HMAC_BLOCK_LEN: usize = 64;
+
+/// Flag of the format field set for grayscale watermarks, the low bits store the bits per channel minus one
+const HEADER_GRAY_FLAG: u32 = 1 << 4;
+
+/// Encodes the watermark dimensions, kind and bits per channel, most significant bit first
+pub(crate) fn encode_header(
+    wm_width: u32,
+    wm_height: u32,
+    kind: WatermarkKind,
+    bits_per_channel: u8,
+) -> Result<BitVec, WatermarkError> {
+    let max = (1 << HEADER_DIM_BITS) - 1;
+    if wm_width > max || wm_height > max {
+        return Err(WatermarkError::WatermarkDimensions {
+            actual: (wm_width, wm_height),
+            max,
+        });
+    }
+
+    let format = match kind {
+        WatermarkKind::Rgb => 0,
+        WatermarkKind::Gray => HEADER_GRAY_FLAG,
+    } | (bits_per_channel as u32 - 1);
+
+    let mut header = BitVec::with_capacity(HEADER_LEN);
+    for (value, len) in [
+        (wm_width, HEADER_DIM_BITS),
+        (wm_height, HEADER_DIM_BITS),
+        (format, HEADER_FORMAT_BITS),
+    ] {
+        for i in (0..len).rev() {
+            header.push((value >> i) & 1 == 1);
+        }
+    }
+    Ok(header)
+}
+
+/// Reads the watermark dimensions, kind and bits per channel back from the header bits
+pub(crate) fn decode_header(header: &BitSlice) -> (u32, u32, WatermarkKind, u8) {
+    let read = |bits: &BitSlice| bits.iter().fold(0, |acc, bit| (acc << 1) | *bit as u32);
+    let format = read(&header[2 * HEADER_DIM_BITS..HEADER_LEN]);
+    let kind = if format & HEADER_GRAY_FLAG != 0 {
+        WatermarkKind::Gray
+    } else {
+        WatermarkKind::Rgb
+    };
+    (
+        read(&header[..HEADER_DIM_BITS]),
+        read(&header[HEADER_DIM_BITS..2 * HEADER_DIM_BITS]),
+        kind,
+        (format & 0b111) as u8 + 1,
+    )
+}
+
+/// HMAC-SHA256 of `bits`, keyed by `key`, truncated to [`TAG_LEN`] bits
+///
+/// The bit count is hashed along the bits, zero padded to whole bytes, so that trailing zeros count
+pub(crate) fn auth_tag(bits: &BitSlice, key: u64) -> BitVec {
+    let mut message = (bits.len() as u64).to_be_bytes().to_vec();
+    message.extend(bits.chunks(8).map(|chunk| {
+        chunk.iter().fold(0, |acc, bit| (acc << 1) | *bit as u8) << (8 - chunk.len())
+    }));
+
+    let mut padded_key = [0; HMAC_BLOCK_LEN];
+    padded_key[..8].copy_from_slice(&key.to_le_bytes());
+    let pad = |byte: u8| padded_key.iter().map(|k| k ^ byte).collect::<Vec<u8>>();
+    let mut inner = Sha256::new();
+    inner.update(pad(0x36));
+    inner.update(&message);
+    let mut outer = Sha256::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+
+    bytes_to_bits(&outer.finalize()[..TAG_LEN / 8])
+}
+
+/// Number of blocks taken by a segment of `len` bits repeated `copies` times
+pub(crate) fn segment_blocks(len: usize, block_bits: usize, copies: usize) -> usize {
+    len.div_ceil(block_bits) * copies
+}
+
+/// Pads `bits` with zeros to whole blocks and repeats the result `copies` times
+pub(crate) fn spread(bits: &BitSlice, block_bits: usize, copies: usize) -> BitVec {
+    let mut padded = bits.to_bitvec();
+    padded.resize(bits.len().div_ceil(block_bits) * block_bits, false);
+
+    let mut ret = BitVec::with_capacity(padded.len() * copies);
+    for _ in 0..copies {
+        ret.extend_from_bitslice(&padded);
+    }
+    ret
+}
+
+/// Inverse of [`spread`]: majority votes each of the `len` bits over the copies
+///
+/// Ties, only possible with an even number of copies, are decoded as zeros
+pub(crate) fn majority_vote(
+    bits: &BitSlice,
+    len: usize,
+    block_bits: usize,
+    copies: usize,
+) -> BitVec {
+    let copy_len = len.div_ceil(block_bits) * block_bits;
+    (0..len)
+        .map(|i| {
+            let ones = (0..copies).filter(|c| bits[c * copy_len + i]).count();
+            ones * 2 > copies
+        })
+        .collect()
+}
+
+/// Bits of the bytes, most significant bit first
+pub(crate) fn bytes_to_bits(bytes: &[u8]) -> BitVec {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .collect()
+}
+
+/// Inverse of [`bytes_to_bits`], trailing bits short of a byte are dropped
+pub(crate) fn bits_to_bytes(bits: &BitSlice) -> Vec<u8> {
+    bits.chunks_exact(8)
+        .map(|chunk| chunk.iter().fold(0, |acc, bit| (acc << 1) | *bit as u8))
+        .collect()
+}
@@ -1,12 +1,334 @@
+use std::collections::BTreeMap;
+
+use bitvec::order::BitOrder;
+use bitvec::slice::BitSlice;
+use bitvec::store::BitStore;
 use bitvec::vec::BitVec;
+use num_traits::Float;
+use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
+use sha2::{Digest, Sha256};
+
+use crate::{Channel, WatermarkError};
+
+/// Number of coefficients, hence watermark bits, the preset masks select in each block
+pub const MASK_LEN: usize = 12;
+
+/// Width of the block grid mask positions refer to
+const GRID_WIDTH: usize = 8;
+
+/// Number of coefficients in an 8 * 8 block
+const BLOCK_LEN: usize = GRID_WIDTH * GRID_WIDTH;
+
+/// Predefined coefficient selections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Preset {
+    /// Zig-zag ranks 12-17, 25-29 and 42, i.e. row-major positions 4-7, 11-15 and 18-20,
+    /// the default of this implementation
+    MidFrequency,
+    /// The first 12 AC coefficients in zig-zag order, more robust but more visible
+    LowFrequency,
+}
+
+/// Row-major index into an 8 * 8 block of the coefficient at `order` in the JPEG zig-zag scan
+///
+/// Lower orders are lower frequencies, order 0 is the DC coefficient
+///
+/// Panics unless `order` is below 64
+pub fn zigzag_index(order: usize) -> usize {
+    assert!(
+        order < BLOCK_LEN,
+        "zig-zag order {} is outside of the {} coefficients of a block",
+        order,
+        BLOCK_LEN
+    );
+    let mut remaining = order;
+    for diagonal in 0..2 * GRID_WIDTH - 1 {
+        let first_row = diagonal.saturating_sub(GRID_WIDTH - 1);
+        let last_row = diagonal.min(GRID_WIDTH - 1);
+        let len = last_row - first_row + 1;
+        if remaining < len {
+            // Even diagonals are scanned up from the bottom-left, odd ones down from the top-right
+            let row = if diagonal.is_multiple_of(2) {
+                last_row - remaining
+            } else {
+                first_row + remaining
+            };
+            return row * GRID_WIDTH + diagonal - row;
+        }
+        remaining -= len;
+    }
+    unreachable!()
+}
+
+/// The coefficients of a block used to carry the watermark bits
+///
+/// Positions are row-major indices into an 8 * 8 block, the n-th position carries the n-th bit,
+/// see [`CoefficientMask::scaled`] for larger blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct CoefficientMask {
+    indices: Vec<usize>,
+}
+
+impl CoefficientMask {
+    /// Builds a mask from an explicit list of row-major positions
+    ///
+    /// Each block carries one bit, or one M-ary symbol, per position, so the number of positions sets the
+    /// capacity. [`MASK_LEN`] like the presets is a balance between capacity and distortion
+    ///
+    /// Errors unless there is at least one position, all distinct and within the block
+    pub fn new(indices: &[usize]) -> Result<Self, WatermarkError> {
+        if indices.is_empty() {
+            return Err(WatermarkError::InvalidMask(
+                "a mask needs at least one position".to_string(),
+            ));
+        }
+        for (n, &i) in indices.iter().enumerate() {
+            if i >= BLOCK_LEN {
+                return Err(WatermarkError::InvalidMask(format!(
+                    "position {} is outside of the {} coefficients of a block",
+                    i, BLOCK_LEN
+                )));
+            }
+            if indices[..n].contains(&i) {
+                return Err(WatermarkError::InvalidMask(format!(
+                    "position {} is selected more than once",
+                    i
+                )));
+            }
+        }
+
+        Ok(Self {
+            indices: indices.to_vec(),
+        })
+    }
+
+    /// Builds a mask from zig-zag ranks, see [`zigzag_index`]
+    ///
+    /// Errors unless there is at least one rank, all distinct and within the block
+    pub fn zigzag(orders: &[usize]) -> Result<Self, WatermarkError> {
+        if let Some(order) = orders.iter().find(|&&order| order >= BLOCK_LEN) {
+            return Err(WatermarkError::InvalidMask(format!(
+                "zig-zag order {} is outside of the {} coefficients of a block",
+                order, BLOCK_LEN
+            )));
+        }
+        let indices: Vec<usize> = orders.iter().map(|&order| zigzag_index(order)).collect();
+        Self::new(&indices)
+    }
+
+    /// Builds one of the predefined masks
+    pub fn preset(preset: Preset) -> Self {
+        let orders: Vec<usize> = match preset {
+            Preset::MidFrequency => vec![14, 15, 27, 28, 13, 16, 26, 29, 42, 12, 17, 25],
+            Preset::LowFrequency => (1..=MASK_LEN).collect(),
+        };
+        Self::zigzag(&orders).expect("preset masks are valid")
+    }
+
+    /// The selected row-major positions, in bit order
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// Number of selected positions, the coefficients carrying bits in each block
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Always false, masks select at least one position
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Whether both masks select a common position
+    ///
+    /// Watermarks embedded one over another with masks that don't overlap leave each other intact
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.indices.iter().any(|i| other.indices.contains(i))
+    }
+
+    /// The same mask for `block_size * block_size` blocks
+    ///
+    /// Position `(row, col)` maps to `(row, col) * block_size / 8`, which keeps the spatial frequency
+    /// of each selected coefficient
+    ///
+    /// Panics unless `block_size` is a non-zero multiple of 8
+    pub fn scaled(&self, block_size: usize) -> Self {
+        assert!(
+            block_size != 0 && block_size.is_multiple_of(GRID_WIDTH),
+            "block size {} is not a multiple of {}",
+            block_size,
+            GRID_WIDTH
+        );
+        let factor = block_size / GRID_WIDTH;
+        let indices = self
+            .indices
+            .iter()
+            .map(|&i| (i / GRID_WIDTH) * factor * block_size + (i % GRID_WIDTH) * factor)
+            .collect();
+        Self { indices }
+    }
+}
+
+/// Deserializes the positions through [`CoefficientMask::new`], so invalid masks are rejected
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for CoefficientMask {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let indices = Vec::<usize>::deserialize(deserializer)?;
+        Self::new(&indices).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Default for CoefficientMask {
+    fn default() -> Self {
+        Self::preset(Preset::MidFrequency)
+    }
+}
+
+/// Coefficients of each block already carrying a watermark, so layered watermarks can't clobber each other
+///
+/// Holds a bitmap of the 8 * 8 mask positions per block of each plane. Keep one per host and pass it to
+/// every [`crate::embed_with_usage`] on that host
+#[derive(Debug, Clone, Default)]
+pub struct CoefficientUsage {
+    /// Block size and per-block bitmaps of each plane written so far
+    planes: BTreeMap<Channel, (usize, Vec<u64>)>,
+}
+
+impl CoefficientUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the mask positions of the leading `blocks` blocks of each channel as used
+    ///
+    /// Errors without marking anything if a position is already used. With another block size,
+    /// any used block conflicts since the blocks no longer line up
+    pub(crate) fn claim(
+        &mut self,
+        block_size: usize,
+        mask: &CoefficientMask,
+        blocks: &[(Channel, usize)],
+    ) -> Result<(), WatermarkError> {
+        let bitmap = mask.indices.iter().fold(0_u64, |acc, &i| acc | (1 << i));
+        for &(channel, count) in blocks {
+            if let Some((used_size, used)) = self.planes.get(&channel) {
+                let conflicting = if *used_size == block_size {
+                    bitmap
+                } else {
+                    u64::MAX
+                };
+                if let Some(block) = used[..count.min(used.len())]
+                    .iter()
+                    .position(|used| used & conflicting != 0)
+                {
+                    return Err(WatermarkError::MaskConflict { channel, block });
+                }
+            }
+        }
+
+        for &(channel, count) in blocks.iter().filter(|(_, count)| *count > 0) {
+            let (_, used) = self
+                .planes
+                .entry(channel)
+                .or_insert_with(|| (block_size, Vec::new()));
+            if used.len() < count {
+                used.resize(count, 0);
+            }
+            for used in used[..count].iter_mut() {
+                *used |= bitmap;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Per-block step size scaled by the energy of the block, see [`adaptive_step_size`]
+///
+/// Busy blocks hide larger modifications than flat ones, so each block's step is the global step scaled by
+/// `sqrt(energy / reference_energy)`, clamped to `[min_scale, max_scale]`. The energy is the mean magnitude
+/// of the AC coefficients outside the mask, which embedding leaves untouched, so the extractor recomputes
+/// the same step from the watermarked block
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdaptiveStep {
+    /// Energy of an 8 * 8 block keeping the global step
+    pub reference_energy: f32,
+    pub min_scale: f32,
+    pub max_scale: f32,
+}
+
+impl Default for AdaptiveStep {
+    fn default() -> Self {
+        Self {
+            reference_energy: 40.0,
+            min_scale: 0.5,
+            max_scale: 2.0,
+        }
+    }
+}
+
+/// Step size of a DCT block under [`AdaptiveStep`], `mask` must be scaled to the block size
+pub fn adaptive_step_size(
+    block: &[f32],
+    step_size: f32,
+    mask: &CoefficientMask,
+    adaptive: &AdaptiveStep,
+) -> f32 {
+    let scale = (block_energy(block, mask) / adaptive.reference_energy)
+        .sqrt()
+        .clamp(adaptive.min_scale, adaptive.max_scale);
+    step_size * scale
+}
+
+/// Energy of a DCT block, the mean magnitude of its AC coefficients outside `mask`
+///
+/// Embedding leaves those coefficients untouched, so the watermarked block has the energy of the original
+/// up to the rounding of the samples
+pub fn block_energy(block: &[f32], mask: &CoefficientMask) -> f32 {
+    let (sum, count) = block
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(i, _)| !mask.indices().contains(i))
+        .fold((0.0, 0), |(sum, count), (_, c)| (sum + c.abs(), count + 1));
+    // A mask selecting every AC coefficient leaves no energy to measure
+    sum / count.max(1) as f32
+}
+
+/// Salt mixed into passphrases, changing it changes every derived key
+const PASSPHRASE_SALT: &[u8] = b"color_watermark/qim-key/v1";
+
+/// Derives a key for [`generate_dither_signal`] from a passphrase
+///
+/// Return value: the first 8 bytes of SHA-256 over a fixed salt and the passphrase, little endian
+pub fn key_from_passphrase(pass: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(PASSPHRASE_SALT);
+    hasher.update(pass.as_bytes());
+    let hash = hasher.finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
 
 /// Generates a Vec for 2 Dither Arrays
 ///
-/// length should be 12 for this specific implementation
+/// length is the number of coefficients of the mask, [`MASK_LEN`] for the presets
 pub fn generate_dither_signal(length: usize, step_size: f32, seed: u64) -> Vec<(f32, f32)> {
-    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    generate_dither_signal_with(length, step_size, &mut ChaCha8Rng::seed_from_u64(seed))
+}
+
+/// Generates the 2 dither arrays of [`generate_dither_signal`] from any random number generator
+///
+/// The dithers are only as reproducible as `rng`, pick one with a fixed, portable output for a given seed
+pub fn generate_dither_signal_with<R: Rng + ?Sized>(
+    length: usize,
+    step_size: f32,
+    rng: &mut R,
+) -> Vec<(f32, f32)> {
     let half = step_size / 2.0;
     (0..length)
         .map(|_| {
@@ -20,60 +342,539 @@ pub fn generate_dither_signal(length: usize, step_size: f32, seed: u64) -> Vec<(
         .collect()
 }
 
-fn in_range(i: usize) -> bool {
-    // I choose to use those coefficients, just because it's easier
-    (4..=7).contains(&i) || (11..=15).contains(&i) || (18..=20).contains(&i)
+/// Panics unless `levels` is a power of two of at least 2
+fn assert_levels(levels: usize) {
+    assert!(
+        levels >= 2 && levels.is_power_of_two(),
+        "{} levels is not a power of two of at least 2",
+        levels
+    );
+}
+
+/// Generates the dithers of `levels` interleaved lattices for each of `length` coefficients, for M-ary QIM
+///
+/// Return value: `length * levels` offsets, lattice `k` of coefficient `j` at `j * levels + k`.
+/// Lattice `k` is lattice 0 shifted by `k * step_size / levels`, 2 levels give the pairs of [`generate_dither_signal`]
+///
+/// Panics unless `levels` is a power of two of at least 2
+pub fn generate_dither_lattices(
+    length: usize,
+    step_size: f32,
+    levels: usize,
+    seed: u64,
+) -> Vec<f32> {
+    lattices(
+        &mut ChaCha8Rng::seed_from_u64(seed),
+        length,
+        step_size,
+        levels,
+    )
+}
+
+/// Same as [`generate_dither_lattices`], but distinct for each block: the block index selects the ChaCha8 stream
+///
+/// Averaging many blocks no longer estimates the dithers, at the cost of deriving them for every block
+///
+/// Panics unless `levels` is a power of two of at least 2
+pub fn generate_block_dither_lattices(
+    length: usize,
+    step_size: f32,
+    levels: usize,
+    seed: u64,
+    block: u64,
+) -> Vec<f32> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(block);
+    lattices(&mut rng, length, step_size, levels)
+}
+
+fn lattices(rng: &mut ChaCha8Rng, length: usize, step_size: f32, levels: usize) -> Vec<f32> {
+    assert_levels(levels);
+    let half = step_size / 2.0;
+    let spacing = step_size / levels as f32;
+    let mut ret = Vec::with_capacity(length * levels);
+    for _ in 0..length {
+        let first = rng.random_range(-half..half);
+        for k in 0..levels {
+            // Wrapped back into [-half, half]
+            let offset = first + k as f32 * spacing;
+            ret.push(if offset > half {
+                first - (levels - k) as f32 * spacing
+            } else {
+                offset
+            });
+        }
+    }
+    ret
+}
+
+/// Stream of the ChaCha8 generator drawing the fragile pattern, apart from the streams of the block dithers
+const FRAGILE_STREAM: u64 = u64::MAX;
+
+/// `len` pseudo-random bits derived from the key, embedded by [`crate::embed_fragile`] and checked by
+/// [`crate::locate_tamper`]
+pub fn fragile_pattern(len: usize, key: u64) -> BitVec {
+    let mut rng = ChaCha8Rng::seed_from_u64(key);
+    rng.set_stream(FRAGILE_STREAM);
+    (0..len).map(|_| rng.random::<bool>()).collect()
+}
+
+/// Stream of the ChaCha8 generator shuffling the blocks, apart from the fragile pattern and the block dithers
+const BLOCK_ORDER_STREAM: u64 = u64::MAX - 1;
+
+/// Key-derived permutation of `0..len`: the `i`-th chunk of the bit stream goes to block `permutation[i]`
+///
+/// Used by [`crate::EmbedConfig::permute_blocks`]
+pub fn block_permutation(len: usize, key: u64) -> Vec<usize> {
+    let mut rng = ChaCha8Rng::seed_from_u64(key);
+    rng.set_stream(BLOCK_ORDER_STREAM);
+    let mut permutation: Vec<usize> = (0..len).collect();
+    permutation.shuffle(&mut rng);
+    permutation
 }
 
-fn round_to_step_size(num: f32, step_size: f32) -> f32 {
-    if num % step_size > (step_size / 2.0) {
-        (num as u32 / step_size as u32 + 1) as f32 * step_size
-    } else {
-        (num as u32 / step_size as u32) as f32 * step_size
+/// Rounds `num` to the nearest multiple of `step_size`, halfway cases are rounded away from zero
+///
+/// Only a division, a rounding and a product, each correctly rounded by IEEE 754 on every target. Rust never
+/// fuses a product and a sum into an FMA on its own and QIM never calls `mul_add`, so the same coefficients
+/// give the same bits on x86, ARM and WebAssembly. Coefficients can still differ in their last bit between
+/// targets, the DCT twiddles come from the `cos` of the platform, so a coefficient right on a decision
+/// boundary may decode differently after a transform
+pub fn round_to_step_size<F: Float>(num: F, step_size: F) -> F {
+    (num / step_size).round() * step_size
+}
+
+/// Embeds one bit into each coefficient of the block selected by `mask`
+///
+/// Distortion-compensated QIM: each coefficient moves a fraction `alpha` of the way to its lattice point,
+/// `x' = x + alpha * (Q(x + d) - d - x)`. `alpha = 1` is plain QIM. The coefficients then land up to
+/// `(1 - alpha) * step_size / 2` off their lattice, past the tenth of a step of [`extract_wm`] for `alpha < 0.8`,
+/// so decode them from the closer lattice, the sign of [`extract_wm_soft`], which is the same for any `alpha`
+///
+/// Generic over the precision of the coefficients, f32 like the rest of the crate or f64
+///
+/// Panics unless `alpha` is in `(0, 1]`
+pub fn embed_wm<F: Float>(
+    host_signal: &mut [F],
+    watermark: &BitSlice,
+    dither_signal: &[(F, F)],
+    step_size: F,
+    alpha: F,
+    mask: &CoefficientMask,
+) {
+    assert!(
+        alpha > F::zero() && alpha <= F::one(),
+        "alpha {} is not in (0, 1]",
+        alpha.to_f64().unwrap_or(f64::NAN)
+    );
+    assert_eq!(watermark.len(), mask.indices().len());
+    for (j, &i) in mask.indices().iter().enumerate() {
+        let d = if watermark[j] {
+            dither_signal[j].1
+        } else {
+            dither_signal[j].0
+        };
+        let x = host_signal[i];
+        host_signal[i] = x + alpha * (round_to_step_size(x + d, step_size) - d - x);
     }
 }
 
-pub fn embed_wm(
+/// Same as [`embed_wm`], reading the bits of the block from `start` on in the whole `payload`
+///
+/// Lets the blocks of a bit stream be embedded from a single buffer, without chunking it
+///
+/// Panics unless `payload` holds one bit per coefficient of the mask from `start` on, or `alpha` isn't in `(0, 1]`
+pub fn embed_wm_at<F: Float>(
+    host_signal: &mut [F],
+    payload: &BitSlice,
+    start: usize,
+    dither_signal: &[(F, F)],
+    step_size: F,
+    alpha: F,
+    mask: &CoefficientMask,
+) {
+    let watermark = &payload[start..start + mask.len()];
+    embed_wm(
+        host_signal,
+        watermark,
+        dither_signal,
+        step_size,
+        alpha,
+        mask,
+    );
+}
+
+/// M-ary version of [`embed_wm`]: embeds `log2(levels)` bits, most significant first, into each coefficient
+/// of the block selected by `mask`, using the dithers of [`generate_dither_lattices`]
+///
+/// More levels carry more bits per block, but the lattices are only `step_size / levels` apart,
+/// so keeping the robustness of binary QIM takes a step size `levels / 2` times larger
+///
+/// Panics unless `levels` is a power of two of at least 2 and `alpha` is in `(0, 1]`
+pub fn embed_wm_mary(
     host_signal: &mut [f32],
-    watermark: &BitVec,
-    dither_signal: &[(f32, f32)],
+    watermark: &BitSlice,
+    lattices: &[f32],
+    levels: usize,
     step_size: f32,
+    alpha: f32,
+    mask: &CoefficientMask,
 ) {
-    let mut j = 0;
-    for (i, h) in host_signal.iter_mut().enumerate() {
-        if in_range(i) {
-            let d = if watermark[j] {
-                dither_signal[j].1
+    assert_levels(levels);
+    assert!(
+        alpha > 0.0 && alpha <= 1.0,
+        "alpha {} is not in (0, 1]",
+        alpha
+    );
+    let bits = levels.trailing_zeros() as usize;
+    assert_eq!(watermark.len(), mask.indices().len() * bits);
+    for ((j, &i), symbol) in mask
+        .indices()
+        .iter()
+        .enumerate()
+        .zip(watermark.chunks(bits))
+    {
+        let symbol = symbol.iter().fold(0, |acc, bit| (acc << 1) | *bit as usize);
+        let d = lattices[j * levels + symbol];
+        let x = host_signal[i];
+        host_signal[i] = x + alpha * (round_to_step_size(x + d, step_size) - d - x);
+    }
+}
+
+/// Same as [`embed_wm_mary`], reading the bits of the block from `start` on in the whole `payload`
+///
+/// Panics unless `payload` holds the bits of the block from `start` on, see [`embed_wm_mary`]
+#[allow(clippy::too_many_arguments)]
+pub fn embed_wm_mary_at(
+    host_signal: &mut [f32],
+    payload: &BitSlice,
+    start: usize,
+    lattices: &[f32],
+    levels: usize,
+    step_size: f32,
+    alpha: f32,
+    mask: &CoefficientMask,
+) {
+    let len = mask.len() * levels.trailing_zeros() as usize;
+    let watermark = &payload[start..start + len];
+    embed_wm_mary(
+        host_signal,
+        watermark,
+        lattices,
+        levels,
+        step_size,
+        alpha,
+        mask,
+    );
+}
+
+/// M-ary version of [`extract_wm`]: decodes `log2(levels)` bits from each coefficient of the block
+/// selected by `mask`, from the closest of its lattices
+///
+/// With 2 levels, a bit is the sign of [`extract_wm_soft`] rather than the tenth of a step of [`extract_wm`]
+///
+/// Panics unless `levels` is a power of two of at least 2
+pub fn extract_wm_mary(
+    watermarked_signal: &[f32],
+    lattices: &[f32],
+    levels: usize,
+    step_size: f32,
+    mask: &CoefficientMask,
+) -> BitVec {
+    let mut ret = BitVec::repeat(false, mask.len() * levels.trailing_zeros() as usize);
+    extract_wm_mary_at(
+        watermarked_signal,
+        lattices,
+        levels,
+        step_size,
+        mask,
+        &mut ret,
+        0,
+    );
+    ret
+}
+
+/// Same as [`extract_wm_mary`], writing the bits of the block from `start` on in the whole `payload`
+///
+/// Panics unless `levels` is a power of two of at least 2 and `payload` has room for the bits of the block
+pub fn extract_wm_mary_at<T: BitStore, O: BitOrder>(
+    watermarked_signal: &[f32],
+    lattices: &[f32],
+    levels: usize,
+    step_size: f32,
+    mask: &CoefficientMask,
+    payload: &mut BitSlice<T, O>,
+    start: usize,
+) {
+    assert_levels(levels);
+    let bits = levels.trailing_zeros() as usize;
+    let out = &mut payload[start..start + mask.len() * bits];
+    for ((j, &i), symbol_bits) in mask.indices().iter().enumerate().zip(out.chunks_mut(bits)) {
+        let mut symbol = 0;
+        let mut closest = f32::INFINITY;
+        for (k, d) in lattices[j * levels..(j + 1) * levels].iter().enumerate() {
+            let tmp = watermarked_signal[i] + d;
+            let distance = (round_to_step_size(tmp, step_size) - tmp).abs();
+            if distance < closest {
+                closest = distance;
+                symbol = k;
+            }
+        }
+        for (b, mut bit) in symbol_bits.iter_mut().rev().enumerate() {
+            *bit = (symbol >> b) & 1 == 1;
+        }
+    }
+}
+
+/// Soft version of [`extract_wm_mary`]: a value in `[-1, 1]` for each bit, like [`extract_wm_soft`]
+///
+/// The sign is the bit of the closest symbol and the magnitude the confidence of the coefficient,
+/// 1 right on the lattice of the symbol down to 0 halfway to the next one. Random coefficients
+/// average a magnitude of 0.5. With 2 levels and the same dithers the values are those of [`extract_wm_soft`]
+///
+/// Panics unless `levels` is a power of two of at least 2
+pub fn extract_wm_mary_soft(
+    watermarked_signal: &[f32],
+    lattices: &[f32],
+    levels: usize,
+    step_size: f32,
+    mask: &CoefficientMask,
+) -> Vec<f32> {
+    assert_levels(levels);
+    let bits = levels.trailing_zeros() as usize;
+    let mut ret = Vec::with_capacity(mask.indices().len() * bits);
+    for (j, &i) in mask.indices().iter().enumerate() {
+        let mut symbol = 0;
+        let mut closest = f32::INFINITY;
+        for (k, d) in lattices[j * levels..(j + 1) * levels].iter().enumerate() {
+            let tmp = watermarked_signal[i] + d;
+            let distance = (round_to_step_size(tmp, step_size) - tmp).abs();
+            if distance < closest {
+                closest = distance;
+                symbol = k;
+            }
+        }
+        // Lattices are step_size / levels apart, so the closest one is at most half of that away
+        let confidence = (1.0 - 2.0 * levels as f32 * closest / step_size).max(0.0);
+        for b in (0..bits).rev() {
+            ret.push(if (symbol >> b) & 1 == 1 {
+                confidence
             } else {
-                dither_signal[j].0
-            };
-            *h = round_to_step_size(*h + d, step_size) - d;
-            j += 1;
+                -confidence
+            });
         }
     }
-    assert_eq!(j, 12);
+    ret
 }
 
-pub fn extract_wm(
+/// Non-blind version of [`extract_wm_mary`], decoding with the help of the block of the original host
+///
+/// Replays the embedding of every symbol into the original coefficient and decodes the symbol whose change
+/// is the closest to the actual change. Only the neighbour on the side of the noise competes, instead of the
+/// lattices on both sides of a blind decision, and distortion compensation no longer costs robustness
+///
+/// Panics unless `levels` is a power of two of at least 2 and `alpha` is in `(0, 1]`
+pub fn extract_wm_nonblind(
     watermarked_signal: &[f32],
-    dither_signal: &[(f32, f32)],
+    original_signal: &[f32],
+    lattices: &[f32],
+    levels: usize,
     step_size: f32,
+    alpha: f32,
+    mask: &CoefficientMask,
 ) -> BitVec {
-    let acceptable_range = step_size / 10.0;
-
-    let mut ret = BitVec::new();
-    let mut j = 0;
-    for (i, wmkd_bit) in watermarked_signal.iter().enumerate() {
-        if in_range(i) {
-            let tmp = wmkd_bit + dither_signal[j].0;
-            if (round_to_step_size(tmp, step_size) - tmp).abs() < acceptable_range {
-                ret.push(false);
-            } else {
-                ret.push(true);
+    assert_levels(levels);
+    assert!(
+        alpha > 0.0 && alpha <= 1.0,
+        "alpha {} is not in (0, 1]",
+        alpha
+    );
+    let bits = levels.trailing_zeros() as usize;
+    let mut ret = BitVec::with_capacity(mask.indices().len() * bits);
+    for (j, &i) in mask.indices().iter().enumerate() {
+        let x = original_signal[i];
+        let difference = watermarked_signal[i] - x;
+        let mut symbol = 0;
+        let mut closest = f32::INFINITY;
+        for (k, d) in lattices[j * levels..(j + 1) * levels].iter().enumerate() {
+            let change = alpha * (round_to_step_size(x + d, step_size) - d - x);
+            let distance = (difference - change).abs();
+            if distance < closest {
+                closest = distance;
+                symbol = k;
             }
-            j += 1;
+        }
+        for b in (0..bits).rev() {
+            ret.push((symbol >> b) & 1 == 1);
         }
     }
-    assert_eq!(j, 12);
     ret
 }
+
+/// Default acceptable range of [`extract_wm_with_range`], a tenth of a step
+pub const DEFAULT_ACCEPTABLE_RANGE: f32 = 0.1;
+
+/// Extracts one bit from each coefficient of the block selected by `mask`
+///
+/// Hard decision of [`extract_wm_soft`]: a coefficient within `acceptable_range = step_size / 10.0` of the
+/// lattice of bit 0, a soft value under -0.6, decodes to 0 and any other to 1. [`extract_wm_with_range`]
+/// with [`DEFAULT_ACCEPTABLE_RANGE`]
+pub fn extract_wm<F: Float>(
+    watermarked_signal: &[F],
+    dither_signal: &[(F, F)],
+    step_size: F,
+    mask: &CoefficientMask,
+) -> BitVec {
+    let mut ret = BitVec::repeat(false, mask.indices().len());
+    extract_wm_into(watermarked_signal, dither_signal, step_size, mask, &mut ret);
+    ret
+}
+
+/// Same as [`extract_wm`], writing the bits into `out` instead of allocating them
+///
+/// `out` can be a chunk of a larger buffer, e.g. of `BitSlice::chunks_mut`, to extract many blocks without allocating
+///
+/// Panics unless `out` holds exactly one bit per coefficient of the mask
+pub fn extract_wm_into<F: Float, T: BitStore, O: BitOrder>(
+    watermarked_signal: &[F],
+    dither_signal: &[(F, F)],
+    step_size: F,
+    mask: &CoefficientMask,
+    out: &mut BitSlice<T, O>,
+) {
+    // A tenth of the step is exact for the steps with a whole tenth, in f32 and f64 alike, unlike a product with
+    // the rounded 0.1
+    decide_with_boundary(
+        watermarked_signal,
+        dither_signal,
+        step_size,
+        step_size / F::from(10).unwrap(),
+        mask,
+        out,
+    );
+}
+
+/// Same as [`extract_wm`], decoding a 0 from the coefficients within `acceptable_range * step_size` of the
+/// lattice of bit 0 and a 1 from the others
+///
+/// The lattice of bit 1 is half a step from that of bit 0, so 0.25 puts the boundary halfway and treats both
+/// bits alike, the sign of [`extract_wm_soft`]. A larger range keeps more of the 0s moved by noise, fewer
+/// false positives, but reads more of the 1s moved toward the lattice of bit 0 as 0s, more false negatives.
+/// A smaller range, such as the default, does the opposite. This includes the bits distortion compensation
+/// leaves off their lattice: with `alpha < 1`, a range past `alpha / 2` flips 1s of clean signals and a range
+/// under `(1 - alpha) / 2` flips 0s
+///
+/// Panics unless `acceptable_range` is in `(0, 0.5)`
+pub fn extract_wm_with_range<F: Float>(
+    watermarked_signal: &[F],
+    dither_signal: &[(F, F)],
+    step_size: F,
+    acceptable_range: F,
+    mask: &CoefficientMask,
+) -> BitVec {
+    let half = F::from(0.5).unwrap();
+    assert!(
+        acceptable_range > F::zero() && acceptable_range < half,
+        "acceptable range {} is not in (0, 0.5)",
+        acceptable_range.to_f64().unwrap_or(f64::NAN)
+    );
+    let mut ret = BitVec::repeat(false, mask.indices().len());
+    decide_with_boundary(
+        watermarked_signal,
+        dither_signal,
+        step_size,
+        acceptable_range * step_size,
+        mask,
+        &mut ret,
+    );
+    ret
+}
+
+/// Writes the bits of [`extract_wm_with_range`] into `out`, a 1 for each coefficient at least `boundary` from
+/// the lattice of bit 0
+///
+/// Compares the distances themselves, unlike the division of the soft value, so no rounding moves the boundary
+fn decide_with_boundary<F: Float, T: BitStore, O: BitOrder>(
+    watermarked_signal: &[F],
+    dither_signal: &[(F, F)],
+    step_size: F,
+    boundary: F,
+    mask: &CoefficientMask,
+    out: &mut BitSlice<T, O>,
+) {
+    assert_eq!(
+        out.len(),
+        mask.indices().len(),
+        "the buffer must hold one bit per masked coefficient"
+    );
+    for (j, &i) in mask.indices().iter().enumerate() {
+        let distance = lattice_distance(watermarked_signal[i], dither_signal[j].0, step_size);
+        out.set(j, distance >= boundary);
+    }
+}
+
+/// Same as [`extract_wm`], writing the bits of the block from `start` on in the whole `payload`
+///
+/// Panics unless `payload` has room for one bit per coefficient of the mask from `start` on
+pub fn extract_wm_at<F: Float, T: BitStore, O: BitOrder>(
+    watermarked_signal: &[F],
+    dither_signal: &[(F, F)],
+    step_size: F,
+    mask: &CoefficientMask,
+    payload: &mut BitSlice<T, O>,
+    start: usize,
+) {
+    let out = &mut payload[start..start + mask.len()];
+    extract_wm_into(watermarked_signal, dither_signal, step_size, mask, out);
+}
+
+/// Extracts the bits of every DCT block with [`extract_wm_mary`], one block after another
+///
+/// Checks an embed in-process from the blocks before the IDCT, skipping the IDCT, encode, decode and DCT
+/// round trip. Takes the lattices, levels and mask of the embed, so it reads what [`embed_wm_mary`] wrote
+/// with any config, and lossless saves with no rounding across the decision boundaries give the same bits
+/// as a full extraction
+///
+/// Panics unless `levels` is a power of two of at least 2
+pub fn extract_from_blocks(
+    blocks: &[Vec<f32>],
+    lattices: &[f32],
+    levels: usize,
+    step_size: f32,
+    mask: &CoefficientMask,
+) -> BitVec {
+    let bits = levels.trailing_zeros() as usize;
+    let mut ret = BitVec::with_capacity(blocks.len() * mask.indices().len() * bits);
+    for block in blocks {
+        ret.extend_from_bitslice(&extract_wm_mary(block, lattices, levels, step_size, mask));
+    }
+    ret
+}
+
+/// Extracts a soft value in `[-1, 1]` from each coefficient of the block selected by `mask`
+///
+/// The sign is the bit of the closer lattice, negative for 0 and positive for 1, and the magnitude the
+/// confidence: -1 and 1 sit right on the lattice of the bit, 0 is halfway between both lattices
+pub fn extract_wm_soft<F: Float>(
+    watermarked_signal: &[F],
+    dither_signal: &[(F, F)],
+    step_size: F,
+    mask: &CoefficientMask,
+) -> Vec<F> {
+    mask.indices()
+        .iter()
+        .enumerate()
+        .map(|(j, &i)| soft_value(watermarked_signal[i], dither_signal[j].0, step_size))
+        .collect()
+}
+
+/// Soft value of the coefficient `x` whose bit 0 has the dither `d0`, see [`extract_wm_soft`]
+fn soft_value<F: Float>(x: F, d0: F, step_size: F) -> F {
+    F::from(4).unwrap() * lattice_distance(x, d0, step_size) / step_size - F::one()
+}
+
+/// Distance of the coefficient `x` to the lattice of bit 0, the lattice of bit 1 is half a step away
+fn lattice_distance<F: Float>(x: F, d0: F, step_size: F) -> F {
+    let tmp = x + d0;
+    (round_to_step_size(tmp, step_size) - tmp).abs()
+}
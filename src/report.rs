@@ -0,0 +1,70 @@
+use bitvec::prelude::BitVec;
+
+/// What an embed wrote into the host, see [`crate::embed_with_report`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EmbedReport {
+    /// Whole blocks available in the planes selected by the config
+    pub blocks_total: usize,
+    /// Blocks carrying bits, from the start of the first selected plane
+    pub blocks_used: usize,
+    /// Embedded bits, including the header, the padding to whole blocks and the repeated copies
+    pub bits_embedded: usize,
+    /// Mean magnitude of the change of the modified DCT coefficients
+    pub mean_coefficient_delta: f32,
+    /// PSNR in dB predicted from the coefficient changes, before rounding to 8-bit pixels
+    ///
+    /// Treats every plane like luma, whose change goes equally into R, G and B, so it is close to
+    /// [`crate::metrics::psnr`] for the Y plane and rougher for chroma planes. The rounding of the
+    /// YCbCr round trip alone costs around 53 dB, so faint embeds are overestimated
+    pub estimated_psnr: f64,
+}
+
+impl EmbedReport {
+    /// Fraction of the available blocks carrying bits
+    pub fn usage(&self) -> f64 {
+        if self.blocks_total == 0 {
+            return 0.0;
+        }
+        self.blocks_used as f64 / self.blocks_total as f64
+    }
+}
+
+/// Whether a watermark fits a host, computed from their dimensions alone, see [`crate::capacity_report`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CapacityReport {
+    /// Bits the planes selected by the config can carry
+    pub total_bits: usize,
+    /// Bits the embed would write, including the header, the padding to whole blocks and the repeated copies
+    pub needed_bits: usize,
+    /// Whether `needed_bits` fit into `total_bits`
+    pub fits: bool,
+    /// `needed_bits / total_bits`, above 1 when the watermark doesn't fit
+    pub utilization: f64,
+}
+
+/// Outcome of an authenticated extraction, see [`crate::extract_authenticated`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authentication {
+    /// The tag matches the extracted bits, which are returned
+    Authenticated(BitVec),
+    /// The tag doesn't match: no watermark with this key, or one too damaged to trust
+    Unauthenticated,
+}
+
+/// Bits recovered from an image that may have lost blocks, see [`crate::extract_partial_with`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialExtraction {
+    /// The recoded watermark bits, those of the missing blocks read as zeros
+    pub bits: BitVec,
+    /// Blocks of the bit stream, header included, the image is too small to hold
+    pub missing_blocks: usize,
+}
+
+impl PartialExtraction {
+    /// Whether every block of the bit stream was read
+    pub fn is_complete(&self) -> bool {
+        self.missing_blocks == 0
+    }
+}
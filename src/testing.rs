@@ -0,0 +1,126 @@
+//! Deterministic synthetic images, to test and benchmark without image files
+//!
+//! The same dimensions and seed always give the same pixels
+
+use image::{DynamicImage, Rgb, RgbImage};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Smooth waves setting the overall colors of hosts
+const HOST_WAVES: usize = 4;
+
+/// Shapes with sharp edges drawn over hosts
+const HOST_SHAPES: usize = 12;
+
+/// Shapes drawn over the background of watermarks
+const WATERMARK_SHAPES: usize = 8;
+
+/// Host image standing in for a photograph: smooth color gradients, flat and textured shapes with sharp
+/// edges, and a little grain
+///
+/// Samples stay within `[16, 240]`, so that embedding doesn't clip them
+pub fn generate_host(width: u32, height: u32, seed: u64) -> DynamicImage {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let scale = width.max(height).max(1) as f32;
+    // Frequencies in radians per pixel, phases and amplitudes of each wave of each channel
+    let waves: Vec<[(f32, f32, f32, f32); 3]> = (0..HOST_WAVES)
+        .map(|_| {
+            [(); 3].map(|_| {
+                (
+                    rng.random_range(0.5..4.0) * std::f32::consts::PI / scale,
+                    rng.random_range(0.5..4.0) * std::f32::consts::PI / scale,
+                    rng.random_range(0.0..std::f32::consts::TAU),
+                    rng.random_range(10.0..25.0),
+                )
+            })
+        })
+        .collect();
+    let shapes: Vec<Shape> = (0..HOST_SHAPES)
+        .map(|_| Shape::random(&mut rng, width, height, (48.0, 208.0)))
+        .collect();
+    let textured: Vec<bool> = shapes.iter().map(|_| rng.random_bool(0.5)).collect();
+
+    let mut image = RgbImage::from_fn(width, height, |x, y| {
+        let (fx, fy) = (x as f32, y as f32);
+        let mut pixel = [128.0; 3];
+        for wave in &waves {
+            for (value, &(kx, ky, phase, amplitude)) in pixel.iter_mut().zip(wave) {
+                *value += amplitude * (kx * fx + ky * fy + phase).sin();
+            }
+        }
+        for (shape, &textured) in shapes.iter().zip(&textured).rev() {
+            if shape.contains(fx, fy) {
+                let texture = if textured {
+                    12.0 * ((fx * 0.9).sin() * (fy * 0.7).cos())
+                } else {
+                    0.0
+                };
+                pixel = shape.color.map(|c| c + texture);
+                break;
+            }
+        }
+        Rgb(pixel.map(|value| value.round().clamp(16.0, 240.0) as u8))
+    });
+    for pixel in image.pixels_mut() {
+        for value in pixel.0.iter_mut() {
+            *value = (*value as i16 + rng.random_range(-3..=3)).clamp(16, 240) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Watermark image standing in for a logo: a diagonal color gradient behind flat shapes of arbitrary colors
+pub fn generate_watermark(width: u32, height: u32, seed: u64) -> DynamicImage {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let corners: [[f32; 3]; 2] = [(); 2].map(|_| [(); 3].map(|_| rng.random_range(0.0..=255.0)));
+    let shapes: Vec<Shape> = (0..WATERMARK_SHAPES)
+        .map(|_| Shape::random(&mut rng, width, height, (0.0, 255.0)))
+        .collect();
+    let span = (width + height).saturating_sub(2).max(1) as f32;
+
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        let (fx, fy) = (x as f32, y as f32);
+        let color = match shapes.iter().rev().find(|shape| shape.contains(fx, fy)) {
+            Some(shape) => shape.color,
+            None => {
+                let t = (fx + fy) / span;
+                [0, 1, 2].map(|c| corners[0][c] + t * (corners[1][c] - corners[0][c]))
+            }
+        };
+        Rgb(color.map(|value| value.round() as u8))
+    }))
+}
+
+/// A flat colored rectangle or ellipse
+struct Shape {
+    center: (f32, f32),
+    radii: (f32, f32),
+    ellipse: bool,
+    color: [f32; 3],
+}
+
+impl Shape {
+    /// Shape of random size, up to half the image, position and color with samples between `low` and `high`
+    fn random<R: Rng>(rng: &mut R, width: u32, height: u32, (low, high): (f32, f32)) -> Self {
+        let (w, h) = (width.max(1) as f32, height.max(1) as f32);
+        Self {
+            center: (rng.random_range(0.0..w), rng.random_range(0.0..h)),
+            radii: (
+                rng.random_range(w / 16.0..=w / 4.0),
+                rng.random_range(h / 16.0..=h / 4.0),
+            ),
+            ellipse: rng.random_bool(0.5),
+            color: [(); 3].map(|_| rng.random_range(low..=high)),
+        }
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        let dx = (x - self.center.0) / self.radii.0;
+        let dy = (y - self.center.1) / self.radii.1;
+        if self.ellipse {
+            dx * dx + dy * dy <= 1.0
+        } else {
+            dx.abs() <= 1.0 && dy.abs() <= 1.0
+        }
+    }
+}
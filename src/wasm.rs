@@ -0,0 +1,36 @@
+//! Bindings for the browser through wasm-bindgen, enabled by the `wasm` feature
+//!
+//! Images go in and out as encoded bytes, PNG on the way out, so nothing touches a filesystem.
+//! Build without the default `rayon` feature of `image`, threads aren't available on `wasm32-unknown-unknown`
+
+use image::ImageFormat;
+use wasm_bindgen::prelude::*;
+
+use crate::WatermarkError;
+
+fn to_js_error(err: WatermarkError) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+/// Embeds the encoded watermark into the encoded host, see [`crate::embed_watermark_bytes`]
+///
+/// Return value: the watermarked image encoded as PNG
+#[wasm_bindgen]
+pub fn embed(host: &[u8], wm: &[u8], key: u64, step: f32) -> Result<Vec<u8>, JsError> {
+    crate::embed_watermark_bytes(host, wm, key, step, ImageFormat::Png).map_err(to_js_error)
+}
+
+/// Extracts the `width * height` watermark of the encoded image, see [`crate::extract_watermark_bytes`]
+///
+/// Return value: the reconstructed watermark encoded as PNG
+#[wasm_bindgen]
+pub fn extract(
+    watermarked: &[u8],
+    key: u64,
+    step: f32,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, JsError> {
+    crate::extract_watermark_bytes(watermarked, key, step, width, height, ImageFormat::Png)
+        .map_err(to_js_error)
+}
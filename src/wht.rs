@@ -0,0 +1,171 @@
+//! Walsh-Hadamard transform over square blocks, an alternative to the DCT using only additions
+//!
+//! Coefficients are in sequency order, the number of sign changes of their basis function, so the
+//! coefficient masks select about the same frequencies as with the DCT. The forward transform is halved
+//! so AC coefficients have the scale of the unnormalized DCT ones, and step sizes keep their meaning
+
+use rustdct::DctNum;
+
+use crate::dct::{transpose, BLK_WIDTH};
+
+/// 2D Walsh-Hadamard transform and its inverse over square blocks
+///
+/// Needs no planning beyond the sequency order, but mirrors [`crate::dct::BlockDct`] so both can be swapped.
+/// Sums and differences of samples are exact, so integer samples round trip without error
+pub struct BlockWht {
+    block_size: usize,
+    /// Natural (Hadamard) order index of each sequency order coefficient
+    sequency: Vec<usize>,
+}
+
+impl BlockWht {
+    /// Computes the sequency order of `block_size * block_size` blocks
+    ///
+    /// Panics unless `block_size` is a power of two
+    pub fn new(block_size: usize) -> Self {
+        assert!(
+            block_size.is_power_of_two(),
+            "block size {} is not a power of two",
+            block_size
+        );
+        // The basis function with s sign changes is the Hadamard row at the bit reversed Gray code of s
+        let bits = block_size.trailing_zeros();
+        let sequency = (0..block_size)
+            .map(|s| {
+                let gray = s ^ (s >> 1);
+                gray.reverse_bits()
+                    .checked_shr(usize::BITS - bits)
+                    .unwrap_or(0)
+            })
+            .collect();
+        Self {
+            block_size,
+            sequency,
+        }
+    }
+
+    /// Width of the blocks
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Applies the 2D WHT on a Vec of blocks
+    ///
+    /// Changes are made in-place, without allocating per block
+    pub fn forward<T: DctNum>(&self, blocks: &mut [Vec<T>]) {
+        let mut scratch = vec![T::zero(); self.block_size];
+        for block in blocks.iter_mut() {
+            self.forward_with_scratch(block, &mut scratch);
+        }
+    }
+
+    /// Applies the inverse 2D WHT on a Vec of blocks
+    ///
+    /// Changes are made in-place, without allocating per block
+    pub fn inverse<T: DctNum>(&self, blocks: &mut [Vec<T>]) {
+        let mut scratch = vec![T::zero(); self.block_size];
+        for block in blocks.iter_mut() {
+            self.inverse_with_scratch(block, &mut scratch);
+        }
+    }
+
+    /// Applies the 2D WHT on a single block in place
+    ///
+    /// Panics unless the block holds `block_size * block_size` values
+    pub fn forward_block<T: DctNum>(&self, block: &mut [T]) {
+        let mut scratch = vec![T::zero(); self.block_size];
+        self.forward_with_scratch(block, &mut scratch);
+    }
+
+    /// Applies the inverse 2D WHT on a single block in place
+    ///
+    /// Panics unless the block holds `block_size * block_size` values
+    pub fn inverse_block<T: DctNum>(&self, block: &mut [T]) {
+        let mut scratch = vec![T::zero(); self.block_size];
+        self.inverse_with_scratch(block, &mut scratch);
+    }
+
+    fn forward_with_scratch<T: DctNum>(&self, block: &mut [T], scratch: &mut [T]) {
+        let n = self.block_size;
+        assert_eq!(block.len(), n * n, "block is not {} * {}", n, n);
+        let half = T::from_f64(0.5).unwrap();
+
+        // Transform the rows, then the columns as rows of the transposed block
+        for _ in 0..2 {
+            for row in block.chunks_mut(n) {
+                fwht(row);
+                // Reorder from natural to sequency order
+                scratch.copy_from_slice(row);
+                for (value, &h) in row.iter_mut().zip(&self.sequency) {
+                    *value = scratch[h];
+                }
+            }
+            transpose(block, n);
+        }
+        for value in block.iter_mut() {
+            *value = *value * half;
+        }
+    }
+
+    fn inverse_with_scratch<T: DctNum>(&self, block: &mut [T], scratch: &mut [T]) {
+        let n = self.block_size;
+        assert_eq!(block.len(), n * n, "block is not {} * {}", n, n);
+        // The Hadamard matrix is its own inverse up to 1 / n per axis, times 2 undoing the halving
+        let coeff = T::from_u8(2).unwrap() / T::from_usize(n * n).unwrap();
+
+        for _ in 0..2 {
+            for row in block.chunks_mut(n) {
+                // Reorder from sequency to natural order
+                scratch.copy_from_slice(row);
+                for (&value, &h) in scratch.iter().zip(&self.sequency) {
+                    row[h] = value;
+                }
+                fwht(row);
+            }
+            transpose(block, n);
+        }
+        for value in block.iter_mut() {
+            *value = *value * coeff;
+        }
+    }
+}
+
+impl Default for BlockWht {
+    fn default() -> Self {
+        Self::new(BLK_WIDTH)
+    }
+}
+
+/// Unnormalized fast Walsh-Hadamard transform of a power of two length row, in natural order
+fn fwht<T: DctNum>(row: &mut [T]) {
+    let mut h = 1;
+    while h < row.len() {
+        for start in (0..row.len()).step_by(2 * h) {
+            for i in start..start + h {
+                let (a, b) = (row[i], row[i + h]);
+                row[i] = a + b;
+                row[i + h] = a - b;
+            }
+        }
+        h *= 2;
+    }
+}
+
+/// Applies the 2D WHT on a Vec of `block_size * block_size` blocks
+///
+/// Changes are made in-place
+pub fn apply_2d_wht<T: DctNum>(blocks: &mut [Vec<T>], block_size: usize) {
+    BlockWht::new(block_size).forward(blocks);
+}
+
+/// Applies the inverse 2D WHT on a Vec of `block_size * block_size` blocks
+///
+/// Changes are made in-place
+pub fn apply_2d_iwht<T: DctNum>(blocks: &mut [Vec<T>], block_size: usize) {
+    BlockWht::new(block_size).inverse(blocks);
+}
+
+/// Factor turning any WHT coefficient into the orthonormal one, see [`crate::dct::orthonormal_scale`]
+pub(crate) fn orthonormal_scale(block_size: usize) -> f64 {
+    2.0 / block_size as f64
+}